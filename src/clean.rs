@@ -1,10 +1,54 @@
 use serde::{Deserialize, Serialize};
+use std::time::{Duration, Instant};
+use crate::protocol::{ExtMessage, HevCycleResult};
+use crate::delivery::{self, DeliveryStatus};
 use crate::{BulbInfo, Manager};
 
+/// How long to wait for the bulbs to report their HEV cycle state.
+const REPLY_TIMEOUT: Duration = Duration::from_secs(2);
+/// How long to wait for the last cycle result once the cycle state is in.
+const LAST_RESULT_TIMEOUT: Duration = Duration::from_millis(100);
+
 #[derive(Deserialize, Debug, Clone)]
 pub struct CleanRequest {
+    /// Cycle length in seconds; the bulb's configured default is used when omitted or `0`.
     pub duration: Option<u32>,
     pub stop: Option<bool>,
+    /// Don't wait for the bulbs to acknowledge or report their state.
+    pub fast: Option<bool>,
+}
+
+/// HEV cycle state as reported by StateHevCycle. Durations are in seconds.
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct HevCycleState {
+    pub duration: u32,
+    pub remaining: u32,
+    pub last_power: bool,
+}
+
+impl HevCycleState {
+    pub fn is_cleaning(&self) -> bool {
+        self.remaining > 0
+    }
+}
+
+/// Default HEV cycle settings as reported by StateHevCycleConfiguration.
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct HevCycleConfig {
+    pub indication: bool,
+    pub duration: u32,
+}
+
+/// Cached HEV state exposed in the List Lights JSON and by `GET /v1/lights/:selector/clean`.
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct HevStatus {
+    pub cleaning: bool,
+    pub duration: u32,
+    pub remaining: u32,
+    pub last_power: bool,
+    pub last_result: Option<HevCycleResult>,
+    pub default_duration: Option<u32>,
+    pub indication: Option<bool>,
 }
 
 #[derive(Serialize, Debug)]
@@ -13,6 +57,29 @@ pub struct CleanResult {
     pub label: String,
     pub status: String,
     pub message: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cleaning: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub duration: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub remaining: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub last_result: Option<HevCycleResult>,
+}
+
+impl CleanResult {
+    fn new(bulb: &BulbInfo, status: &str, message: Option<String>) -> Self {
+        CleanResult {
+            id: bulb.id.clone(),
+            label: bulb.label.clone(),
+            status: status.to_string(),
+            message,
+            cleaning: None,
+            duration: None,
+            remaining: None,
+            last_result: None,
+        }
+    }
 }
 
 #[derive(Serialize)]
@@ -20,6 +87,20 @@ pub struct CleanResponse {
     pub results: Vec<CleanResult>,
 }
 
+#[derive(Serialize, Debug)]
+pub struct CleanStatus {
+    pub id: String,
+    pub label: String,
+    pub status: String,
+    pub message: Option<String>,
+    pub hev: Option<HevStatus>,
+}
+
+#[derive(Serialize)]
+pub struct CleanStatusResponse {
+    pub results: Vec<CleanStatus>,
+}
+
 pub struct CleanHandler;
 
 impl CleanHandler {
@@ -27,37 +108,118 @@ impl CleanHandler {
         CleanHandler
     }
 
+    /// Starts or stops the HEV cycle on every bulb, then reports the state each bulb replies with.
+    ///
+    /// Waits for acks through `delivery::confirm` and for replies through
+    /// `Manager::wait_for_bulb`, unless the request is `fast`, so the bulbs lock must not be held.
     pub fn handle_clean(
         &self,
-        _mgr: &Manager,
+        mgr: &Manager,
         bulbs: &[&BulbInfo],
-        _request: CleanRequest,
+        request: CleanRequest,
     ) -> CleanResponse {
-        let mut results = Vec::new();
-        
-        for bulb in bulbs {
-            let has_hev = bulb.product.as_ref()
-                .map_or(false, |p| p.capabilities.has_hev);
-            
-            if !has_hev {
-                results.push(CleanResult {
+        let set_cycle = ExtMessage::SetHevCycle {
+            enable: !request.stop.unwrap_or(false),
+            duration_s: request.duration.unwrap_or(0),
+        };
+
+        let sent_at = Instant::now();
+        let sent: Vec<_> = bulbs.iter().map(|bulb| {
+            if !bulb.has_hev() {
+                return Err("Device does not support HEV/Clean mode".to_string());
+            }
+            bulb.send_ext(&mgr.sock, &set_cycle)
+                .and_then(|_| bulb.send_ext(&mgr.sock, &ExtMessage::GetHevCycle))
+                .and_then(|_| bulb.send_ext(&mgr.sock, &ExtMessage::GetLastHevCycleResult))
+                .map_err(|e| format!("Failed to send HEV cycle: {}", e))
+        }).collect();
+        let fast = request.fast.unwrap_or(false);
+        let statuses = delivery::confirm_unless_fast(&mgr.sock, bulbs, &sent, sent_at, fast);
+
+        // The cycle is acknowledged; now collect the replies
+        let deadline = Instant::now() + REPLY_TIMEOUT;
+        let results = bulbs.iter().zip(sent).zip(statuses).map(|((bulb, result), status)| {
+            match (result, status) {
+                (Err(e), _) => return CleanResult::new(bulb, "error", Some(e)),
+                (Ok(()), Some(DeliveryStatus::Ok)) => {}
+                (Ok(()), status) => return CleanResult::new(
+                    bulb,
+                    status.map_or("error", |s| s.as_str()),
+                    Some("HEV cycle was not acknowledged".to_string()),
+                ),
+            }
+            if fast {
+                return CleanResult::new(bulb, "ok", None);
+            }
+
+            let state = mgr.wait_for_bulb(bulb.target, deadline, |b| {
+                b.hev_cycle.updated_since(sent_at).cloned()
+            });
+
+            let mut result = match state {
+                Some(state) => {
+                    let mut result = CleanResult::new(bulb, "ok", None);
+                    result.cleaning = Some(state.is_cleaning());
+                    result.duration = Some(state.duration);
+                    result.remaining = Some(state.remaining);
+                    result
+                }
+                None => CleanResult::new(
+                    bulb,
+                    "timed_out",
+                    Some("No HEV cycle state received from device".to_string()),
+                ),
+            };
+
+            // Requested alongside GetHevCycle, so the reply has usually arrived by now
+            result.last_result = mgr.wait_for_bulb(bulb.target, Instant::now() + LAST_RESULT_TIMEOUT, |b| {
+                b.hev_last_result.updated_since(sent_at).copied()
+            });
+            result
+        }).collect();
+
+        CleanResponse { results }
+    }
+
+    /// Reports the cached HEV state of every bulb, waiting briefly for bulbs not heard from yet.
+    ///
+    /// The queries themselves are sent by `Manager::refresh`, like any other polled state.
+    pub fn handle_status(&self, mgr: &Manager, bulbs: &[&BulbInfo]) -> CleanStatusResponse {
+        let deadline = Instant::now() + REPLY_TIMEOUT;
+        let results = bulbs.iter().map(|bulb| {
+            if !bulb.has_hev() {
+                return CleanStatus {
                     id: bulb.id.clone(),
                     label: bulb.label.clone(),
                     status: "error".to_string(),
                     message: Some("Device does not support HEV/Clean mode".to_string()),
-                });
-                continue;
+                    hev: None,
+                };
             }
-            
-            results.push(CleanResult {
-                id: bulb.id.clone(),
-                label: bulb.label.clone(),
-                status: "ok".to_string(),
-                message: Some("Clean mode operation acknowledged (HEV message type not yet implemented in lifx-rs)".to_string()),
+
+            let hev = bulb.hev.clone().or_else(|| {
+                mgr.wait_for_bulb(bulb.target, deadline, |b| b.hev.clone())
             });
-        }
-        
-        CleanResponse { results }
+
+            match hev {
+                Some(hev) => CleanStatus {
+                    id: bulb.id.clone(),
+                    label: bulb.label.clone(),
+                    status: "ok".to_string(),
+                    message: None,
+                    hev: Some(hev),
+                },
+                None => CleanStatus {
+                    id: bulb.id.clone(),
+                    label: bulb.label.clone(),
+                    status: "timed_out".to_string(),
+                    message: Some("No HEV cycle state received from device".to_string()),
+                    hev: None,
+                },
+            }
+        }).collect();
+
+        CleanStatusResponse { results }
     }
 }
 
@@ -76,6 +238,7 @@ mod tests {
         let request = CleanRequest {
             duration: Some(3600),
             stop: Some(false),
+            fast: None,
         };
         
         assert_eq!(request.duration.unwrap(), 3600);
@@ -87,6 +250,7 @@ mod tests {
         let request = CleanRequest {
             duration: None,
             stop: Some(true),
+            fast: None,
         };
         
         assert!(request.duration.is_none());
@@ -100,6 +264,10 @@ mod tests {
             label: "Test Bulb".to_string(),
             status: "ok".to_string(),
             message: Some("Clean mode started".to_string()),
+            cleaning: Some(true),
+            duration: Some(7200),
+            remaining: Some(7200),
+            last_result: None,
         };
         
         assert_eq!(result.id, "test_id");
@@ -117,12 +285,20 @@ mod tests {
                     label: "Bulb 1".to_string(),
                     status: "ok".to_string(),
                     message: None,
+                    cleaning: Some(false),
+                    duration: Some(0),
+                    remaining: Some(0),
+                    last_result: Some(HevCycleResult::Success),
                 },
                 CleanResult {
                     id: "bulb2".to_string(),
                     label: "Bulb 2".to_string(),
                     status: "error".to_string(),
                     message: Some("Device does not support HEV".to_string()),
+                    cleaning: None,
+                    duration: None,
+                    remaining: None,
+                    last_result: None,
                 },
             ],
         };
@@ -131,4 +307,60 @@ mod tests {
         assert_eq!(response.results[0].status, "ok");
         assert_eq!(response.results[1].status, "error");
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_hev_cycle_state_is_cleaning() {
+        let running = HevCycleState { duration: 7200, remaining: 120, last_power: false };
+        let finished = HevCycleState { duration: 7200, remaining: 0, last_power: false };
+
+        assert!(running.is_cleaning());
+        assert!(!finished.is_cleaning());
+    }
+
+    #[test]
+    fn test_clean_result_serialization_omits_missing_state() {
+        let result = CleanResult {
+            id: "bulb1".to_string(),
+            label: "Bulb 1".to_string(),
+            status: "timed_out".to_string(),
+            message: None,
+            cleaning: None,
+            duration: None,
+            remaining: None,
+            last_result: None,
+        };
+        let json = serde_json::to_value(&result).unwrap();
+        assert!(json.get("remaining").is_none());
+
+        let result = CleanResult {
+            cleaning: Some(true),
+            remaining: Some(60),
+            last_result: Some(HevCycleResult::InterruptedByLan),
+            ..result
+        };
+        let json = serde_json::to_value(&result).unwrap();
+        assert_eq!(json["cleaning"], true);
+        assert_eq!(json["remaining"], 60);
+        assert_eq!(json["last_result"], "interrupted_by_lan");
+    }
+
+    #[test]
+    fn test_hev_status_serialization() {
+        let status = HevStatus {
+            cleaning: true,
+            duration: 7200,
+            remaining: 3600,
+            last_power: false,
+            last_result: Some(HevCycleResult::Success),
+            default_duration: Some(7200),
+            indication: None,
+        };
+        let json = serde_json::to_value(&status).unwrap();
+
+        assert_eq!(json["cleaning"], true);
+        assert_eq!(json["remaining"], 3600);
+        assert_eq!(json["last_result"], "success");
+        assert_eq!(json["default_duration"], 7200);
+        assert!(json["indication"].is_null());
+    }
+}
@@ -0,0 +1,309 @@
+//! Parsing of LIFX cloud color strings.
+//!
+//! Accepts named colors (`red`), `#rrggbb`, `rgb:r,g,b`, `hue:`, `saturation:`, `brightness:`,
+//! `kelvin:` and space separated combinations such as `hue:120 saturation:1.0 brightness:0.5`.
+//! Later components override earlier ones.
+
+use lifx_rs::lan::HSBK;
+use palette::{Hsv, IntoColor, Srgb};
+use serde::Serialize;
+use crate::error::{LifxError, Result};
+use crate::{
+    parse_f64_safe, parse_i64_safe, parse_u16_safe, LifxColor, HUE_BLUE, HUE_CYAN, HUE_GREEN,
+    HUE_ORANGE, HUE_PINK, HUE_PURPLE, HUE_RED, HUE_YELLOW, LIFX_BRIGHTNESS_MAX, LIFX_HUE_DEGREE_FACTOR,
+    LIFX_SATURATION_MAX,
+};
+
+pub const KELVIN_MIN: u16 = 1500;
+pub const KELVIN_MAX: u16 = 9000;
+/// Kelvin used when neither the color string nor the light provide one.
+pub const DEFAULT_KELVIN: u16 = 3500;
+
+/// A parsed color string, in LIFX protocol units.
+///
+/// Components the string does not mention are `None` and keep the light's current value.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct ColorSpec {
+    pub hue: Option<u16>,
+    pub saturation: Option<u16>,
+    pub brightness: Option<u16>,
+    pub kelvin: Option<u16>,
+}
+
+impl ColorSpec {
+    pub fn parse(color: &str) -> Result<ColorSpec> {
+        let color = color.trim();
+        if color.is_empty() {
+            return Err(invalid("Color string cannot be empty".to_string()));
+        }
+
+        let mut spec = ColorSpec::default();
+        for component in color.split_whitespace() {
+            spec.parse_component(&component.to_lowercase())?;
+        }
+        Ok(spec)
+    }
+
+    fn parse_component(&mut self, component: &str) -> Result<()> {
+        if let Some(value) = component.strip_prefix("hue:") {
+            self.hue = Some(hue_to_lifx(parse_ranged("hue", value, 0.0, 360.0)?));
+        } else if let Some(value) = component.strip_prefix("saturation:") {
+            self.saturation = Some(fraction_to_lifx(parse_ranged("saturation", value, 0.0, 1.0)?));
+        } else if let Some(value) = component.strip_prefix("brightness:") {
+            self.brightness = Some(fraction_to_lifx(parse_ranged("brightness", value, 0.0, 1.0)?));
+        } else if let Some(value) = component.strip_prefix("kelvin:") {
+            let kelvin = parse_u16_safe(value).map_err(|_| invalid(format!("Invalid kelvin value: {}", value)))?;
+            if !(KELVIN_MIN..=KELVIN_MAX).contains(&kelvin) {
+                return Err(invalid(format!("kelvin must be between {} and {}", KELVIN_MIN, KELVIN_MAX)));
+            }
+            self.kelvin = Some(kelvin);
+            self.saturation = Some(0);
+        } else if let Some(value) = component.strip_prefix("rgb:") {
+            let parts: Vec<&str> = value.split(',').collect();
+            if parts.len() != 3 {
+                return Err(invalid("RGB format must be 'rgb:r,g,b'".to_string()));
+            }
+            let (r, g, b) = (rgb_component(parts[0])?, rgb_component(parts[1])?, rgb_component(parts[2])?);
+            self.set_rgb(r, g, b);
+        } else if let Some(hex) = component.strip_prefix('#') {
+            if hex.len() != 6 || !hex.chars().all(|c| c.is_ascii_hexdigit()) {
+                return Err(invalid(format!("Invalid hex color: #{}", hex)));
+            }
+            let channel = |i: usize| u8::from_str_radix(&hex[i..i + 2], 16).unwrap_or(0);
+            self.set_rgb(channel(0), channel(2), channel(4));
+        } else {
+            let hue = match component {
+                "white" => {
+                    self.saturation = Some(0);
+                    return Ok(());
+                }
+                "red" => HUE_RED,
+                "orange" => HUE_ORANGE,
+                "yellow" => HUE_YELLOW,
+                "green" => HUE_GREEN,
+                "cyan" => HUE_CYAN,
+                "blue" => HUE_BLUE,
+                "purple" => HUE_PURPLE,
+                "pink" => HUE_PINK,
+                _ => return Err(invalid(format!("Unknown color: {}", component))),
+            };
+            self.hue = Some(hue);
+            self.saturation = Some(LIFX_SATURATION_MAX as u16);
+        }
+        Ok(())
+    }
+
+    /// RGB colors set hue, saturation and brightness from their HSV equivalent.
+    fn set_rgb(&mut self, r: u8, g: u8, b: u8) {
+        let hsv: Hsv = Srgb::new(r as f32 / 255.0, g as f32 / 255.0, b as f32 / 255.0).into_color();
+        self.hue = Some(hue_to_lifx(hsv.hue.into_positive_degrees() as f64));
+        self.saturation = Some(fraction_to_lifx(hsv.saturation as f64));
+        self.brightness = Some(fraction_to_lifx(hsv.value as f64));
+    }
+
+    /// Applies the components this string sets on top of `base`.
+    pub fn apply_to(&self, base: HSBK) -> HSBK {
+        HSBK {
+            hue: self.hue.unwrap_or(base.hue),
+            saturation: self.saturation.unwrap_or(base.saturation),
+            brightness: self.brightness.unwrap_or(base.brightness),
+            kelvin: self.kelvin.unwrap_or(base.kelvin),
+        }
+    }
+}
+
+/// Body of `GET /v1/color`: the components a color string sets, in cloud units.
+///
+/// Components the string leaves alone are `null`.
+#[derive(Serialize, Debug, PartialEq)]
+pub struct ColorDescription {
+    pub hue: Option<f64>,
+    pub saturation: Option<f64>,
+    pub brightness: Option<f64>,
+    pub kelvin: Option<u16>,
+}
+
+impl From<ColorSpec> for ColorDescription {
+    fn from(spec: ColorSpec) -> Self {
+        ColorDescription {
+            hue: spec.hue.map(|h| round_to(h as f64 / LIFX_HUE_DEGREE_FACTOR as f64, 2)),
+            saturation: spec.saturation.map(|s| round_to(s as f64 / LIFX_SATURATION_MAX as f64, 4)),
+            brightness: spec.brightness.map(|b| round_to(b as f64 / LIFX_BRIGHTNESS_MAX as f64, 4)),
+            kelvin: spec.kelvin,
+        }
+    }
+}
+
+/// Parses `color` and applies it to the light's current color.
+pub fn parse_color(color: &str, current: Option<&LifxColor>) -> Result<HSBK> {
+    Ok(ColorSpec::parse(color)?.apply_to(base_color(current)))
+}
+
+/// The light's current color, or full brightness white when it is not known yet.
+pub fn base_color(current: Option<&LifxColor>) -> HSBK {
+    match current {
+        Some(c) => HSBK {
+            hue: c.hue,
+            saturation: c.saturation,
+            brightness: c.brightness,
+            kelvin: c.kelvin,
+        },
+        None => HSBK {
+            hue: 0,
+            saturation: 0,
+            brightness: LIFX_BRIGHTNESS_MAX as u16,
+            kelvin: DEFAULT_KELVIN,
+        },
+    }
+}
+
+/// Converts degrees to the LIFX 16 bit hue, wrapping 360 to 0.
+fn hue_to_lifx(degrees: f64) -> u16 {
+    ((degrees * LIFX_HUE_DEGREE_FACTOR as f64).round() as u32 % 0x10000) as u16
+}
+
+/// Converts a 0.0 to 1.0 saturation or brightness to its 16 bit value.
+pub fn fraction_to_lifx(value: f64) -> u16 {
+    (value.clamp(0.0, 1.0) * LIFX_BRIGHTNESS_MAX as f64).round() as u16
+}
+
+fn round_to(value: f64, places: i32) -> f64 {
+    let factor = 10f64.powi(places);
+    (value * factor).round() / factor
+}
+
+fn parse_ranged(name: &str, value: &str, min: f64, max: f64) -> Result<f64> {
+    let parsed = parse_f64_safe(value).map_err(|_| invalid(format!("Invalid {} value: {}", name, value)))?;
+    if !parsed.is_finite() || parsed < min || parsed > max {
+        return Err(invalid(format!("{} must be between {} and {}", name, min, max)));
+    }
+    Ok(parsed)
+}
+
+fn rgb_component(value: &str) -> Result<u8> {
+    match parse_i64_safe(value.trim()) {
+        Ok(v) if (0..=255).contains(&v) => Ok(v as u8),
+        _ => Err(invalid(format!("RGB components must be between 0 and 255, got {}", value))),
+    }
+}
+
+fn invalid(message: String) -> LifxError {
+    LifxError::ValidationError(message)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(color: &str) -> HSBK {
+        parse_color(color, None).unwrap()
+    }
+
+    #[test]
+    fn test_named_colors() {
+        let red = parse("red");
+        assert_eq!(red.hue, HUE_RED);
+        assert_eq!(red.saturation, 65535);
+        assert_eq!(red.brightness, 65535);
+
+        assert_eq!(parse("green").hue, HUE_GREEN);
+        assert_eq!(parse("Blue").hue, HUE_BLUE);
+        assert_eq!(parse("pink").hue, HUE_PINK);
+        assert_eq!(parse("white").saturation, 0);
+    }
+
+    #[test]
+    fn test_hsbk_components() {
+        let color = parse("hue:120 saturation:1.0 brightness:0.5");
+        assert_eq!(color.hue, HUE_GREEN);
+        assert_eq!(color.saturation, 65535);
+        assert_eq!(color.brightness, 32768);
+
+        assert_eq!(parse("hue:180").hue, HUE_CYAN);
+        assert_eq!(parse("hue:360").hue, 0);
+
+        let kelvin = parse("kelvin:2700");
+        assert_eq!(kelvin.kelvin, 2700);
+        assert_eq!(kelvin.saturation, 0);
+
+        let combined = parse("kelvin:5000 brightness:0.25");
+        assert_eq!(combined.kelvin, 5000);
+        assert_eq!(combined.brightness, 16384);
+    }
+
+    #[test]
+    fn test_rgb_and_hex_use_hsv() {
+        let red = parse("rgb:255,0,0");
+        assert_eq!((red.hue, red.saturation, red.brightness), (0, 65535, 65535));
+
+        // HSV value, not HSL lightness: dark blue keeps full saturation at half brightness
+        let navy = parse("rgb:0,0,128");
+        assert_eq!(navy.hue, 43691);
+        assert_eq!(navy.saturation, 65535);
+        assert_eq!(navy.brightness, 32896);
+
+        let hex = parse("#00FF00");
+        assert_eq!((hex.hue, hex.saturation, hex.brightness), (HUE_GREEN, 65535, 65535));
+        assert_eq!(parse("#ffffff").saturation, 0);
+    }
+
+    #[test]
+    fn test_later_components_override() {
+        let color = parse("red saturation:0.5");
+        assert_eq!(color.hue, HUE_RED);
+        assert_eq!(color.saturation, 32768);
+
+        let color = parse("kelvin:3000 hue:240 saturation:1");
+        assert_eq!(color.saturation, 65535);
+        assert_eq!(color.kelvin, 3000);
+    }
+
+    #[test]
+    fn test_unmentioned_components_keep_current() {
+        let current = LifxColor { hue: 1000, saturation: 2000, kelvin: 4000, brightness: 3000 };
+
+        let color = parse_color("brightness:1.0", Some(&current)).unwrap();
+        assert_eq!((color.hue, color.saturation, color.brightness, color.kelvin), (1000, 2000, 65535, 4000));
+
+        let color = parse_color("hue:0", Some(&current)).unwrap();
+        assert_eq!((color.hue, color.saturation, color.brightness), (0, 2000, 3000));
+    }
+
+    #[test]
+    fn test_out_of_range_values_are_rejected() {
+        for color in &[
+            "hue:361", "hue:-1", "saturation:1.5", "brightness:-0.1", "kelvin:1000", "kelvin:10000",
+            "rgb:256,0,0", "rgb:0,0", "#12345", "#gggggg", "hue:abc", "rainbow", "", "red nonsense",
+        ] {
+            assert!(ColorSpec::parse(color).is_err(), "{:?} should be rejected", color);
+        }
+    }
+
+    #[test]
+    fn test_color_description() {
+        let description = ColorDescription::from(ColorSpec::parse("hue:120 saturation:1.0 brightness:0.5").unwrap());
+        assert_eq!(description, ColorDescription {
+            hue: Some(120.0),
+            saturation: Some(1.0),
+            brightness: Some(0.5),
+            kelvin: None,
+        });
+
+        let description = ColorDescription::from(ColorSpec::parse("kelvin:5000").unwrap());
+        assert_eq!(description.hue, None);
+        assert_eq!(description.saturation, Some(0.0));
+        assert_eq!(description.kelvin, Some(5000));
+
+        let json = serde_json::to_value(ColorDescription::from(ColorSpec::parse("red").unwrap())).unwrap();
+        assert_eq!(json["hue"], 0.0);
+        assert!(json["brightness"].is_null());
+    }
+
+    #[test]
+    fn test_errors_are_validation_errors() {
+        match ColorSpec::parse("kelvin:100") {
+            Err(LifxError::ValidationError(message)) => assert!(message.contains("kelvin")),
+            other => panic!("Unexpected result {:?}", other),
+        }
+    }
+}
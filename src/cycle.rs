@@ -1,11 +1,44 @@
 use serde::{Deserialize, Serialize};
-use lifx_rs::lan::{Waveform, HSBK, Message, BuildOptions, RawMessage};
-use crate::{BulbInfo, Manager};
+use std::time::Instant;
+use lifx_rs::lan::HSBK;
+use crate::color::{base_color, fraction_to_lifx, ColorSpec};
+use crate::error::{LifxError, Result};
+use crate::{delivery, BulbInfo, LifxColor, Manager};
+
+/// How far (in LIFX units, out of 65535) a bulb may drift from a state and still match it.
+const MATCH_TOLERANCE: u16 = 1000;
+/// Kelvin values are reported rounded, so allow a small difference.
+const KELVIN_TOLERANCE: u16 = 50;
 
 #[derive(Deserialize, Debug, Clone)]
 pub struct CycleRequest {
     pub states: Vec<CycleState>,
     pub defaults: Option<CycleDefaults>,
+    /// `forward` (default) or `backward`.
+    pub direction: Option<String>,
+    /// Don't wait for the bulbs to acknowledge.
+    pub fast: Option<bool>,
+}
+
+impl CycleRequest {
+    pub fn validate(&self) -> Result<()> {
+        if self.states.is_empty() {
+            return Err(LifxError::ValidationError("Cycle states cannot be empty".to_string()));
+        }
+        if self.states.len() > 5 {
+            return Err(LifxError::ValidationError("Cycle supports at most 5 states".to_string()));
+        }
+        match self.direction.as_deref() {
+            None | Some("forward") | Some("backward") => Ok(()),
+            Some(other) => Err(LifxError::ValidationError(format!(
+                "Invalid direction '{}', expected 'forward' or 'backward'", other
+            ))),
+        }
+    }
+
+    fn is_backward(&self) -> bool {
+        self.direction.as_deref() == Some("backward")
+    }
 }
 
 #[derive(Deserialize, Debug, Clone)]
@@ -42,39 +75,76 @@ impl CycleHandler {
         CycleHandler
     }
 
+    /// Moves the selected bulbs to the state after the one they currently match.
+    ///
+    /// The current state is taken from the first bulb with a known color so every bulb
+    /// lands on the same step; bulbs matching no state start at the beginning of the cycle.
+    /// Waits for acks through `delivery::confirm` unless the request is `fast`, so the bulbs
+    /// lock must not be held.
     pub fn handle_cycle(
         &self,
         mgr: &Manager,
         bulbs: &[&BulbInfo],
         request: CycleRequest,
     ) -> CycleResponse {
-        let mut results = Vec::new();
-        
-        for bulb in bulbs {
-            let result = self.apply_cycle(mgr, bulb, &request);
-            results.push(CycleResult {
+        let current = bulbs.iter().find_map(|bulb| bulb.lifx_color.as_ref());
+        let index = self.next_state_index(&request, current);
+
+        let sent_at = Instant::now();
+        let sent: Vec<_> = bulbs.iter().map(|bulb| {
+            let result = self.apply_cycle(mgr, bulb, &request, index);
+            if let Err(ref e) = result {
+                log::error!("Failed to cycle {}: {}", bulb.label, e);
+            }
+            result
+        }).collect();
+        let statuses = delivery::confirm_unless_fast(&mgr.sock, bulbs, &sent, sent_at, request.fast.unwrap_or(false));
+
+        let results = bulbs.iter().zip(statuses).map(|(bulb, status)| {
+            CycleResult {
                 id: bulb.id.clone(),
                 label: bulb.label.clone(),
-                status: if result.is_ok() { "ok".to_string() } else { "error".to_string() },
-            });
-        }
+                status: status.map_or("error", |s| s.as_str()).to_string(),
+            }
+        }).collect();
         
         CycleResponse { results }
     }
 
+    /// Index of the state to apply next, given the color the bulbs currently show.
+    fn next_state_index(&self, request: &CycleRequest, current: Option<&LifxColor>) -> usize {
+        let count = request.states.len();
+        if count == 0 {
+            return 0;
+        }
+
+        let matched = current.and_then(|current| {
+            request.states.iter().position(|state| {
+                self.parse_cycle_state(state, Some(current), request.defaults.as_ref())
+                    .map_or(false, |target| color_matches(&target, current))
+            })
+        });
+
+        match (matched, request.is_backward()) {
+            (Some(i), false) => (i + 1) % count,
+            (Some(i), true) => (i + count - 1) % count,
+            (None, false) => 0,
+            (None, true) => count - 1,
+        }
+    }
+
     fn apply_cycle(
         &self,
         mgr: &Manager,
         bulb: &BulbInfo,
         request: &CycleRequest,
-    ) -> Result<(), String> {
-        if request.states.is_empty() {
-            return Err("Cycle states cannot be empty".to_string());
-        }
+        index: usize,
+    ) -> std::result::Result<(), String> {
+        let state = request.states.get(index)
+            .ok_or_else(|| "Cycle states cannot be empty".to_string())?;
         
         let defaults = request.defaults.as_ref();
         let default_duration = defaults.and_then(|d| d.duration).unwrap_or(1.0);
-        let default_brightness = defaults.and_then(|d| d.brightness);
         
         if let Some(ref defaults) = request.defaults {
             if let Some(ref power) = defaults.power {
@@ -89,124 +159,53 @@ impl CycleHandler {
             }
         }
         
-        let total_duration: f64 = request.states.iter()
-            .map(|s| s.duration.unwrap_or(default_duration))
-            .sum();
-        
-        let period = (total_duration * 1000.0) as u32;
-        let cycles = 1.0;
+        let target_color = self.parse_cycle_state(state, bulb.lifx_color.as_ref(), defaults)?;
+        let duration_ms = (state.duration.unwrap_or(default_duration).max(0.0) * 1000.0) as u32;
         
-        let current = bulb.lifx_color.as_ref();
-        let first_state = &request.states[0];
-        let target_color = self.parse_cycle_state(first_state, current, default_brightness)?;
-        
-        let options = BuildOptions {
-            target: Some(bulb.target),
-            res_required: true,
-            source: bulb.source,
-            ..Default::default()
-        };
-        
-        let message = Message::SetWaveform {
-            reserved: 0,
-            transient: false,
-            color: target_color,
-            period,
-            cycles,
-            skew_ratio: 0,
-            waveform: Waveform::Triangle,
-        };
-        
-        let raw_message = RawMessage::build(&options, message)
-            .map_err(|e| format!("Failed to build message: {:?}", e))?;
-        
-        mgr.sock.send_to(&raw_message.pack().map_err(|e| format!("Failed to pack message: {:?}", e))?, bulb.addr)
-            .map_err(|e| format!("Failed to send message: {:?}", e))?;
+        bulb.set_color(&mgr.sock, target_color, duration_ms)
+            .map_err(|e| format!("Failed to set color: {:?}", e))?;
         
         Ok(())
     }
 
+    /// Target color of `state`, with the request's default brightness and saturation filling in
+    /// what the state leaves out.
     fn parse_cycle_state(
         &self,
         state: &CycleState,
-        current: Option<&crate::LifxColor>,
-        default_brightness: Option<f64>,
-    ) -> Result<HSBK, String> {
-        let mut hue = current.map_or(0, |c| c.hue);
-        let mut saturation = current.map_or(0, |c| c.saturation);
-        let brightness = state.brightness
-            .or(default_brightness)
-            .map(|b| (b * 65535.0) as u16)
-            .or_else(|| current.map(|c| c.brightness))
-            .unwrap_or(65535);
-        let kelvin = current.map_or(3500, |c| c.kelvin);
-        
-        if let Some(ref color) = state.color {
-            match color.as_str() {
-                "white" => {
-                    saturation = 0;
-                    hue = 0;
-                },
-                "red" => {
-                    hue = 0;
-                    saturation = 65535;
-                },
-                "orange" => {
-                    hue = 7098;
-                    saturation = 65535;
-                },
-                "yellow" => {
-                    hue = 10920;
-                    saturation = 65535;
-                },
-                "cyan" => {
-                    hue = 32760;
-                    saturation = 65535;
-                },
-                "green" => {
-                    hue = 21840;
-                    saturation = 65535;
-                },
-                "blue" => {
-                    hue = 43680;
-                    saturation = 65535;
-                },
-                "purple" => {
-                    hue = 50050;
-                    saturation = 65535;
-                },
-                "pink" => {
-                    hue = 63700;
-                    saturation = 25000;
-                },
-                s if s.starts_with("hue:") => {
-                    let h = s.strip_prefix("hue:")
-                        .and_then(|v| v.parse::<f64>().ok())
-                        .ok_or_else(|| "Invalid hue value".to_string())?;
-                    hue = ((h * 65535.0 / 360.0) as u16).min(65535);
-                },
-                s if s.starts_with("saturation:") => {
-                    let sat = s.strip_prefix("saturation:")
-                        .and_then(|v| v.parse::<f64>().ok())
-                        .ok_or_else(|| "Invalid saturation value".to_string())?;
-                    saturation = ((sat * 65535.0) as u16).min(65535);
-                },
-                s if s.starts_with("kelvin:") => {
-                    let k = s.strip_prefix("kelvin:")
-                        .and_then(|v| v.parse::<u16>().ok())
-                        .ok_or_else(|| "Invalid kelvin value".to_string())?;
-                    return Ok(HSBK {
-                        hue: 0,
-                        saturation: 0,
-                        brightness,
-                        kelvin: k.clamp(1500, 9000),
-                    });
-                },
-                _ => return Err(format!("Unknown color: {}", color)),
-            }
+        current: Option<&LifxColor>,
+        defaults: Option<&CycleDefaults>,
+    ) -> std::result::Result<HSBK, String> {
+        let mut spec = match state.color {
+            Some(ref color) => ColorSpec::parse(color).map_err(|e| e.to_string())?,
+            None => ColorSpec::default(),
+        };
+        if let Some(brightness) = state.brightness.or_else(|| defaults.and_then(|d| d.brightness)) {
+            spec.brightness = Some(fraction_to_lifx(brightness));
+        }
+        if spec.saturation.is_none() {
+            spec.saturation = defaults.and_then(|d| d.saturation).map(fraction_to_lifx);
         }
         
-        Ok(HSBK { hue, saturation, brightness, kelvin })
+        Ok(spec.apply_to(base_color(current)))
+    }
+}
+
+/// Whether a bulb showing `current` is already in the `target` state.
+fn color_matches(target: &HSBK, current: &LifxColor) -> bool {
+    let close = |a: u16, b: u16| a.max(b) - a.min(b) <= MATCH_TOLERANCE;
+    // Hue wraps around, so 65535 and 0 are neighbours
+    let hue_diff = target.hue.max(current.hue) - target.hue.min(current.hue);
+    let hue_close = hue_diff.min(u16::MAX - hue_diff) <= MATCH_TOLERANCE;
+
+    if !close(target.brightness, current.brightness) || !close(target.saturation, current.saturation) {
+        return false;
+    }
+    if target.saturation == 0 {
+        // Hue is meaningless for whites, kelvin decides instead
+        target.kelvin.max(current.kelvin) - target.kelvin.min(current.kelvin) <= KELVIN_TOLERANCE
+    } else {
+        hue_close
     }
 }
 
@@ -241,6 +240,8 @@ mod tests {
                 brightness: Some(0.8),
                 duration: Some(1.5),
             }),
+            direction: None,
+            fast: None,
         };
         
         assert_eq!(request.states.len(), 2);
@@ -259,7 +260,7 @@ mod tests {
         };
         
         let hsbk = handler.parse_cycle_state(&state, None, None).unwrap();
-        assert_eq!(hsbk.hue, 21840); // Green hue
+        assert_eq!(hsbk.hue, crate::HUE_GREEN);
         assert_eq!(hsbk.saturation, 65535);
         assert_eq!(hsbk.brightness, (0.75 * 65535.0) as u16);
     }
@@ -274,9 +275,16 @@ mod tests {
             duration: Some(1.0),
         };
         
-        let hsbk = handler.parse_cycle_state(&state, None, Some(0.6)).unwrap();
+        let defaults = CycleDefaults { power: None, saturation: Some(0.25), brightness: Some(0.6), duration: None };
+        let hsbk = handler.parse_cycle_state(&state, None, Some(&defaults)).unwrap();
         assert_eq!(hsbk.hue, 0); // Red hue
         assert_eq!(hsbk.brightness, (0.6 * 65535.0) as u16);
+        // Red sets its own saturation
+        assert_eq!(hsbk.saturation, 65535);
+
+        let uncolored = CycleState { color: None, brightness: None, duration: None };
+        let hsbk = handler.parse_cycle_state(&uncolored, None, Some(&defaults)).unwrap();
+        assert_eq!(hsbk.saturation, fraction_to_lifx(0.25));
     }
     
     #[test]
@@ -308,4 +316,96 @@ mod tests {
         let result = handler.parse_cycle_state(&state, None, None);
         assert!(result.is_err());
     }
-}
\ No newline at end of file
+
+    fn state(color: &str) -> CycleState {
+        CycleState {
+            color: Some(color.to_string()),
+            brightness: Some(1.0),
+            duration: Some(1.0),
+        }
+    }
+
+    fn request(direction: Option<&str>) -> CycleRequest {
+        CycleRequest {
+            states: vec![state("red"), state("green"), state("blue")],
+            defaults: None,
+            direction: direction.map(|d| d.to_string()),
+            fast: None,
+        }
+    }
+
+    fn color(hue: u16, saturation: u16, brightness: u16) -> LifxColor {
+        LifxColor { hue, saturation, kelvin: 3500, brightness }
+    }
+
+    #[test]
+    fn test_next_state_forward() {
+        let handler = CycleHandler::new();
+        let request = request(None);
+
+        // Green (21840) -> blue
+        assert_eq!(handler.next_state_index(&request, Some(&color(21840, 65535, 65535))), 2);
+        // Blue wraps around to red
+        assert_eq!(handler.next_state_index(&request, Some(&color(43680, 65535, 65535))), 0);
+    }
+
+    #[test]
+    fn test_next_state_backward() {
+        let handler = CycleHandler::new();
+        let request = request(Some("backward"));
+
+        assert_eq!(handler.next_state_index(&request, Some(&color(21840, 65535, 65535))), 0);
+        assert_eq!(handler.next_state_index(&request, Some(&color(0, 65535, 65535))), 2);
+    }
+
+    #[test]
+    fn test_next_state_on_multizone_bulb() {
+        use crate::{LiColor, RefreshableData};
+        use lifx_rs::lan::Message;
+        use std::time::Duration;
+
+        let handler = CycleHandler::new();
+        let mut strip = BulbInfo::new(0x1234, 1, "192.168.1.100:56700".parse().unwrap());
+        let green = HSBK { hue: 21840, saturation: 65535, brightness: 65535, kelvin: 3500 };
+        let mut zones = RefreshableData::empty(Duration::from_secs(15), Message::GetColorZones { start_index: 0, end_index: 255 });
+        zones.update(vec![Some(green); 8]);
+        strip.color = LiColor::Multi(zones);
+        strip.update_zones();
+
+        // The strip matches green, so it moves on to blue rather than restarting at red
+        assert_eq!(handler.next_state_index(&request(None), strip.lifx_color.as_ref()), 2);
+    }
+
+    #[test]
+    fn test_next_state_without_match_starts_cycle() {
+        let handler = CycleHandler::new();
+
+        // Dimmed green does not match the full brightness green state
+        let dim_green = color(21840, 65535, 20000);
+        assert_eq!(handler.next_state_index(&request(None), Some(&dim_green)), 0);
+        assert_eq!(handler.next_state_index(&request(Some("backward")), Some(&dim_green)), 2);
+        assert_eq!(handler.next_state_index(&request(None), None), 0);
+    }
+
+    #[test]
+    fn test_color_matches_tolerance() {
+        let target = HSBK { hue: 100, saturation: 65535, brightness: 65535, kelvin: 3500 };
+
+        assert!(color_matches(&target, &color(65300, 65535, 65000)));
+        assert!(!color_matches(&target, &color(5000, 65535, 65535)));
+
+        let white = HSBK { hue: 0, saturation: 0, brightness: 65535, kelvin: 2700 };
+        assert!(color_matches(&white, &LifxColor { hue: 30000, saturation: 0, kelvin: 2700, brightness: 65535 }));
+        assert!(!color_matches(&white, &color(0, 0, 65535)));
+    }
+
+    #[test]
+    fn test_cycle_request_validation() {
+        assert!(request(None).validate().is_ok());
+        assert!(request(Some("backward")).validate().is_ok());
+        assert!(request(Some("sideways")).validate().is_err());
+
+        let empty = CycleRequest { states: vec![], defaults: None, direction: None, fast: None };
+        assert!(empty.validate().is_err());
+    }
+}
@@ -0,0 +1,319 @@
+//! Acknowledged delivery of Set* messages.
+//!
+//! Every Set* message is sent with `ack_required` and a per-bulb sequence number. The receive
+//! worker marks messages as acknowledged, and [`confirm`] resends whatever is still missing
+//! before reporting a per-bulb [`DeliveryStatus`].
+//!
+//! A request is served on a single thread from start to finish, so packets are remembered with
+//! the thread that sent them: a request only waits for its own packets, not for those another
+//! request sent to the same bulb meanwhile.
+
+use serde::Serialize;
+use std::collections::HashMap;
+use std::net::{SocketAddr, UdpSocket};
+use std::sync::Mutex;
+use std::thread::{self, ThreadId};
+use std::time::{Duration, Instant};
+use lifx_rs::lan::Message;
+use log::{debug, warn};
+use crate::protocol::ExtMessage;
+use crate::BulbInfo;
+
+/// How long to wait for acks before resending.
+pub const ACK_TIMEOUT: Duration = Duration::from_millis(500);
+/// Number of times an unacknowledged message is resent.
+pub const MAX_RETRIES: u32 = 2;
+/// Packets nobody waited for are dropped after this long.
+const PENDING_MAX_AGE: Duration = Duration::from_secs(10);
+const POLL_INTERVAL: Duration = Duration::from_millis(20);
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum DeliveryStatus {
+    Ok,
+    TimedOut,
+    Offline,
+}
+
+impl DeliveryStatus {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            DeliveryStatus::Ok => "ok",
+            DeliveryStatus::TimedOut => "timed_out",
+            DeliveryStatus::Offline => "offline",
+        }
+    }
+}
+
+/// Sequence numbers and unacknowledged packets of one bulb.
+///
+/// Shared through an `Arc` by every copy of a `BulbInfo`, so handlers working on a snapshot
+/// see the acks recorded by the receive worker.
+#[derive(Debug, Default)]
+pub struct Delivery {
+    state: Mutex<DeliveryState>,
+}
+
+#[derive(Debug, Default)]
+struct DeliveryState {
+    next_sequence: u8,
+    /// Set* messages sent so far; unlike the sequence number it never wraps.
+    sent: u64,
+    /// Packets waiting for an ack, by sequence number.
+    pending: HashMap<u8, Pending>,
+}
+
+#[derive(Debug)]
+struct Pending {
+    sent_at: Instant,
+    sender: ThreadId,
+    packet: Vec<u8>,
+    /// The Set* message, so its change can be cached once acknowledged.
+    message: Option<SetMessage>,
+}
+
+/// A Set* message sent through either `lifx_rs` or `protocol`.
+#[derive(Debug, Clone)]
+pub enum SetMessage {
+    Lan(Message),
+    Ext(ExtMessage),
+}
+
+impl From<Message> for SetMessage {
+    fn from(msg: Message) -> Self {
+        SetMessage::Lan(msg)
+    }
+}
+
+impl From<ExtMessage> for SetMessage {
+    fn from(msg: ExtMessage) -> Self {
+        SetMessage::Ext(msg)
+    }
+}
+
+impl Delivery {
+    pub fn new() -> Self {
+        Delivery::default()
+    }
+
+    /// Allocates the sequence number for the next packet.
+    pub fn next_sequence(&self) -> u8 {
+        let mut state = self.lock();
+        let sequence = state.next_sequence;
+        state.next_sequence = sequence.wrapping_add(1);
+        state.sent += 1;
+        sequence
+    }
+
+    /// How many Set* messages have been sent, to tell whether the bulb was changed since.
+    pub fn sent(&self) -> u64 {
+        self.lock().sent
+    }
+
+    /// Remembers a sent packet, and the message it carries, until its ack arrives.
+    pub fn track(&self, sequence: u8, packet: Vec<u8>, message: Option<SetMessage>) {
+        let mut state = self.lock();
+        state.pending.retain(|_, pending| pending.sent_at.elapsed() < PENDING_MAX_AGE);
+        state.pending.insert(sequence, Pending {
+            sent_at: Instant::now(),
+            sender: thread::current().id(),
+            packet,
+            message,
+        });
+    }
+
+    /// Marks a packet as delivered, returning the message it carried if one was tracked.
+    pub fn acknowledge(&self, sequence: u8) -> Option<SetMessage> {
+        self.lock().pending.remove(&sequence).and_then(|pending| pending.message)
+    }
+
+    /// Whether any packet this thread sent at or after `since` is still unacknowledged.
+    pub fn has_pending_since(&self, since: Instant) -> bool {
+        self.lock().pending.values().any(|pending| pending.is_own_since(since))
+    }
+
+    fn resend_since(&self, sock: &UdpSocket, addr: SocketAddr, since: Instant) {
+        let packets: Vec<Vec<u8>> = self.lock().pending.values()
+            .filter(|pending| pending.is_own_since(since))
+            .map(|pending| pending.packet.clone())
+            .collect();
+        for packet in packets {
+            if let Err(e) = sock.send_to(&packet, addr) {
+                warn!("Failed to resend packet to {}: {}", addr, e);
+            }
+        }
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, DeliveryState> {
+        // The state stays consistent even if a holder panicked, so carry on with it
+        self.state.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+impl Pending {
+    /// Whether the calling thread sent this packet at or after `since`.
+    fn is_own_since(&self, since: Instant) -> bool {
+        self.sent_at >= since && self.sender == thread::current().id()
+    }
+}
+
+/// Waits for every bulb to acknowledge what this thread sent to it since `since`, resending
+/// missing packets up to `MAX_RETRIES` times. Returns one status per bulb, in order; bulbs that were
+/// already considered disconnected are reported `offline` rather than `timed_out`.
+///
+/// The bulbs lock must not be held, or the worker cannot record the acks.
+pub(crate) fn confirm(sock: &UdpSocket, bulbs: &[&BulbInfo], since: Instant) -> Vec<DeliveryStatus> {
+    for attempt in 0..=MAX_RETRIES {
+        let deadline = Instant::now() + ACK_TIMEOUT;
+        while Instant::now() < deadline && bulbs.iter().any(|b| b.delivery.has_pending_since(since)) {
+            thread::sleep(POLL_INTERVAL);
+        }
+
+        let missing: Vec<&&BulbInfo> = bulbs.iter().filter(|b| b.delivery.has_pending_since(since)).collect();
+        if missing.is_empty() {
+            break;
+        }
+        if attempt < MAX_RETRIES {
+            for bulb in missing {
+                debug!("Resending unacknowledged packets to {}", bulb.addr);
+                bulb.delivery.resend_since(sock, bulb.addr, since);
+            }
+        }
+    }
+
+    bulbs.iter().map(|bulb| {
+        if !bulb.delivery.has_pending_since(since) {
+            DeliveryStatus::Ok
+        } else if !bulb.connected {
+            DeliveryStatus::Offline
+        } else {
+            DeliveryStatus::TimedOut
+        }
+    }).collect()
+}
+
+/// [`confirm`] for the bulbs whose messages went out; bulbs whose send failed get `None`.
+pub(crate) fn confirm_sent<T, E>(
+    sock: &UdpSocket,
+    bulbs: &[&BulbInfo],
+    sent: &[Result<T, E>],
+    since: Instant,
+) -> Vec<Option<DeliveryStatus>> {
+    let delivered: Vec<&BulbInfo> = bulbs.iter().zip(sent)
+        .filter(|(_, result)| result.is_ok())
+        .map(|(bulb, _)| *bulb)
+        .collect();
+    let mut statuses = confirm(sock, &delivered, since).into_iter();
+    sent.iter().map(|result| result.as_ref().ok().and_then(|_| statuses.next())).collect()
+}
+
+/// [`confirm_sent`], except that `fast` requests don't wait: every message that went out is
+/// reported `ok` straight away.
+pub(crate) fn confirm_unless_fast<T, E>(
+    sock: &UdpSocket,
+    bulbs: &[&BulbInfo],
+    sent: &[Result<T, E>],
+    since: Instant,
+    fast: bool,
+) -> Vec<Option<DeliveryStatus>> {
+    if fast {
+        return sent.iter().map(|result| result.as_ref().ok().map(|_| DeliveryStatus::Ok)).collect();
+    }
+    confirm_sent(sock, bulbs, sent, since)
+}
+
+/// Picks a random message source, so several servers on one network don't take each other's
+/// replies. 0 and 1 are avoided as some firmware treats them as broadcast sources.
+pub fn random_source() -> u32 {
+    loop {
+        let source: u32 = rand::random();
+        if source > 1 {
+            return source;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sequence_wraps() {
+        let delivery = Delivery::new();
+        for expected in 0..=255u8 {
+            assert_eq!(delivery.next_sequence(), expected);
+        }
+        assert_eq!(delivery.next_sequence(), 0);
+        assert_eq!(delivery.sent(), 257);
+    }
+
+    #[test]
+    fn test_ack_clears_pending() {
+        let delivery = Delivery::new();
+        let since = Instant::now();
+        let first = delivery.next_sequence();
+        let second = delivery.next_sequence();
+        delivery.track(first, vec![1], None);
+        delivery.track(second, vec![2], None);
+
+        delivery.acknowledge(first);
+        assert!(delivery.has_pending_since(since));
+        // Acks for unknown sequences are ignored
+        delivery.acknowledge(200);
+        assert!(delivery.has_pending_since(since));
+
+        delivery.acknowledge(second);
+        assert!(!delivery.has_pending_since(since));
+    }
+
+    #[test]
+    fn test_older_packets_are_not_waited_for() {
+        let delivery = Delivery::new();
+        delivery.track(delivery.next_sequence(), vec![1], None);
+
+        let since = Instant::now();
+        assert!(!delivery.has_pending_since(since));
+        delivery.track(delivery.next_sequence(), vec![2], None);
+        assert!(delivery.has_pending_since(since));
+    }
+
+    #[test]
+    fn test_other_requests_are_not_waited_for() {
+        let delivery = std::sync::Arc::new(Delivery::new());
+        let since = Instant::now();
+
+        let other = std::sync::Arc::clone(&delivery);
+        thread::spawn(move || other.track(other.next_sequence(), vec![1], None)).join().unwrap();
+        assert!(!delivery.has_pending_since(since));
+
+        delivery.track(delivery.next_sequence(), vec![2], None);
+        assert!(delivery.has_pending_since(since));
+    }
+
+    #[test]
+    fn test_random_source_skips_reserved_values() {
+        for _ in 0..1000 {
+            assert!(random_source() > 1);
+        }
+    }
+
+    #[test]
+    fn test_fast_requests_do_not_wait() {
+        let sock = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let bulb = BulbInfo::new(0x1234, 7, "127.0.0.1:9".parse().unwrap());
+        let since = Instant::now();
+        bulb.delivery.track(bulb.delivery.next_sequence(), vec![1], None);
+        let sent: Vec<Result<(), ()>> = vec![Ok(()), Err(())];
+
+        let statuses = confirm_unless_fast(&sock, &[&bulb, &bulb], &sent, since, true);
+        assert_eq!(statuses, vec![Some(DeliveryStatus::Ok), None]);
+        assert!(since.elapsed() < ACK_TIMEOUT);
+        assert!(bulb.delivery.has_pending_since(since));
+    }
+
+    #[test]
+    fn test_delivery_status_names() {
+        assert_eq!(DeliveryStatus::TimedOut.as_str(), "timed_out");
+        assert_eq!(serde_json::to_string(&DeliveryStatus::Offline).unwrap(), "\"offline\"");
+    }
+}
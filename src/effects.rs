@@ -1,6 +1,15 @@
 use serde::{Deserialize, Serialize};
-use lifx_rs::lan::{Waveform, HSBK, Message, BuildOptions, RawMessage};
-use crate::{BulbInfo, Manager};
+use std::net::UdpSocket;
+use std::thread;
+use std::time::{Duration, Instant};
+use lifx_rs::lan::{Waveform, HSBK, Message, PowerLevel};
+use crate::error::{LifxError, Result as LifxResult};
+use crate::protocol::{ExtMessage, MultiZoneEffectType, TileEffectType, TILE_EFFECT_PALETTE_SIZE};
+use crate::color::{base_color, parse_color, ColorSpec};
+use crate::{delivery, BulbInfo, Manager};
+
+/// Palette used by morph when the request does not provide one.
+const DEFAULT_MORPH_PALETTE: [&str; 7] = ["red", "orange", "yellow", "green", "cyan", "blue", "purple"];
 
 #[derive(Deserialize, Debug, Clone)]
 pub struct EffectRequest {
@@ -11,6 +20,66 @@ pub struct EffectRequest {
     pub persist: Option<bool>,
     pub power_on: Option<bool>,
     pub peak: Option<f64>,
+    /// Don't wait for the bulbs to acknowledge.
+    pub fast: Option<bool>,
+}
+
+/// Body of the firmware effect endpoints: `effects/move`, `effects/morph` and `effects/flame`.
+#[derive(Deserialize, Debug, Clone, Default)]
+pub struct FirmwareEffectRequest {
+    /// Seconds per cycle of the effect.
+    pub period: Option<f64>,
+    /// Seconds to run the effect for; it runs until turned off when omitted.
+    pub duration: Option<f64>,
+    /// Number of cycles to run a move effect for, instead of `duration`.
+    pub cycles: Option<f64>,
+    /// Move only: `forward` (default) or `backward`.
+    pub direction: Option<String>,
+    /// Morph only: up to 16 colors.
+    pub palette: Option<Vec<String>>,
+    pub power_on: Option<bool>,
+    /// Don't wait for the bulbs to acknowledge.
+    pub fast: Option<bool>,
+}
+
+impl FirmwareEffectRequest {
+    pub fn validate(&self) -> LifxResult<()> {
+        if let Some(period) = self.period {
+            if period <= 0.0 {
+                return Err(LifxError::ValidationError("period must be greater than 0".to_string()));
+            }
+        }
+        if self.duration.map_or(false, |d| d < 0.0) || self.cycles.map_or(false, |c| c < 0.0) {
+            return Err(LifxError::ValidationError("duration and cycles cannot be negative".to_string()));
+        }
+        match self.direction.as_deref() {
+            None | Some("forward") | Some("backward") => {}
+            Some(other) => {
+                return Err(LifxError::ValidationError(format!(
+                    "Invalid direction '{}', expected 'forward' or 'backward'", other
+                )))
+            }
+        }
+        if let Some(ref palette) = self.palette {
+            if palette.is_empty() || palette.len() > TILE_EFFECT_PALETTE_SIZE {
+                return Err(LifxError::ValidationError(format!(
+                    "palette needs between 1 and {} colors", TILE_EFFECT_PALETTE_SIZE
+                )));
+            }
+            for color in palette {
+                ColorSpec::parse(color)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Body of `effects/off`.
+#[derive(Deserialize, Debug, Clone, Default)]
+pub struct EffectsOffRequest {
+    pub power_off: Option<bool>,
+    /// Don't wait for the bulbs to acknowledge.
+    pub fast: Option<bool>,
 }
 
 #[derive(Serialize, Debug, Clone)]
@@ -32,114 +101,237 @@ impl EffectsHandler {
         EffectsHandler
     }
 
-    pub fn handle_pulse(
-        &self,
-        mgr: &Manager,
-        bulbs: &[&BulbInfo],
-        request: EffectRequest,
-    ) -> EffectsResponse {
-        let mut results = Vec::new();
-        
-        for bulb in bulbs {
-            let result = self.apply_pulse_effect(mgr, bulb, &request);
-            results.push(EffectResult {
+    /// Runs the pulse waveform, switching between the current color and `color`.
+    pub fn handle_pulse(&self, mgr: &Manager, bulbs: &[&BulbInfo], request: EffectRequest) -> EffectsResponse {
+        self.for_each_bulb(mgr, bulbs, request.fast, |bulb| self.apply_waveform_effect(mgr, bulb, &request, Waveform::Pulse))
+    }
+
+    /// Runs the breathe waveform, fading smoothly between the current color and `color`.
+    pub fn handle_breathe(&self, mgr: &Manager, bulbs: &[&BulbInfo], request: EffectRequest) -> EffectsResponse {
+        self.for_each_bulb(mgr, bulbs, request.fast, |bulb| self.apply_waveform_effect(mgr, bulb, &request, Waveform::Sine))
+    }
+
+    /// Flashes `color` in short pulses.
+    pub fn handle_strobe(&self, mgr: &Manager, bulbs: &[&BulbInfo], request: EffectRequest) -> EffectsResponse {
+        self.for_each_bulb(mgr, bulbs, request.fast, |bulb| self.apply_strobe_effect(mgr, bulb, &request))
+    }
+
+    /// Runs the firmware move effect on multizone devices.
+    pub fn handle_move(&self, mgr: &Manager, bulbs: &[&BulbInfo], request: FirmwareEffectRequest) -> EffectsResponse {
+        self.for_each_bulb(mgr, bulbs, request.fast, |bulb| self.apply_move_effect(mgr, bulb, &request))
+    }
+
+    /// Runs the firmware morph effect on matrix devices.
+    pub fn handle_morph(&self, mgr: &Manager, bulbs: &[&BulbInfo], request: FirmwareEffectRequest) -> EffectsResponse {
+        self.for_each_bulb(mgr, bulbs, request.fast, |bulb| self.apply_tile_effect(mgr, bulb, TileEffectType::Morph, &request))
+    }
+
+    /// Runs the firmware flame effect on matrix devices.
+    pub fn handle_flame(&self, mgr: &Manager, bulbs: &[&BulbInfo], request: FirmwareEffectRequest) -> EffectsResponse {
+        self.for_each_bulb(mgr, bulbs, request.fast, |bulb| self.apply_tile_effect(mgr, bulb, TileEffectType::Flame, &request))
+    }
+
+    /// Stops firmware effects and waveforms, optionally turning the lights off.
+    pub fn handle_off(&self, mgr: &Manager, bulbs: &[&BulbInfo], request: EffectsOffRequest) -> EffectsResponse {
+        self.for_each_bulb(mgr, bulbs, request.fast, |bulb| self.apply_effects_off(mgr, bulb, &request))
+    }
+
+    /// Runs `apply` on every bulb, then waits for acks through `delivery::confirm` unless the
+    /// request is `fast`, so the bulbs lock must not be held.
+    fn for_each_bulb<F>(&self, mgr: &Manager, bulbs: &[&BulbInfo], fast: Option<bool>, mut apply: F) -> EffectsResponse
+    where
+        F: FnMut(&BulbInfo) -> Result<(), String>,
+    {
+        let sent_at = Instant::now();
+        let sent: Vec<_> = bulbs.iter().map(|bulb| {
+            let result = apply(*bulb);
+            if let Err(ref e) = result {
+                log::warn!("Effect failed on {}: {}", bulb.label, e);
+            }
+            result
+        }).collect();
+        let statuses = delivery::confirm_unless_fast(&mgr.sock, bulbs, &sent, sent_at, fast.unwrap_or(false));
+
+        let results = bulbs.iter().zip(statuses).map(|(bulb, status)| {
+            EffectResult {
                 id: bulb.id.clone(),
                 label: bulb.label.clone(),
-                status: if result.is_ok() { "ok".to_string() } else { "error".to_string() },
-            });
-        }
-        
+                status: status.map_or("error", |s| s.as_str()).to_string(),
+            }
+        }).collect();
+
         EffectsResponse { results }
     }
 
-    pub fn handle_breathe(
+    fn apply_move_effect(
         &self,
         mgr: &Manager,
-        bulbs: &[&BulbInfo],
-        request: EffectRequest,
-    ) -> EffectsResponse {
-        let mut results = Vec::new();
-        
-        for bulb in bulbs {
-            let result = self.apply_breathe_effect(mgr, bulb, &request);
-            results.push(EffectResult {
-                id: bulb.id.clone(),
-                label: bulb.label.clone(),
-                status: if result.is_ok() { "ok".to_string() } else { "error".to_string() },
-            });
+        bulb: &BulbInfo,
+        request: &FirmwareEffectRequest,
+    ) -> Result<(), String> {
+        if !bulb.is_multizone() {
+            return Err("Device does not support multizone effects".to_string());
         }
-        
-        EffectsResponse { results }
+
+        let period = request.period.unwrap_or(1.0);
+        let duration = match (request.duration, request.cycles) {
+            (Some(duration), _) => duration,
+            (None, Some(cycles)) => cycles * period,
+            (None, None) => 0.0,
+        };
+        let mut parameters = [0; 8];
+        parameters[1] = if request.direction.as_deref() == Some("backward") { 1 } else { 0 };
+
+        self.power_on_if_requested(mgr, bulb, request.power_on)?;
+        let message = ExtMessage::SetMultiZoneEffect {
+            instance_id: rand::random(),
+            effect: MultiZoneEffectType::Move,
+            speed_ms: (period * 1000.0) as u32,
+            duration_ns: (duration * 1e9) as u64,
+            parameters,
+        };
+        bulb.send_ext(&mgr.sock, &message)
+            .map_err(|e| format!("Failed to send effect: {:?}", e))
     }
 
-    pub fn handle_strobe(
+    fn apply_tile_effect(
         &self,
         mgr: &Manager,
-        bulbs: &[&BulbInfo],
-        request: EffectRequest,
-    ) -> EffectsResponse {
-        let mut results = Vec::new();
-        
-        for bulb in bulbs {
-            let result = self.apply_strobe_effect(mgr, bulb, &request);
-            results.push(EffectResult {
-                id: bulb.id.clone(),
-                label: bulb.label.clone(),
-                status: if result.is_ok() { "ok".to_string() } else { "error".to_string() },
-            });
+        bulb: &BulbInfo,
+        effect: TileEffectType,
+        request: &FirmwareEffectRequest,
+    ) -> Result<(), String> {
+        if !bulb.has_matrix() {
+            return Err("Device does not support tile effects".to_string());
         }
-        
-        EffectsResponse { results }
+
+        let current = bulb.lifx_color.as_ref();
+        let palette = match (effect, &request.palette) {
+            (TileEffectType::Morph, Some(palette)) => palette.iter()
+                .map(|c| parse_color(c, current).map_err(|e| e.to_string()))
+                .collect::<Result<Vec<_>, _>>()?,
+            (TileEffectType::Morph, None) => DEFAULT_MORPH_PALETTE.iter()
+                .map(|c| parse_color(c, current).map_err(|e| e.to_string()))
+                .collect::<Result<Vec<_>, _>>()?,
+            _ => Vec::new(),
+        };
+
+        self.power_on_if_requested(mgr, bulb, request.power_on)?;
+        let message = ExtMessage::SetTileEffect {
+            instance_id: rand::random(),
+            effect,
+            speed_ms: (request.period.unwrap_or(5.0) * 1000.0) as u32,
+            duration_ns: (request.duration.unwrap_or(0.0) * 1e9) as u64,
+            palette,
+        };
+        bulb.send_ext(&mgr.sock, &message)
+            .map_err(|e| format!("Failed to send effect: {:?}", e))
     }
 
-    fn apply_pulse_effect(
+    fn apply_effects_off(
         &self,
         mgr: &Manager,
         bulb: &BulbInfo,
-        request: &EffectRequest,
+        request: &EffectsOffRequest,
     ) -> Result<(), String> {
-        let period = (request.period.unwrap_or(1.0) * 1000.0) as u32;
-        let cycles = request.cycles.unwrap_or(5.0) as f32;
-        let peak = request.peak.unwrap_or(0.5);
-        
-        let current_color = bulb.lifx_color.as_ref();
-        let _from_color = self.parse_color_or_current(request.from_color.as_deref(), current_color)?;
-        let to_color = self.parse_color_or_default(request.color.as_deref(), current_color)?;
-        
-        let transient = !request.persist.unwrap_or(false);
-        let skew_ratio = self.peak_to_skew_ratio(peak);
-        
-        let options = BuildOptions {
-            target: Some(bulb.target),
-            res_required: true,
-            source: bulb.source,
-            ..Default::default()
-        };
-        
-        let message = Message::SetWaveform {
-            reserved: 0,
-            transient,
-            color: to_color,
-            period,
-            cycles,
-            skew_ratio,
-            waveform: Waveform::Pulse,
-        };
-        
-        let raw_message = RawMessage::build(&options, message)
-            .map_err(|e| format!("Failed to build message: {:?}", e))?;
-        
-        mgr.sock.send_to(&raw_message.pack().map_err(|e| format!("Failed to pack message: {:?}", e))?, bulb.addr)
-            .map_err(|e| format!("Failed to send message: {:?}", e))?;
-        
+        if bulb.is_multizone() {
+            let message = ExtMessage::SetMultiZoneEffect {
+                instance_id: rand::random(),
+                effect: MultiZoneEffectType::Off,
+                speed_ms: 0,
+                duration_ns: 0,
+                parameters: [0; 8],
+            };
+            bulb.send_ext(&mgr.sock, &message)
+                .map_err(|e| format!("Failed to stop effect: {:?}", e))?;
+        } else if bulb.has_matrix() {
+            let message = ExtMessage::SetTileEffect {
+                instance_id: rand::random(),
+                effect: TileEffectType::Off,
+                speed_ms: 0,
+                duration_ns: 0,
+                palette: Vec::new(),
+            };
+            bulb.send_ext(&mgr.sock, &message)
+                .map_err(|e| format!("Failed to stop effect: {:?}", e))?;
+        } else if let Some(current) = bulb.lifx_color.as_ref() {
+            // Setting a color cancels any running waveform
+            let color = HSBK {
+                hue: current.hue,
+                saturation: current.saturation,
+                brightness: current.brightness,
+                kelvin: current.kelvin,
+            };
+            bulb.set_color(&mgr.sock, color, 0)
+                .map_err(|e| format!("Failed to stop waveform: {:?}", e))?;
+        }
+
+        if request.power_off.unwrap_or(false) {
+            bulb.set_power(&mgr.sock, PowerLevel::Standby)
+                .map_err(|e| format!("Failed to set power: {:?}", e))?;
+        }
+        Ok(())
+    }
+
+    /// Firmware effects are invisible on a light that is off, so turn it on unless told not to.
+    fn power_on_if_requested(&self, mgr: &Manager, bulb: &BulbInfo, power_on: Option<bool>) -> Result<(), String> {
+        if power_on.unwrap_or(true) {
+            bulb.set_power(&mgr.sock, PowerLevel::Enabled)
+                .map_err(|e| format!("Failed to set power: {:?}", e))?;
+        }
+        Ok(())
+    }
+
+    /// Shows `from_color` and powers the bulb on (the cloud default) before a waveform.
+    ///
+    /// Returns whether the bulb was switched on from off.
+    fn prepare_waveform(&self, mgr: &Manager, bulb: &BulbInfo, request: &EffectRequest) -> Result<bool, String> {
+        if let Some(ref from_color) = request.from_color {
+            let from_color = parse_color(from_color, bulb.lifx_color.as_ref())
+                .map_err(|e| e.to_string())?;
+            bulb.set_color(&mgr.sock, from_color, 0)
+                .map_err(|e| format!("Failed to set from_color: {:?}", e))?;
+        }
+
+        let was_off = bulb.power_level.as_ref() == Some(&PowerLevel::Standby);
+        let powered_on = was_off && request.power_on.unwrap_or(true);
+        if powered_on {
+            bulb.set_power(&mgr.sock, PowerLevel::Enabled)
+                .map_err(|e| format!("Failed to set power: {:?}", e))?;
+        }
+        Ok(powered_on)
+    }
+
+    /// Turns the bulb back off once a transient waveform has finished.
+    ///
+    /// Skipped if anything else was sent to the bulb in the meantime, such as a request turning
+    /// it on for good or another effect, which then restores power itself.
+    fn restore_power_after(&self, sock: &UdpSocket, bulb: &BulbInfo, delay: Duration) -> Result<(), String> {
+        let sock = sock.try_clone()
+            .map_err(|e| format!("Failed to clone socket: {:?}", e))?;
+        let bulb = bulb.clone();
+        let sent = bulb.delivery.sent();
+
+        thread::spawn(move || {
+            thread::sleep(delay);
+            if bulb.delivery.sent() != sent {
+                log::debug!("Not restoring power on {}, it was changed during the effect", bulb.label);
+                return;
+            }
+            if let Err(e) = bulb.set_power(&sock, PowerLevel::Standby) {
+                log::error!("Failed to restore power on {}: {:?}", bulb.label, e);
+            }
+        });
         Ok(())
     }
 
-    fn apply_breathe_effect(
+    /// Runs a pulse or breathe (sine) waveform.
+    fn apply_waveform_effect(
         &self,
         mgr: &Manager,
         bulb: &BulbInfo,
         request: &EffectRequest,
+        waveform: Waveform,
     ) -> Result<(), String> {
         let period = (request.period.unwrap_or(1.0) * 1000.0) as u32;
         let cycles = request.cycles.unwrap_or(5.0) as f32;
@@ -151,12 +343,7 @@ impl EffectsHandler {
         let transient = !request.persist.unwrap_or(false);
         let skew_ratio = self.peak_to_skew_ratio(peak);
         
-        let options = BuildOptions {
-            target: Some(bulb.target),
-            res_required: true,
-            source: bulb.source,
-            ..Default::default()
-        };
+        let powered_on = self.prepare_waveform(mgr, bulb, request)?;
         
         let message = Message::SetWaveform {
             reserved: 0,
@@ -165,14 +352,15 @@ impl EffectsHandler {
             period,
             cycles,
             skew_ratio,
-            waveform: Waveform::Sine,
+            waveform,
         };
         
-        let raw_message = RawMessage::build(&options, message)
-            .map_err(|e| format!("Failed to build message: {:?}", e))?;
+        bulb.send_set(&mgr.sock, message)
+            .map_err(|e| format!("Failed to send waveform: {:?}", e))?;
         
-        mgr.sock.send_to(&raw_message.pack().map_err(|e| format!("Failed to pack message: {:?}", e))?, bulb.addr)
-            .map_err(|e| format!("Failed to send message: {:?}", e))?;
+        if transient && powered_on {
+            self.restore_power_after(&mgr.sock, bulb, effect_duration(period, cycles))?;
+        }
         
         Ok(())
     }
@@ -192,13 +380,6 @@ impl EffectsHandler {
         let transient = !request.persist.unwrap_or(false);
         let skew_ratio = 0i16;
         
-        let options = BuildOptions {
-            target: Some(bulb.target),
-            res_required: true,
-            source: bulb.source,
-            ..Default::default()
-        };
-        
         let message = Message::SetWaveform {
             reserved: 0,
             transient,
@@ -209,177 +390,33 @@ impl EffectsHandler {
             waveform: Waveform::Pulse,
         };
         
-        let raw_message = RawMessage::build(&options, message)
-            .map_err(|e| format!("Failed to build message: {:?}", e))?;
-        
-        mgr.sock.send_to(&raw_message.pack().map_err(|e| format!("Failed to pack message: {:?}", e))?, bulb.addr)
-            .map_err(|e| format!("Failed to send message: {:?}", e))?;
+        bulb.send_set(&mgr.sock, message)
+            .map_err(|e| format!("Failed to send waveform: {:?}", e))?;
         
         Ok(())
     }
 
-    fn parse_color_or_current(
-        &self,
-        color_str: Option<&str>,
-        current: Option<&crate::LifxColor>,
-    ) -> Result<HSBK, String> {
-        if let Some(color) = color_str {
-            self.parse_color_string(color, current)
-        } else {
-            Ok(HSBK {
-                hue: current.map_or(0, |c| c.hue),
-                saturation: current.map_or(0, |c| c.saturation),
-                brightness: current.map_or(65535, |c| c.brightness),
-                kelvin: current.map_or(3500, |c| c.kelvin),
-            })
-        }
-    }
-
+    /// Parses `color_str` against the current color, or falls back to full brightness white.
     fn parse_color_or_default(
         &self,
         color_str: Option<&str>,
         current: Option<&crate::LifxColor>,
     ) -> Result<HSBK, String> {
-        if let Some(color) = color_str {
-            self.parse_color_string(color, current)
-        } else {
-            Ok(HSBK {
-                hue: 0,
-                saturation: 0,
-                brightness: 65535,
-                kelvin: 3500,
-            })
-        }
-    }
-
-    fn parse_color_string(
-        &self,
-        color_str: &str,
-        current: Option<&crate::LifxColor>,
-    ) -> Result<HSBK, String> {
-        let mut hue = current.map_or(0, |c| c.hue);
-        let mut saturation = current.map_or(0, |c| c.saturation);
-        let mut brightness = current.map_or(65535, |c| c.brightness);
-        let mut kelvin = current.map_or(3500, |c| c.kelvin);
-        
         match color_str {
-            "white" => {
-                saturation = 0;
-                hue = 0;
-            },
-            "red" => {
-                hue = 0;
-                saturation = 65535;
-            },
-            "orange" => {
-                hue = 7098;
-                saturation = 65535;
-            },
-            "yellow" => {
-                hue = 10920;
-                saturation = 65535;
-            },
-            "cyan" => {
-                hue = 32760;
-                saturation = 65535;
-            },
-            "green" => {
-                hue = 21840;
-                saturation = 65535;
-            },
-            "blue" => {
-                hue = 43680;
-                saturation = 65535;
-            },
-            "purple" => {
-                hue = 50050;
-                saturation = 65535;
-            },
-            "pink" => {
-                hue = 63700;
-                saturation = 25000;
-            },
-            s if s.starts_with("kelvin:") => {
-                let k = s.strip_prefix("kelvin:")
-                    .and_then(|v| v.parse::<u16>().ok())
-                    .ok_or_else(|| "Invalid kelvin value".to_string())?;
-                kelvin = k.clamp(1500, 9000);
-                saturation = 0;
-            },
-            s if s.starts_with("hue:") => {
-                let h = s.strip_prefix("hue:")
-                    .and_then(|v| v.parse::<f64>().ok())
-                    .ok_or_else(|| "Invalid hue value".to_string())?;
-                hue = ((h * 65535.0 / 360.0) as u16).min(65535);
-            },
-            s if s.starts_with("saturation:") => {
-                let sat = s.strip_prefix("saturation:")
-                    .and_then(|v| v.parse::<f64>().ok())
-                    .ok_or_else(|| "Invalid saturation value".to_string())?;
-                saturation = ((sat * 65535.0) as u16).min(65535);
-            },
-            s if s.starts_with("brightness:") => {
-                let br = s.strip_prefix("brightness:")
-                    .and_then(|v| v.parse::<f64>().ok())
-                    .ok_or_else(|| "Invalid brightness value".to_string())?;
-                brightness = ((br * 65535.0) as u16).min(65535);
-            },
-            s if s.starts_with("#") => {
-                let hex = s.strip_prefix("#").unwrap_or("");
-                if hex.len() != 6 {
-                    return Err("Hex color must be 6 characters".to_string());
-                }
-                
-                let r = u8::from_str_radix(&hex[0..2], 16).map_err(|_| "Invalid hex color")?;
-                let g = u8::from_str_radix(&hex[2..4], 16).map_err(|_| "Invalid hex color")?;
-                let b = u8::from_str_radix(&hex[4..6], 16).map_err(|_| "Invalid hex color")?;
-                
-                let (h, s, _) = self.rgb_to_hsl(r, g, b);
-                hue = (h * 65535.0 / 360.0) as u16;
-                saturation = (s * 65535.0) as u16;
-            },
-            _ => return Err(format!("Unknown color: {}", color_str)),
+            Some(color) => parse_color(color, current).map_err(|e| e.to_string()),
+            None => Ok(base_color(None)),
         }
-        
-        Ok(HSBK { hue, saturation, brightness, kelvin })
     }
 
     fn peak_to_skew_ratio(&self, peak: f64) -> i16 {
         let clamped = peak.max(0.0).min(1.0);
         ((clamped - 0.5) * 65535.0) as i16
     }
+}
 
-    fn rgb_to_hsl(&self, r: u8, g: u8, b: u8) -> (f64, f64, f64) {
-        let r = r as f64 / 255.0;
-        let g = g as f64 / 255.0;
-        let b = b as f64 / 255.0;
-        
-        let max = r.max(g).max(b);
-        let min = r.min(g).min(b);
-        let diff = max - min;
-        
-        let l = (max + min) / 2.0;
-        
-        if diff == 0.0 {
-            return (0.0, 0.0, l);
-        }
-        
-        let s = if l < 0.5 {
-            diff / (max + min)
-        } else {
-            diff / (2.0 - max - min)
-        };
-        
-        let h = if max == r {
-            ((g - b) / diff + if g < b { 6.0 } else { 0.0 }) / 6.0
-        } else if max == g {
-            ((b - r) / diff + 2.0) / 6.0
-        } else {
-            ((r - g) / diff + 4.0) / 6.0
-        };
-        
-        (h * 360.0, s, l)
-    }
+/// Total run time of a waveform of `cycles` periods of `period_ms`.
+fn effect_duration(period_ms: u32, cycles: f32) -> Duration {
+    Duration::from_millis((period_ms as f64 * cycles.max(0.0) as f64).round() as u64)
 }
 
 impl Default for EffectsHandler {
@@ -407,71 +444,21 @@ mod tests {
     }
     
     #[test]
-    fn test_rgb_to_hsl() {
-        let handler = EffectsHandler::new();
-        
-        // Test pure red
-        let (h, s, l) = handler.rgb_to_hsl(255, 0, 0);
-        assert!((h - 0.0).abs() < 0.1);
-        assert!((s - 1.0).abs() < 0.01);
-        assert!((l - 0.5).abs() < 0.01);
-        
-        // Test pure green
-        let (h, s, l) = handler.rgb_to_hsl(0, 255, 0);
-        assert!((h - 120.0).abs() < 0.1);
-        assert!((s - 1.0).abs() < 0.01);
-        assert!((l - 0.5).abs() < 0.01);
-        
-        // Test pure blue
-        let (h, s, l) = handler.rgb_to_hsl(0, 0, 255);
-        assert!((h - 240.0).abs() < 0.1);
-        assert!((s - 1.0).abs() < 0.01);
-        assert!((l - 0.5).abs() < 0.01);
-        
-        // Test white
-        let (_, s, l) = handler.rgb_to_hsl(255, 255, 255);
-        assert_eq!(s, 0.0);
-        assert!((l - 1.0).abs() < 0.01);
-        
-        // Test black
-        let (_, s, l) = handler.rgb_to_hsl(0, 0, 0);
-        assert_eq!(s, 0.0);
-        assert_eq!(l, 0.0);
-    }
-    
-    #[test]
-    fn test_parse_color_string() {
+    fn test_parse_color_or_default() {
         let handler = EffectsHandler::new();
-        
-        // Test named colors
-        let red = handler.parse_color_string("red", None).unwrap();
+        let current = crate::LifxColor { hue: 1000, saturation: 2000, kelvin: 4000, brightness: 3000 };
+
+        let red = handler.parse_color_or_default(Some("red"), Some(&current)).unwrap();
         assert_eq!(red.hue, 0);
         assert_eq!(red.saturation, 65535);
-        
-        let green = handler.parse_color_string("green", None).unwrap();
-        assert_eq!(green.hue, 21840);
-        assert_eq!(green.saturation, 65535);
-        
-        let white = handler.parse_color_string("white", None).unwrap();
-        assert_eq!(white.hue, 0);
+        assert_eq!(red.brightness, 3000);
+
+        let white = handler.parse_color_or_default(None, Some(&current)).unwrap();
         assert_eq!(white.saturation, 0);
-        
-        // Test kelvin
-        let kelvin = handler.parse_color_string("kelvin:3500", None).unwrap();
-        assert_eq!(kelvin.kelvin, 3500);
-        assert_eq!(kelvin.saturation, 0);
-        
-        // Test hue
-        let hue = handler.parse_color_string("hue:180", None).unwrap();
-        assert_eq!(hue.hue, 32767); // 180 degrees = 32767 in LIFX scale
-        
-        // Test hex color
-        let hex = handler.parse_color_string("#FF0000", None).unwrap();
-        assert_eq!(hex.hue, 0); // Red
-        assert_eq!(hex.saturation, 65535);
-        
-        // Test invalid color
-        assert!(handler.parse_color_string("invalid", None).is_err());
+        assert_eq!(white.brightness, 65535);
+        assert_eq!(white.kelvin, 3500);
+
+        assert!(handler.parse_color_or_default(Some("invalid"), None).is_err());
     }
     
     #[test]
@@ -484,10 +471,72 @@ mod tests {
             persist: Some(false),
             power_on: Some(true),
             peak: Some(0.5),
+            fast: None,
         };
         
         assert_eq!(request.color.unwrap(), "red");
         assert_eq!(request.period.unwrap(), 1.0);
         assert_eq!(request.cycles.unwrap(), 5.0);
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_firmware_effect_request_validation() {
+        let request: FirmwareEffectRequest = serde_json::from_str(
+            r#"{"period": 2.0, "direction": "backward", "palette": ["red", "blue"]}"#
+        ).unwrap();
+        assert!(request.validate().is_ok());
+        assert!(FirmwareEffectRequest::default().validate().is_ok());
+
+        let bad_direction = FirmwareEffectRequest { direction: Some("up".to_string()), ..Default::default() };
+        assert!(bad_direction.validate().is_err());
+
+        let bad_period = FirmwareEffectRequest { period: Some(0.0), ..Default::default() };
+        assert!(bad_period.validate().is_err());
+
+        let big_palette = FirmwareEffectRequest { palette: Some(vec!["red".to_string(); 17]), ..Default::default() };
+        assert!(big_palette.validate().is_err());
+    }
+
+    #[test]
+    fn test_default_morph_palette_parses() {
+        for color in DEFAULT_MORPH_PALETTE.iter() {
+            assert!(parse_color(color, None).is_ok());
+        }
+    }
+
+    #[test]
+    fn test_effect_duration() {
+        assert_eq!(effect_duration(1000, 5.0), Duration::from_secs(5));
+        assert_eq!(effect_duration(500, 2.5), Duration::from_millis(1250));
+        assert_eq!(effect_duration(1000, -1.0), Duration::from_millis(0));
+    }
+
+    #[test]
+    fn test_power_is_not_restored_after_a_later_change() {
+        use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+
+        let device = UdpSocket::bind("127.0.0.1:0").unwrap();
+        device.set_read_timeout(Some(Duration::from_millis(300))).unwrap();
+        let sock = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), device.local_addr().unwrap().port());
+        let bulb = BulbInfo::new(0x1234, 0x0100_00d5_73d0, addr);
+        let handler = EffectsHandler::new();
+        let received = |device: &UdpSocket| {
+            let mut buf = [0; 1024];
+            let mut types = Vec::new();
+            while let Ok((len, _)) = device.recv_from(&mut buf) {
+                types.push(crate::protocol::decode_packet(&buf[..len]).unwrap().0.message_type);
+            }
+            types
+        };
+
+        // Left alone, the bulb is switched off (SetPower, 21) once the effect is over
+        handler.restore_power_after(&sock, &bulb, Duration::from_millis(50)).unwrap();
+        assert_eq!(received(&device), vec![21]);
+
+        // A color set (LightSetColor, 102) during the effect keeps it on
+        handler.restore_power_after(&sock, &bulb, Duration::from_millis(50)).unwrap();
+        bulb.set_color(&sock, base_color(None), 0).unwrap();
+        assert_eq!(received(&device), vec![102]);
+    }
+}
@@ -0,0 +1,320 @@
+//! Group and location management.
+//!
+//! Bulbs store their group and location themselves, each as a 16 byte id, a label and an
+//! `updated_at` timestamp. There is no registry: a group exists as long as a bulb is in it, and
+//! when bulbs disagree on its label the most recently updated one wins, as in the LIFX apps.
+
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+use lifx_rs::lan::{LifxIdent, LifxString, Message};
+use crate::error::{LifxError, Result};
+use crate::label::MAX_LABEL_BYTES;
+use crate::{delivery, hex_id, BulbInfo, Manager};
+
+/// Which of the two bulb collections a request is about.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Collection {
+    Group,
+    Location,
+}
+
+impl Collection {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Collection::Group => "group",
+            Collection::Location => "location",
+        }
+    }
+
+    /// The id, label and `updated_at` the bulb reports for this collection.
+    fn of<'a>(&self, bulb: &'a BulbInfo) -> Option<(&'a str, &'a str, u64)> {
+        match self {
+            Collection::Group => bulb.lifx_group.as_ref().map(|g| (g.id.as_str(), g.name.as_str(), g.updated_at)),
+            Collection::Location => bulb.lifx_location.as_ref().map(|l| (l.id.as_str(), l.name.as_str(), l.updated_at)),
+        }
+    }
+
+    fn set_message(&self, id: [u8; 16], name: &str, updated_at: u64) -> Message {
+        let label = LifxString(name.to_string());
+        match self {
+            Collection::Group => Message::SetGroup { group: LifxIdent(id), label, updated_at },
+            Collection::Location => Message::SetLocation { location: LifxIdent(id), label, updated_at },
+        }
+    }
+
+    fn query(&self) -> Message {
+        match self {
+            Collection::Group => Message::GetGroup,
+            Collection::Location => Message::GetLocation,
+        }
+    }
+}
+
+/// Body of `PUT /v1/groups/:id` and `POST /v1/lights/:selector/group` (and their location
+/// counterparts).
+#[derive(Deserialize, Debug, Clone)]
+pub struct GroupNameRequest {
+    pub name: String,
+}
+
+impl GroupNameRequest {
+    pub fn validate(&self) -> Result<()> {
+        if self.name.trim().is_empty() {
+            return Err(LifxError::ValidationError("name must not be empty".to_string()));
+        }
+        if self.name.len() > MAX_LABEL_BYTES {
+            return Err(LifxError::ValidationError(format!(
+                "name must be at most {} bytes, got {}", MAX_LABEL_BYTES, self.name.len()
+            )));
+        }
+        Ok(())
+    }
+}
+
+/// Body of `PUT /v1/lights/:selector/group` and `PUT /v1/lights/:selector/location`.
+#[derive(Deserialize, Debug, Clone)]
+pub struct GroupAssignRequest {
+    /// Id of an existing group or location.
+    pub id: String,
+}
+
+impl GroupAssignRequest {
+    pub fn validate(&self) -> Result<()> {
+        parse_id(&self.id).map(|_| ())
+    }
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct GroupMember {
+    pub id: String,
+    pub label: String,
+}
+
+/// A group or location as listed by `GET /v1/groups` and `GET /v1/locations`.
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct GroupInfo {
+    pub id: String,
+    pub name: String,
+    pub updated_at: u64,
+    pub lights: Vec<GroupMember>,
+}
+
+#[derive(Serialize, Debug)]
+pub struct GroupChangeResult {
+    pub id: String,
+    pub label: String,
+    pub status: String,
+    pub message: Option<String>,
+}
+
+/// The group or location after a change, and how each bulb took it.
+#[derive(Serialize)]
+pub struct GroupChangeResponse {
+    pub id: String,
+    pub name: String,
+    pub results: Vec<GroupChangeResult>,
+}
+
+pub struct GroupsHandler {
+    collection: Collection,
+}
+
+impl GroupsHandler {
+    pub fn new(collection: Collection) -> Self {
+        GroupsHandler { collection }
+    }
+
+    /// Every group (or location) reported by a known bulb, sorted by name.
+    pub fn list(&self, mgr: &Manager) -> Result<Vec<GroupInfo>> {
+        let bulbs = mgr.bulbs.lock()?;
+        Ok(self.collect(bulbs.values()))
+    }
+
+    /// Creates a group with a fresh id and moves the bulbs into it.
+    ///
+    /// Waits for acks through `delivery::confirm`, so the bulbs lock must not be held.
+    pub fn create(&self, mgr: &Manager, bulbs: &[&BulbInfo], request: GroupNameRequest) -> GroupChangeResponse {
+        let id: [u8; 16] = rand::random();
+        self.assign(mgr, bulbs, id, request.name)
+    }
+
+    /// Renames a group on every bulb in it.
+    pub fn rename(&self, mgr: &Manager, id: &str, request: GroupNameRequest) -> Result<GroupChangeResponse> {
+        let ident = parse_id(id)?;
+        let members: Vec<BulbInfo> = {
+            let bulbs = mgr.bulbs.lock()?;
+            bulbs.values()
+                .filter(|bulb| self.collection.of(bulb).map_or(false, |(bulb_id, _, _)| bulb_id.eq_ignore_ascii_case(id)))
+                .cloned()
+                .collect()
+        };
+        if members.is_empty() {
+            return Err(self.not_found(id));
+        }
+
+        let members: Vec<&BulbInfo> = members.iter().collect();
+        Ok(self.assign(mgr, &members, ident, request.name))
+    }
+
+    /// Moves the bulbs into an existing group, keeping its current name.
+    pub fn join(&self, mgr: &Manager, bulbs: &[&BulbInfo], request: GroupAssignRequest) -> Result<GroupChangeResponse> {
+        let ident = parse_id(&request.id)?;
+        let name = {
+            let all = mgr.bulbs.lock()?;
+            self.collect(all.values()).into_iter()
+                .find(|info| info.id.eq_ignore_ascii_case(&request.id))
+                .map(|info| info.name)
+                .ok_or_else(|| self.not_found(&request.id))?
+        };
+        Ok(self.assign(mgr, bulbs, ident, name))
+    }
+
+    /// Sends SetGroup/SetLocation with a new `updated_at`, so the change wins over what other
+    /// bulbs still report, then asks for the state to refresh the cached group.
+    fn assign(&self, mgr: &Manager, bulbs: &[&BulbInfo], id: [u8; 16], name: String) -> GroupChangeResponse {
+        let message = self.collection.set_message(id, &name, now_nanos());
+        let sent_at = Instant::now();
+        let sent: Vec<_> = bulbs.iter()
+            .map(|bulb| {
+                bulb.send_set(&mgr.sock, message.clone())
+                    .and_then(|_| bulb.send_query(&mgr.sock, self.collection.query()))
+            })
+            .collect();
+        let statuses = delivery::confirm_sent(&mgr.sock, bulbs, &sent, sent_at);
+
+        let results = bulbs.iter().zip(sent).zip(statuses).map(|((bulb, result), status)| {
+            GroupChangeResult {
+                id: bulb.id.clone(),
+                label: bulb.label.clone(),
+                status: status.map_or("error", |s| s.as_str()).to_string(),
+                message: result.err().map(|e| format!("Failed to set {}: {:?}", self.collection.name(), e)),
+            }
+        }).collect();
+
+        GroupChangeResponse { id: hex_id(&id), name, results }
+    }
+
+    fn collect<'a, I>(&self, bulbs: I) -> Vec<GroupInfo>
+    where
+        I: IntoIterator<Item = &'a BulbInfo>,
+    {
+        let mut groups: BTreeMap<String, GroupInfo> = BTreeMap::new();
+        for bulb in bulbs {
+            let (id, name, updated_at) = match self.collection.of(bulb) {
+                Some(group) => group,
+                None => continue,
+            };
+            let info = groups.entry(id.to_string()).or_insert_with(|| GroupInfo {
+                id: id.to_string(),
+                name: name.to_string(),
+                updated_at,
+                lights: Vec::new(),
+            });
+            if updated_at > info.updated_at {
+                info.name = name.to_string();
+                info.updated_at = updated_at;
+            }
+            info.lights.push(GroupMember { id: bulb.id.clone(), label: bulb.label.clone() });
+        }
+
+        let mut groups: Vec<GroupInfo> = groups.into_iter().map(|(_, mut info)| {
+            info.lights.sort_by(|a, b| a.id.cmp(&b.id));
+            info
+        }).collect();
+        groups.sort_by(|a, b| a.name.cmp(&b.name).then_with(|| a.id.cmp(&b.id)));
+        groups
+    }
+
+    fn not_found(&self, id: &str) -> LifxError {
+        LifxError::DeviceNotFound(format!("no bulb is in {} {}", self.collection.name(), id))
+    }
+}
+
+/// Parses a 32 character hex group or location id.
+fn parse_id(id: &str) -> Result<[u8; 16]> {
+    let invalid = || LifxError::ValidationError(format!("id must be 32 hex digits, got '{}'", id));
+    if id.len() != 32 || !id.chars().all(|c| c.is_ascii_hexdigit()) {
+        return Err(invalid());
+    }
+
+    let mut bytes = [0u8; 16];
+    for (i, byte) in bytes.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(&id[i * 2..i * 2 + 2], 16).map_err(|_| invalid())?;
+    }
+    Ok(bytes)
+}
+
+/// `updated_at` for a change made now, in nanoseconds since the epoch.
+fn now_nanos() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_nanos() as u64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+    use crate::{LifxGroup, LifxLocation};
+
+    const KITCHEN: &str = "4b4b4b4b4b4b4b4b4b4b4b4b4b4b4b4b";
+    const OFFICE: &str = "4f4f4f4f4f4f4f4f4f4f4f4f4f4f4f4f";
+
+    fn bulb(target: u64, label: &str, group: (&str, &str, u64)) -> BulbInfo {
+        let addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(192, 168, 1, 100)), 56700);
+        let mut bulb = BulbInfo::new(0x1234, target, addr);
+        bulb.label = label.to_string();
+        bulb.lifx_group = Some(LifxGroup { id: group.0.to_string(), name: group.1.to_string(), updated_at: group.2 });
+        bulb
+    }
+
+    #[test]
+    fn test_list_groups_newest_name_wins() {
+        let bulbs = vec![
+            bulb(1, "Stove", (KITCHEN, "Kitchen", 10)),
+            bulb(2, "Desk", (OFFICE, "Office", 10)),
+            bulb(3, "Pendant", (KITCHEN, "Cuisine", 20)),
+        ];
+        let groups = GroupsHandler::new(Collection::Group).collect(&bulbs);
+
+        assert_eq!(groups.len(), 2);
+        assert_eq!(groups[0].id, KITCHEN);
+        assert_eq!(groups[0].name, "Cuisine");
+        assert_eq!(groups[0].updated_at, 20);
+        assert_eq!(
+            groups[0].lights.iter().map(|l| l.label.as_str()).collect::<Vec<_>>(),
+            vec!["Stove", "Pendant"]
+        );
+        assert_eq!(groups[1].name, "Office");
+    }
+
+    #[test]
+    fn test_list_skips_bulbs_without_location() {
+        let mut located = bulb(1, "Stove", (KITCHEN, "Kitchen", 10));
+        located.lifx_location = Some(LifxLocation { id: OFFICE.to_string(), name: "Home".to_string(), updated_at: 5 });
+        let unknown = bulb(2, "Desk", (OFFICE, "Office", 10));
+
+        let locations = GroupsHandler::new(Collection::Location).collect(vec![&located, &unknown]);
+        assert_eq!(locations.len(), 1);
+        assert_eq!(locations[0].name, "Home");
+        assert_eq!(locations[0].lights.len(), 1);
+    }
+
+    #[test]
+    fn test_parse_id() {
+        let id = parse_id("000102030405060708090a0b0c0d0eff").unwrap();
+        assert_eq!(id[1], 1);
+        assert_eq!(id[15], 0xff);
+        assert_eq!(hex_id(&id), "000102030405060708090a0b0c0d0eff");
+
+        assert!(parse_id("0001").is_err());
+        assert!(parse_id("zz0102030405060708090a0b0c0d0eff").is_err());
+        assert!(GroupAssignRequest { id: "kitchen".to_string() }.validate().is_err());
+    }
+
+    #[test]
+    fn test_group_name_validation() {
+        assert!(GroupNameRequest { name: "Living Room".to_string() }.validate().is_ok());
+        assert!(GroupNameRequest { name: " ".to_string() }.validate().is_err());
+        assert!(GroupNameRequest { name: "x".repeat(MAX_LABEL_BYTES + 1) }.validate().is_err());
+    }
+}
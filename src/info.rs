@@ -0,0 +1,153 @@
+use serde::Serialize;
+use std::time::{Duration, Instant};
+use lifx_rs::lan::ProductInfo;
+use crate::{BulbInfo, Manager};
+
+/// How long to wait for a bulb that has not reported its Wi-Fi signal or uptime yet.
+const REPLY_TIMEOUT: Duration = Duration::from_secs(2);
+const NANOS_PER_SEC: u64 = 1_000_000_000;
+
+/// Diagnostics of one bulb, as returned by `GET /v1/lights/:selector/info`.
+#[derive(Serialize, Debug)]
+pub struct DeviceInfo {
+    pub id: String,
+    pub label: String,
+    pub status: String,
+    pub message: Option<String>,
+    pub ip: String,
+    pub port: u16,
+    pub vendor_id: Option<u32>,
+    pub product_id: Option<u32>,
+    pub product: Option<ProductInfo>,
+    /// Host (bulb) firmware as `major.minor`.
+    pub host_firmware: Option<String>,
+    /// Wi-Fi module firmware as `major.minor`.
+    pub wifi_firmware: Option<String>,
+    pub wifi_signal_dbm: Option<i32>,
+    /// Seconds since the bulb was powered on.
+    pub uptime: Option<u64>,
+    /// Seconds the bulb was powered off before its last power on.
+    pub downtime: Option<u64>,
+}
+
+#[derive(Serialize)]
+pub struct InfoResponse {
+    pub results: Vec<DeviceInfo>,
+}
+
+pub struct InfoHandler;
+
+impl InfoHandler {
+    pub fn new() -> Self {
+        InfoHandler
+    }
+
+    /// Reports the cached diagnostics of every bulb, waiting briefly for bulbs that have not
+    /// answered GetWifiInfo or GetInfo yet.
+    ///
+    /// The queries themselves are sent by `Manager::refresh`, like any other polled state.
+    /// Waits through `Manager::wait_for_bulb`, so the bulbs lock must not be held.
+    pub fn handle_info(&self, mgr: &Manager, bulbs: &[&BulbInfo]) -> InfoResponse {
+        let deadline = Instant::now() + REPLY_TIMEOUT;
+        let results = bulbs.iter().map(|bulb| {
+            let complete = |b: &BulbInfo| b.wifi_signal.as_ref().is_some() && b.uptime.as_ref().is_some();
+            if complete(bulb) {
+                return device_info(bulb);
+            }
+
+            let refreshed = mgr.wait_for_bulb(bulb.target, deadline, |b| {
+                if complete(b) { Some(device_info(b)) } else { None }
+            });
+            refreshed.unwrap_or_else(|| {
+                let mut info = device_info(bulb);
+                info.status = "timed_out".to_string();
+                info.message = Some("Device did not report its Wi-Fi signal and uptime".to_string());
+                info
+            })
+        }).collect();
+
+        InfoResponse { results }
+    }
+}
+
+impl Default for InfoHandler {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+fn device_info(bulb: &BulbInfo) -> DeviceInfo {
+    let model = bulb.model.as_ref();
+    let uptime = bulb.uptime.as_ref();
+    DeviceInfo {
+        id: bulb.id.clone(),
+        label: bulb.label.clone(),
+        status: "ok".to_string(),
+        message: None,
+        ip: bulb.addr.ip().to_string(),
+        port: bulb.addr.port(),
+        vendor_id: model.map(|(vendor, _)| *vendor),
+        product_id: model.map(|(_, product)| *product),
+        product: bulb.product.clone(),
+        host_firmware: bulb.host_firmware.as_ref().map(|v| firmware_version(*v)),
+        wifi_firmware: bulb.wifi_firmware.as_ref().map(|v| firmware_version(*v)),
+        wifi_signal_dbm: bulb.wifi_signal.as_ref().and_then(|mw| signal_dbm(*mw)),
+        uptime: uptime.map(|(up, _)| up / NANOS_PER_SEC),
+        downtime: uptime.map(|(_, down)| down / NANOS_PER_SEC),
+    }
+}
+
+/// Formats a firmware version, which holds the major version in its upper 16 bits.
+pub fn firmware_version(version: u32) -> String {
+    format!("{}.{}", version >> 16, version & 0xffff)
+}
+
+/// Converts the signal StateWifiInfo reports, in milliwatts, to dBm.
+pub fn signal_dbm(milliwatts: f32) -> Option<i32> {
+    if milliwatts.is_finite() && milliwatts > 0.0 {
+        Some((10.0 * milliwatts.log10() + 0.5).floor() as i32)
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+
+    #[test]
+    fn test_firmware_version() {
+        assert_eq!(firmware_version((3 << 16) | 70), "3.70");
+        assert_eq!(firmware_version(0), "0.0");
+    }
+
+    #[test]
+    fn test_signal_dbm() {
+        assert_eq!(signal_dbm(1.0), Some(0));
+        assert_eq!(signal_dbm(0.000_001), Some(-60));
+        // 10^-7.04 mW is -70.4 dBm, which rounds to -70
+        assert_eq!(signal_dbm(9.12e-8), Some(-70));
+        assert_eq!(signal_dbm(0.0), None);
+        assert_eq!(signal_dbm(f32::NAN), None);
+    }
+
+    #[test]
+    fn test_device_info_from_cache() {
+        let addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(192, 168, 1, 100)), 56700);
+        let mut bulb = BulbInfo::new(0x1234, 0x5634_12d5_73d0, addr);
+        bulb.host_firmware.update((3 << 16) | 90);
+        bulb.wifi_signal.update(0.000_01);
+        bulb.uptime.update((3_600 * NANOS_PER_SEC + 5, 42 * NANOS_PER_SEC));
+
+        let info = device_info(&bulb);
+        assert_eq!(info.id, "d073d5123456");
+        assert_eq!((info.ip.as_str(), info.port), ("192.168.1.100", 56700));
+        assert_eq!(info.host_firmware.as_deref(), Some("3.90"));
+        assert_eq!(info.wifi_firmware, None);
+        assert_eq!(info.wifi_signal_dbm, Some(-50));
+        assert_eq!(info.uptime, Some(3_600));
+        assert_eq!(info.downtime, Some(42));
+        assert_eq!(info.vendor_id, None);
+    }
+}
@@ -0,0 +1,126 @@
+use serde::{Deserialize, Serialize};
+use std::time::{Duration, Instant};
+use crate::error::{LifxError, Result};
+use crate::{BulbInfo, Manager};
+
+/// Labels are stored by the bulb in a fixed 32 byte field.
+pub const MAX_LABEL_BYTES: usize = 32;
+/// How long to wait for a bulb to report its new label.
+const REPLY_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Body of `PUT /v1/lights/:selector/label`.
+#[derive(Deserialize, Debug, Clone)]
+pub struct LabelRequest {
+    pub label: String,
+}
+
+impl LabelRequest {
+    pub fn validate(&self) -> Result<()> {
+        if self.label.trim().is_empty() {
+            return Err(LifxError::ValidationError("label must not be empty".to_string()));
+        }
+        if self.label.len() > MAX_LABEL_BYTES {
+            return Err(LifxError::ValidationError(format!(
+                "label must be at most {} bytes, got {}", MAX_LABEL_BYTES, self.label.len()
+            )));
+        }
+        Ok(())
+    }
+}
+
+#[derive(Serialize, Debug)]
+pub struct LabelResult {
+    pub id: String,
+    /// The label the bulb reports, the new one on success.
+    pub label: String,
+    pub status: String,
+    pub message: Option<String>,
+}
+
+#[derive(Serialize)]
+pub struct LabelResponse {
+    pub results: Vec<LabelResult>,
+}
+
+pub struct LabelHandler;
+
+impl LabelHandler {
+    pub fn new() -> Self {
+        LabelHandler
+    }
+
+    /// Sends SetLabel to every bulb, then waits for each to report the new label.
+    ///
+    /// The receive worker stores the StateLabel reply, so the new label shows up in List Lights
+    /// right away. Waits through `Manager::wait_for_bulb`, so the bulbs lock must not be held.
+    pub fn handle_label(&self, mgr: &Manager, bulbs: &[&BulbInfo], request: LabelRequest) -> LabelResponse {
+        let label = request.label;
+        let sent_at = Instant::now();
+        let sent: Vec<_> = bulbs.iter().map(|bulb| bulb.set_label(&mgr.sock, &label)).collect();
+        let deadline = sent_at + REPLY_TIMEOUT;
+
+        let results = bulbs.iter().zip(sent).map(|(bulb, result)| {
+            if let Err(e) = result {
+                return LabelResult {
+                    id: bulb.id.clone(),
+                    label: bulb.label.clone(),
+                    status: "error".to_string(),
+                    message: Some(format!("Failed to set label: {:?}", e)),
+                };
+            }
+
+            let reported = mgr.wait_for_bulb(bulb.target, deadline, |b| {
+                b.name.updated_since(sent_at).filter(|name| **name == label).cloned()
+            });
+            match reported {
+                Some(reported) => LabelResult {
+                    id: bulb.id.clone(),
+                    label: reported,
+                    status: "ok".to_string(),
+                    message: None,
+                },
+                None => LabelResult {
+                    id: bulb.id.clone(),
+                    label: bulb.label.clone(),
+                    status: "timed_out".to_string(),
+                    message: Some("Device did not report the new label".to_string()),
+                },
+            }
+        }).collect();
+
+        LabelResponse { results }
+    }
+}
+
+impl Default for LabelHandler {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(label: &str) -> LabelRequest {
+        LabelRequest { label: label.to_string() }
+    }
+
+    #[test]
+    fn test_label_length_is_limited_in_bytes() {
+        assert!(request("Kitchen Pendant").validate().is_ok());
+        assert!(request(&"a".repeat(MAX_LABEL_BYTES)).validate().is_ok());
+        assert!(request(&"a".repeat(MAX_LABEL_BYTES + 1)).validate().is_err());
+
+        // 11 characters, but 33 bytes in UTF-8
+        let err = request(&"ドアのランプ照明です。").validate().unwrap_err();
+        assert!(err.to_string().contains("at most 32 bytes, got 33"));
+    }
+
+    #[test]
+    fn test_label_must_not_be_empty() {
+        assert!(request("").validate().is_err());
+        assert!(request("   ").validate().is_err());
+        assert!(serde_json::from_str::<LabelRequest>("{}").is_err());
+    }
+}
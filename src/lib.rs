@@ -1,19 +1,17 @@
 // TODO - Impliment authentication header (DONE)
 // TODO - Wrap as a rust library with configurable ports + authentication (DONE)
 // TODO - Impliment LIFX Effects, Scenes, Clean, Cycle (DONE)
-// TODO - Impliment an extended API for changing device labels, wifi-config, etc.
+// TODO - Impliment an extended API for changing device labels, wifi-config, etc. (DONE)
 
 
 use get_if_addrs::{get_if_addrs, IfAddr, Ifv4Addr};
-use lifx_rs::lan::{get_product_info, BuildOptions, Message, PowerLevel, ProductInfo, RawMessage, HSBK};
+use lifx_rs::lan::{get_product_info, ApplicationRequest, BuildOptions, LifxString, Message, PowerLevel, ProductInfo, RawMessage, HSBK};
 use std::collections::HashMap;
-use std::net::{IpAddr, SocketAddr, UdpSocket};
+use std::net::{IpAddr, Ipv4Addr, SocketAddr, UdpSocket};
 use std::sync::{Arc, Mutex};
 use std::thread::{spawn};
-use std::time::{Duration, Instant};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 use rouille::try_or_400;
-use rand::{thread_rng, Rng};
-use rand::distributions::Alphanumeric;
 use std::thread;
 use log::{debug, info, warn, error};
 
@@ -24,9 +22,7 @@ use rouille::post_input;
 use serde::{Serialize, Deserialize};
 use serde_json::json;
 
-use palette::{Hsv, Srgb, IntoColor};
 
-use colors_transform::{Rgb, Color};
 
 pub mod error;
 
@@ -37,7 +33,7 @@ pub mod set_states;
 use set_states::{SetStatesHandler, StatesRequest};
 
 pub mod effects;
-use effects::{EffectsHandler, EffectRequest};
+use effects::{EffectsHandler, EffectRequest, EffectsOffRequest, FirmwareEffectRequest};
 
 pub mod scenes;
 use scenes::{ScenesHandler, CreateSceneRequest, ActivateSceneRequest};
@@ -46,14 +42,61 @@ pub mod cycle;
 use cycle::{CycleHandler, CycleRequest};
 
 pub mod clean;
-use clean::{CleanHandler, CleanRequest};
+use clean::{CleanHandler, CleanRequest, HevCycleConfig, HevCycleState, HevStatus};
 
 pub mod mutex_utils;
 use mutex_utils::{safe_lock, safe_lock_monitored, safe_lock_with_recovery};
 
+pub mod router;
+use router::{Endpoint, RouteMatch, Router};
+
+pub mod selector;
+use selector::{Selector, ZoneRange};
+
+pub mod protocol;
+use protocol::{ExtMessage, HevCycleResult, PacketOptions, TileDevice};
+
+pub mod color;
+use color::{ColorDescription, ColorSpec};
+
+pub mod zones;
+use zones::{ZonesHandler, ZonesRequest};
+
+pub mod tiles;
+use tiles::{TilesHandler, TilesRequest};
+
+pub mod delivery;
+use delivery::{Delivery, SetMessage};
+
+pub mod toggle;
+use toggle::{ToggleHandler, ToggleRequest};
+
+pub mod label;
+use label::{LabelHandler, LabelRequest};
+
+pub mod groups;
+use groups::{Collection, GroupAssignRequest, GroupNameRequest, GroupsHandler};
+
+pub mod info;
+use info::InfoHandler;
+
+pub mod onboarding;
+use onboarding::{OnboardingHandler, OnboardingRequest};
+
+pub mod state_delta;
+use state_delta::{StateDeltaHandler, StateDeltaRequest};
+
+pub mod static_devices;
+use static_devices::{StaticDevice, StaticProbe};
+
 
 
 const HOUR: Duration = Duration::from_secs(60 * 60);
+const DEFAULT_DISCOVERY_INTERVAL_SECS: u64 = 300;
+const DEFAULT_OFFLINE_AFTER_SECS: u64 = 30;
+/// Bulbs reply to whichever port a request came from, so an ephemeral port works without root
+/// and without clashing with other controllers on 56700.
+const DEFAULT_LAN_PORT: u16 = 0;
 
 // Helper functions for safe parsing
 fn parse_u16_safe(value: &str) -> Result<u16, String> {
@@ -241,21 +284,40 @@ fn authenticate_request(
     }
 }
 
+/// Query sent to refresh a `RefreshableData`, either a `lifx_rs` message or one encoded in `protocol`.
+#[derive(Debug, Clone)]
+enum RefreshMessage {
+    Lan(Message),
+    Ext(ExtMessage),
+}
+
+impl From<Message> for RefreshMessage {
+    fn from(msg: Message) -> Self {
+        RefreshMessage::Lan(msg)
+    }
+}
+
+impl From<ExtMessage> for RefreshMessage {
+    fn from(msg: ExtMessage) -> Self {
+        RefreshMessage::Ext(msg)
+    }
+}
+
 #[derive(Debug, Clone)]
 struct RefreshableData<T> {
     data: Option<T>,
     max_age: Duration,
     last_updated: Instant,
-    refresh_msg: Message,
+    refresh_msg: RefreshMessage,
 }
 
 impl<T> RefreshableData<T> {
-    fn empty(max_age: Duration, refresh_msg: Message) -> RefreshableData<T> {
+    fn empty(max_age: Duration, refresh_msg: impl Into<RefreshMessage>) -> RefreshableData<T> {
         RefreshableData {
             data: None,
             max_age,
             last_updated: Instant::now(),
-            refresh_msg,
+            refresh_msg: refresh_msg.into(),
         }
     }
     fn update(&mut self, data: T) {
@@ -270,6 +332,14 @@ impl<T> RefreshableData<T> {
     fn as_ref(&self) -> Option<&T> {
         self.data.as_ref()
     }
+    /// The data, only if it was received after `instant`.
+    fn updated_since(&self, instant: Instant) -> Option<&T> {
+        if self.last_updated > instant {
+            self.data.as_ref()
+        } else {
+            None
+        }
+    }
 }
 
 #[derive(Debug, Clone, Serialize)]
@@ -291,11 +361,23 @@ pub struct BulbInfo {
     pub lifx_last_seen: String,
     #[serde(rename = "seconds_since_seen")]
     pub seconds_since_seen: i64,
+    /// HEV clean cycle state, only reported by bulbs with HEV support.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub hev: Option<HevStatus>,
+    /// Color of every zone of a multizone strip or beam, once all zones have been reported.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub zones: Option<Vec<LifxColor>>,
+    /// Layout of the tile chain of a matrix device.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tiles: Option<Vec<TileDevice>>,
     // pub error: Option<String>,
     // pub errors: Option<Vec<Error>>,
 
     #[serde(skip_serializing)]
     last_seen: Instant,
+    /// Wall clock time of `last_seen`, for reporting.
+    #[serde(skip_serializing)]
+    last_seen_at: SystemTime,
 
     source: u32,
 
@@ -317,10 +399,39 @@ pub struct BulbInfo {
     host_firmware: RefreshableData<u32>,
     #[serde(skip_serializing)]
     wifi_firmware: RefreshableData<u32>,
+    /// Signal strength in milliwatts, from StateWifiInfo.
+    #[serde(skip_serializing)]
+    wifi_signal: RefreshableData<f32>,
+    /// Uptime and downtime in nanoseconds, from StateInfo.
+    #[serde(skip_serializing)]
+    uptime: RefreshableData<(u64, u64)>,
     #[serde(skip_serializing)]
     power_level: RefreshableData<PowerLevel>,
     #[serde(skip_serializing)]
+    infrared: RefreshableData<u16>,
+    #[serde(skip_serializing)]
+    hev_cycle: RefreshableData<HevCycleState>,
+    #[serde(skip_serializing)]
+    hev_last_result: RefreshableData<HevCycleResult>,
+    #[serde(skip_serializing)]
+    hev_config: RefreshableData<HevCycleConfig>,
+    /// Zone count reported through StateExtendedColorZones; unset on firmware without extended multizone.
+    #[serde(skip_serializing)]
+    extended_zones: RefreshableData<u16>,
+    /// Whether GetExtendedColorZones was sent. Without a reply the firmware lacks extended
+    /// multizone, and the zones are only asked for through GetColorZones.
+    #[serde(skip_serializing)]
+    extended_zones_queried: bool,
+    #[serde(skip_serializing)]
+    tile_chain: RefreshableData<Vec<TileDevice>>,
+    /// Last State64 framebuffer received for each tile index.
+    #[serde(skip_serializing)]
+    tile_colors: HashMap<u8, RefreshableData<Vec<HSBK>>>,
+    #[serde(skip_serializing)]
     color: LiColor,
+    /// Sequence numbers and unacknowledged Set* packets, shared by every copy of this bulb.
+    #[serde(skip_serializing)]
+    delivery: Arc<Delivery>,
 }
 
 #[derive(Debug)]
@@ -336,8 +447,12 @@ enum LiColor {
 #[doc(hidden)]
 #[derive(Clone)]
 pub struct LifxLocation {
+    /// 32 character lowercase hex, as in the cloud API.
     pub id: String,
     pub name: String,
+    /// When the location was last changed, in nanoseconds since the epoch, as reported by the bulb.
+    #[serde(skip_serializing)]
+    pub updated_at: u64,
 }
 
 /// Represents an LIFX Color
@@ -356,17 +471,21 @@ pub struct LifxColor {
 #[doc(hidden)]
 #[derive(Clone)]
 pub struct LifxGroup {
+    /// 32 character lowercase hex, as in the cloud API.
     pub id: String,
     pub name: String,
+    /// When the group was last changed, in nanoseconds since the epoch, as reported by the bulb.
+    #[serde(skip_serializing)]
+    pub updated_at: u64,
 }
 
 impl BulbInfo {
     fn new(source: u32, target: u64, addr: SocketAddr) -> BulbInfo {
-        let id: String = thread_rng().sample_iter(&Alphanumeric).take(12).map(char::from).collect();
-        let uuid: String = thread_rng().sample_iter(&Alphanumeric).take(30).map(char::from).collect();
+        let id = serial_from_target(target);
+        let uuid = uuid_from_serial(&id);
         BulbInfo {
-            id: id.to_string(),
-            uuid: uuid.to_string(),
+            id,
+            uuid,
             label: format!(""),
             connected: true,
             power: format!("off"),
@@ -375,9 +494,13 @@ impl BulbInfo {
             lifx_group: None,
             lifx_location: None,
             product: None,
-            lifx_last_seen: format!(""),
+            lifx_last_seen: format_timestamp(SystemTime::now()),
             seconds_since_seen: 0,
+            hev: None,
+            zones: None,
+            tiles: None,
             last_seen: Instant::now(),
+            last_seen_at: SystemTime::now(),
             source,
             target,
             addr,
@@ -387,70 +510,260 @@ impl BulbInfo {
             model: RefreshableData::empty(HOUR, Message::GetVersion),
             host_firmware: RefreshableData::empty(HOUR, Message::GetHostFirmware),
             wifi_firmware: RefreshableData::empty(HOUR, Message::GetWifiFirmware),
+            wifi_signal: RefreshableData::empty(Duration::from_secs(30), Message::GetWifiInfo),
+            uptime: RefreshableData::empty(Duration::from_secs(60), Message::GetInfo),
             power_level: RefreshableData::empty(Duration::from_millis(500), Message::GetPower),
+            infrared: RefreshableData::empty(Duration::from_secs(15), Message::LightGetInfrared),
+            hev_cycle: RefreshableData::empty(Duration::from_secs(5), ExtMessage::GetHevCycle),
+            hev_last_result: RefreshableData::empty(Duration::from_secs(15), ExtMessage::GetLastHevCycleResult),
+            hev_config: RefreshableData::empty(HOUR, ExtMessage::GetHevCycleConfiguration),
+            extended_zones: RefreshableData::empty(HOUR, ExtMessage::GetExtendedColorZones),
+            extended_zones_queried: false,
+            tile_chain: RefreshableData::empty(HOUR, ExtMessage::GetDeviceChain),
+            tile_colors: HashMap::new(),
             color: LiColor::Unknown,
+            delivery: Arc::new(Delivery::new()),
         }
     }
 
     fn update(&mut self, addr: SocketAddr) {
         self.last_seen = Instant::now();
+        self.last_seen_at = SystemTime::now();
         self.addr = addr;
     }
 
+    /// Refreshes `last_seen`, `seconds_since_seen` and `connected` from the last message
+    /// received; bulbs silent for longer than `offline_after` are reported disconnected.
+    fn update_liveness(&mut self, offline_after: Duration) {
+        let silence = self.last_seen.elapsed();
+        self.lifx_last_seen = format_timestamp(self.last_seen_at);
+        self.seconds_since_seen = silence.as_secs() as i64;
+        self.connected = silence <= offline_after;
+    }
+
+    fn has_hev(&self) -> bool {
+        self.product.as_ref().map_or(false, |p| p.capabilities.has_hev)
+    }
+
+    fn has_infrared(&self) -> bool {
+        self.product.as_ref().map_or(false, |p| p.capabilities.has_ir)
+    }
+
+    fn has_matrix(&self) -> bool {
+        self.product.as_ref().map_or(false, |p| p.capabilities.has_matrix)
+    }
+
+    fn is_multizone(&self) -> bool {
+        matches!(self.color, LiColor::Multi(_))
+    }
+
+    /// Number of zones, known once the bulb has reported any of its zones.
+    fn zone_count(&self) -> Option<u16> {
+        let reported = match &self.color {
+            LiColor::Multi(d) => d.as_ref().map(|zones| zones.len() as u16),
+            _ => None,
+        };
+        reported.or_else(|| self.extended_zones.as_ref().copied())
+    }
+
+    fn supports_extended_multizone(&self) -> bool {
+        self.extended_zones.as_ref().is_some()
+    }
+
+    /// Publishes the zone colors once every zone is known.
+    ///
+    /// Multizone bulbs have no color of their own, so the first zone stands in for it: handlers
+    /// working from the current color, such as cycle, then work on strips and beams too.
+    fn update_zones(&mut self) {
+        if let LiColor::Multi(ref d) = self.color {
+            self.zones = d.as_ref().and_then(|zones| {
+                zones
+                    .iter()
+                    .map(|zone| zone.map(|c| LifxColor {
+                        hue: c.hue,
+                        saturation: c.saturation,
+                        kelvin: c.kelvin,
+                        brightness: c.brightness,
+                    }))
+                    .collect()
+            });
+            if let Some(first) = self.zones.as_ref().and_then(|zones| zones.first()) {
+                self.brightness = (first.brightness as f32 / LIFX_BRIGHTNESS_MAX) as f64;
+                self.lifx_color = Some(first.clone());
+            }
+        }
+    }
+
+    fn cache_power(&mut self, level: PowerLevel) {
+        self.power = if level == PowerLevel::Enabled { format!("on") } else { format!("off") };
+        self.power_level.update(level);
+    }
+
+    /// Caches the color of a single zone bulb; multizone bulbs report theirs per zone.
+    fn cache_color(&mut self, color: HSBK) {
+        if let LiColor::Single(ref mut d) = self.color {
+            d.update(color);
+            self.lifx_color = Some(LifxColor {
+                hue: color.hue,
+                saturation: color.saturation,
+                kelvin: color.kelvin,
+                brightness: color.brightness,
+            });
+            self.brightness = (color.brightness as f32 / LIFX_BRIGHTNESS_MAX) as f64;
+        }
+    }
+
+    fn cache_zone_colors(&mut self, start: usize, end: usize, color: HSBK) {
+        let count = end.saturating_sub(start).saturating_add(1);
+        self.cache_zones(start, std::iter::repeat(color).take(count));
+    }
+
+    /// Caches the colors of consecutive zones from `start`, once the zone count is known.
+    fn cache_zones(&mut self, start: usize, colors: impl IntoIterator<Item = HSBK>) {
+        if let LiColor::Multi(ref mut d) = self.color {
+            if let Some(ref mut zones) = d.data {
+                for (zone, color) in zones.iter_mut().skip(start).zip(colors) {
+                    *zone = Some(color);
+                }
+            }
+        }
+        self.update_zones();
+    }
+
+    /// Caches the change made by an acknowledged Set* message, so a request right after it,
+    /// such as another toggle, works from the new state rather than the last poll.
+    fn apply_acknowledged(&mut self, message: SetMessage) {
+        match message {
+            SetMessage::Lan(Message::SetPower { level }) => self.cache_power(level),
+            SetMessage::Lan(Message::LightSetPower { level, .. }) => {
+                self.cache_power(if level > 0 { PowerLevel::Enabled } else { PowerLevel::Standby })
+            }
+            SetMessage::Lan(Message::LightSetColor { color, .. }) => {
+                self.cache_color(color);
+                self.cache_zone_colors(0, usize::MAX, color);
+            }
+            SetMessage::Lan(Message::SetColorZones { start_index, end_index, color, .. }) => {
+                self.cache_zone_colors(start_index as usize, end_index as usize, color)
+            }
+            SetMessage::Lan(Message::LightSetInfrared { brightness }) => self.infrared.update(brightness),
+            SetMessage::Ext(ExtMessage::SetExtendedColorZones { zone_index, colors, .. }) => {
+                self.cache_zones(zone_index as usize, colors)
+            }
+            _ => {}
+        }
+    }
+
+    /// Combines the cached HEV replies; `None` until the bulb has reported its cycle state.
+    fn hev_status(&self) -> Option<HevStatus> {
+        let cycle = self.hev_cycle.as_ref()?;
+        let config = self.hev_config.as_ref();
+        Some(HevStatus {
+            cleaning: cycle.is_cleaning(),
+            duration: cycle.duration,
+            remaining: cycle.remaining,
+            last_power: cycle.last_power,
+            last_result: self.hev_last_result.as_ref().copied(),
+            default_duration: config.map(|c| c.duration),
+            indication: config.map(|c| c.indication),
+        })
+    }
+
     fn refresh_if_needed<T>(
         &self,
         sock: &UdpSocket,
         data: &RefreshableData<T>,
     ) -> Result<(), failure::Error> {
         if data.needs_refresh() {
-            let options = BuildOptions {
-                target: Some(self.target),
-                res_required: true,
-                source: self.source,
-                ..Default::default()
-            };
-            let message = RawMessage::build(&options, data.refresh_msg.clone())?;
-            sock.send_to(&message.pack()?, self.addr)?;
+            match &data.refresh_msg {
+                RefreshMessage::Lan(msg) => self.send_query(sock, msg.clone())?,
+                RefreshMessage::Ext(msg) => self.send_ext(sock, msg)?,
+            }
         }
         Ok(())
     }
 
-    fn set_power(
-        &self,
-        sock: &UdpSocket,
-        power_level: PowerLevel,
-    ) -> Result<(), failure::Error> {
-        
+    /// Sends a Get* message asking for a State reply.
+    fn send_query(&self, sock: &UdpSocket, msg: Message) -> Result<(), failure::Error> {
         let options = BuildOptions {
             target: Some(self.target),
             res_required: true,
             source: self.source,
             ..Default::default()
         };
-        let message = RawMessage::build(&options, Message::SetPower{level: power_level})?;
+        let message = RawMessage::build(&options, msg)?;
         sock.send_to(&message.pack()?, self.addr)?;
-  
         Ok(())
     }
 
-    fn set_infrared(
-        &self,
-        sock: &UdpSocket,
-        brightness: u16,
-    ) -> Result<(), failure::Error> {
-        
+    /// Sends a message encoded by `protocol`. Queries ask for a State reply, anything else for a
+    /// tracked ack.
+    fn send_ext(&self, sock: &UdpSocket, msg: &ExtMessage) -> Result<(), failure::Error> {
+        let is_query = msg.is_query();
+        let options = PacketOptions {
+            source: self.source,
+            target: self.target,
+            sequence: if is_query { 0 } else { self.delivery.next_sequence() },
+            ack_required: !is_query,
+            res_required: is_query,
+        };
+        let packet = msg.encode(&options);
+        if !is_query {
+            self.delivery.track(options.sequence, packet.clone(), Some(msg.clone().into()));
+        }
+        sock.send_to(&packet, self.addr)?;
+        Ok(())
+    }
+
+    /// Sends a Set* message with `ack_required`, tracking it until the bulb acknowledges it.
+    fn send_set(&self, sock: &UdpSocket, message: Message) -> Result<(), failure::Error> {
+        let sequence = self.delivery.next_sequence();
         let options = BuildOptions {
             target: Some(self.target),
-            res_required: true,
+            ack_required: true,
+            sequence,
             source: self.source,
             ..Default::default()
         };
-        let message = RawMessage::build(&options, Message::LightSetInfrared{brightness: brightness})?;
-        sock.send_to(&message.pack()?, self.addr)?;
-  
+        let packet = RawMessage::build(&options, message.clone())?.pack()?;
+        self.delivery.track(sequence, packet.clone(), Some(message.into()));
+        sock.send_to(&packet, self.addr)?;
+
         Ok(())
     }
 
+    fn set_power(
+        &self,
+        sock: &UdpSocket,
+        power_level: PowerLevel,
+    ) -> Result<(), failure::Error> {
+        self.send_set(sock, Message::SetPower{level: power_level})
+    }
+
+    /// Switches power with a transition, using LightSetPower.
+    fn set_power_with_duration(
+        &self,
+        sock: &UdpSocket,
+        power_level: PowerLevel,
+        duration_ms: u32,
+    ) -> Result<(), failure::Error> {
+        let level = if power_level == PowerLevel::Enabled { u16::MAX } else { 0 };
+        self.send_set(sock, Message::LightSetPower { level, duration: duration_ms })
+    }
+
+    /// Renames the bulb and asks for the StateLabel that confirms it.
+    fn set_label(&self, sock: &UdpSocket, label: &str) -> Result<(), failure::Error> {
+        self.send_set(sock, Message::SetLabel { label: LifxString(label.to_string()) })?;
+        self.send_query(sock, Message::GetLabel)
+    }
+
+    fn set_infrared(
+        &self,
+        sock: &UdpSocket,
+        brightness: u16,
+    ) -> Result<(), failure::Error> {
+        self.send_set(sock, Message::LightSetInfrared{brightness: brightness})
+    }
+
 
     fn set_color(
         &self,
@@ -458,30 +771,80 @@ impl BulbInfo {
         color: HSBK,
         duration: u32
     ) -> Result<(), failure::Error> {
-        
-        let options = BuildOptions {
-            target: Some(self.target),
-            res_required: true,
-            source: self.source,
-            ..Default::default()
-        };
-        let message = RawMessage::build(&options, Message::LightSetColor{reserved: 0, color: color, duration: duration})?;
-        sock.send_to(&message.pack()?, self.addr)?;
-  
-        Ok(())
+        self.send_set(sock, Message::LightSetColor{reserved: 0, color: color, duration: duration})
     }
 
 
 
 
-    fn query_for_missing_info(&self, sock: &UdpSocket) -> Result<(), failure::Error> {
+    /// Sets `color` on the given zones of a multizone bulb, or on the whole bulb otherwise.
+    fn set_color_in_zones(
+        &self,
+        sock: &UdpSocket,
+        color: HSBK,
+        duration: u32,
+        zones: Option<&[ZoneRange]>,
+    ) -> Result<(), failure::Error> {
+        match zones {
+            Some(zones) if self.is_multizone() && !zones.is_empty() => {
+                for (i, range) in zones.iter().enumerate() {
+                    let apply = if i + 1 == zones.len() {
+                        ApplicationRequest::Apply
+                    } else {
+                        ApplicationRequest::NoApply
+                    };
+                    self.set_color_zones(sock, range.start, range.end, color, duration, apply)?;
+                }
+                Ok(())
+            }
+            _ => self.set_color(sock, color, duration),
+        }
+    }
+
+    fn set_color_zones(
+        &self,
+        sock: &UdpSocket,
+        start: u16,
+        end: u16,
+        color: HSBK,
+        duration: u32,
+        apply: ApplicationRequest,
+    ) -> Result<(), failure::Error> {
+        self.send_set(sock, Message::SetColorZones {
+            start_index: start.min(255) as u8,
+            end_index: end.min(255) as u8,
+            color,
+            duration,
+            apply,
+        })
+    }
+
+    fn query_for_missing_info(&mut self, sock: &UdpSocket) -> Result<(), failure::Error> {
         self.refresh_if_needed(sock, &self.name)?;
         self.refresh_if_needed(sock, &self.model)?;
         self.refresh_if_needed(sock, &self.location)?;
         self.refresh_if_needed(sock, &self.host_firmware)?;
         self.refresh_if_needed(sock, &self.wifi_firmware)?;
+        self.refresh_if_needed(sock, &self.wifi_signal)?;
+        self.refresh_if_needed(sock, &self.uptime)?;
         self.refresh_if_needed(sock, &self.power_level)?;
         self.refresh_if_needed(sock, &self.group)?;
+        if self.has_infrared() {
+            self.refresh_if_needed(sock, &self.infrared)?;
+        }
+        if self.has_hev() {
+            self.refresh_if_needed(sock, &self.hev_cycle)?;
+            self.refresh_if_needed(sock, &self.hev_last_result)?;
+            self.refresh_if_needed(sock, &self.hev_config)?;
+        }
+        // Firmware without extended multizone never answers, so only repeat answered queries
+        if self.is_multizone() && (!self.extended_zones_queried || self.supports_extended_multizone()) {
+            self.extended_zones_queried = true;
+            self.refresh_if_needed(sock, &self.extended_zones)?;
+        }
+        if self.has_matrix() {
+            self.refresh_if_needed(sock, &self.tile_chain)?;
+        }
         match &self.color {
             LiColor::Unknown => (), // we'll need to wait to get info about this bulb's model, so we'll know if it's multizone or not
             LiColor::Single(d) => self.refresh_if_needed(sock, d)?,
@@ -556,40 +919,143 @@ impl BulbInfo {
 //     }
 // }
 
+/// Serial number of a device as the cloud API reports it, e.g. `d073d5123456`: the MAC address
+/// held in the first six little-endian bytes of the frame target.
+fn serial_from_target(target: u64) -> String {
+    hex_id(&target.to_le_bytes()[..6])
+}
+
+/// Lowercase hex of protocol identifiers, such as the 16 byte group and location ids.
+fn hex_id(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// UUID derived from a serial number, so it is the same every time the device is discovered.
+///
+/// The 128 bits come from two FNV-1a hashes of the serial and are marked as a version 8
+/// (custom) UUID.
+fn uuid_from_serial(serial: &str) -> String {
+    fn fnv1a(seed: u8, data: &[u8]) -> u64 {
+        std::iter::once(seed).chain(data.iter().copied()).fold(0xcbf2_9ce4_8422_2325, |hash, byte| {
+            (hash ^ byte as u64).wrapping_mul(0x0000_0100_0000_01b3)
+        })
+    }
+
+    let mut bytes = [0u8; 16];
+    bytes[..8].copy_from_slice(&fnv1a(0, serial.as_bytes()).to_be_bytes());
+    bytes[8..].copy_from_slice(&fnv1a(1, serial.as_bytes()).to_be_bytes());
+    bytes[6] = (bytes[6] & 0x0f) | 0x80;
+    bytes[8] = (bytes[8] & 0x3f) | 0x80;
+
+    let hex = hex_id(&bytes);
+    format!("{}-{}-{}-{}-{}", &hex[0..8], &hex[8..12], &hex[12..16], &hex[16..20], &hex[20..32])
+}
+
+/// Formats a time like the cloud API does, e.g. `2015-12-16T19:58:13.867+00:00`.
+fn format_timestamp(time: SystemTime) -> String {
+    let since_epoch = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let secs = since_epoch.as_secs();
+    let (days, rem) = (secs / 86400, secs % 86400);
+
+    // Civil date from days since the epoch (Howard Hinnant's days_from_civil, inverted)
+    let z = days + 719468;
+    let era = z / 146097;
+    let doe = z - era * 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:03}+00:00",
+        year, month, day, rem / 3600, rem % 3600 / 60, rem % 60, since_epoch.subsec_millis()
+    )
+}
+
 pub struct Manager {
     pub bulbs: Arc<Mutex<HashMap<u64, BulbInfo>>>,
     pub last_discovery: Instant,
     pub sock: UdpSocket,
     pub source: u32,
+    /// How often the LAN is searched for new bulbs.
+    pub discovery_interval: Duration,
+    /// Silence after which a bulb is reported as not connected.
+    pub offline_after: Duration,
+    /// Silence after which a bulb is forgotten altogether; never when unset.
+    pub purge_after: Option<Duration>,
+    /// Configured bulbs probed by unicast in addition to broadcast discovery.
+    static_devices: Vec<StaticProbe>,
+    last_static_probe: Instant,
 }
 
 impl Manager {
-    fn new() -> Result<Manager, failure::Error> {
-        let sock = UdpSocket::bind("0.0.0.0:56700")?;
+    fn new(config: &Config) -> error::Result<Manager> {
+        let static_devices = static_devices::resolve(&config.static_devices)?;
+
+        let lan_addr = config.lan_addr()?;
+        let sock = UdpSocket::bind(lan_addr).map_err(|e| {
+            error::LifxError::ConfigError(format!("Failed to bind LAN socket on {}: {}", lan_addr, e))
+        })?;
+        info!("LAN socket bound to {}", sock.local_addr()?);
         sock.set_broadcast(true)?;
 
-        // spawn a thread that can send to our socket
-        let recv_sock = sock.try_clone()?;
-
-        let bulbs = Arc::new(Mutex::new(HashMap::new()));
-        let receiver_bulbs = bulbs.clone();
-        let source = 0x72757374;
-
-        // spawn a thread that will receive data from our socket and update our internal data structures
-        spawn(move || Self::worker(recv_sock, source, receiver_bulbs));
-
+        let source = delivery::random_source();
         let mut mgr = Manager {
-            bulbs,
+            bulbs: Arc::new(Mutex::new(HashMap::new())),
             last_discovery: Instant::now(),
             sock,
             source,
+            discovery_interval: config.discovery_interval(),
+            offline_after: config.offline_after(),
+            purge_after: config.purge_after(),
+            static_devices,
+            last_static_probe: Instant::now(),
         };
-        mgr.discover()?;
+        mgr.discover().map_err(|e| error::LifxError::FailureError(e.to_string()))?;
+
+        // Only now spawn the thread that receives from our socket and updates the bulbs, so a
+        // failed start leaves nothing running; discovery replies wait in the socket until then
+        let recv_sock = mgr.sock.try_clone()?;
+        let receiver_bulbs = Arc::clone(&mgr.bulbs);
+        spawn(move || Self::worker(recv_sock, source, receiver_bulbs));
         Ok(mgr)
     }
 
+    /// A second manager on the same socket and bulbs.
+    ///
+    /// Requests are served on one of these, so waiting for replies holds up neither other
+    /// requests nor the refresh thread, which keep the original locked only briefly.
+    fn try_clone(&self) -> std::io::Result<Manager> {
+        Ok(Manager {
+            bulbs: Arc::clone(&self.bulbs),
+            last_discovery: self.last_discovery,
+            sock: self.sock.try_clone()?,
+            source: self.source,
+            discovery_interval: self.discovery_interval,
+            offline_after: self.offline_after,
+            purge_after: self.purge_after,
+            static_devices: self.static_devices.clone(),
+            last_static_probe: self.last_static_probe,
+        })
+    }
+
     fn handle_message(raw: RawMessage, bulb: &mut BulbInfo) -> Result<(), lifx_rs::lan::Error> {
+        if let Some(msg) = ExtMessage::decode(raw.protocol_header.typ, &raw.payload) {
+            Self::handle_ext_message(msg, bulb);
+            return Ok(());
+        }
+
         match Message::from_raw(&raw)? {
+            Message::Acknowledgement { seq } => {
+                // Another client's acks could reuse our sequence numbers
+                if raw.frame.source == bulb.source {
+                    if let Some(message) = bulb.delivery.acknowledge(seq) {
+                        bulb.apply_acknowledged(message);
+                    }
+                }
+            }
             Message::StateService { port: _, service: _ } => {
                 // if port != bulb.addr.port() as u32 || service != Service::UDP {
                 //     debug!("Unsupported service: {:?}/{}", service, port);
@@ -602,17 +1068,10 @@ impl Manager {
             },
 
   
-            Message::StateLocation { location, label, updated_at: _ } => {
-
-                let lab = label.0;
-
-                bulb.location.update(lab.clone());
-
-
-          
-                let group_two = LifxLocation{id: format!("{:?}", location.0).replace(", ", "").replace("[", "").replace("]", ""), name: lab};
-                bulb.lifx_location = Some(group_two);
-
+            Message::StateLocation { location, label, updated_at } => {
+                let name = label.0;
+                bulb.location.update(name.clone());
+                bulb.lifx_location = Some(LifxLocation { id: hex_id(&location.0), name, updated_at });
             },
             Message::StateVersion {
                 vendor, product, ..
@@ -639,53 +1098,34 @@ impl Manager {
                     }
                 }
             }
-            Message::StatePower { level } => {
-                bulb.power_level.update(level);
+            Message::StatePower { level } => bulb.cache_power(level),
 
-                if bulb.power_level.data.as_ref() == Some(&PowerLevel::Enabled) {
-                    bulb.power = format!("on");
-                } else {
-                    bulb.power = format!("off");
-                }
+            Message::LightStateInfrared { brightness } => bulb.infrared.update(brightness),
 
-               
+            Message::LightStatePower { level } => {
+                bulb.cache_power(if level > 0 { PowerLevel::Enabled } else { PowerLevel::Standby })
             },
 
-            Message::StateGroup { group, label, updated_at: _ } => {
-
-                let group_one = LifxGroup{id: format!("{:?}", group.0), name: label.to_string()};
-                
-                let group_two = LifxGroup{id: format!("{:?}", group.0).replace(", ", "").replace("[", "").replace("]", ""), name: label.to_string()};
-                bulb.group.update(group_one);
-                bulb.lifx_group = Some(group_two);
+            Message::StateGroup { group, label, updated_at } => {
+                let group = LifxGroup { id: hex_id(&group.0), name: label.to_string(), updated_at };
+                bulb.group.update(group.clone());
+                bulb.lifx_group = Some(group);
             },
 
 
 
             Message::StateHostFirmware { version, .. } => bulb.host_firmware.update(version),
             Message::StateWifiFirmware { version, .. } => bulb.wifi_firmware.update(version),
+            Message::StateWifiInfo { signal, .. } => bulb.wifi_signal.update(signal),
+            Message::StateInfo { uptime, downtime, .. } => bulb.uptime.update((uptime, downtime)),
             Message::LightState {
                 color,
                 power,
                 label,
                 ..
             } => {
-                if let LiColor::Single(ref mut d) = bulb.color {
-                    d.update(color);
-
-                    let bc = color;
-
-
-                    bulb.lifx_color = Some(LifxColor{
-                        hue: bc.hue,
-                        saturation: bc.saturation,
-                        kelvin: bc.kelvin,
-                        brightness: bc.brightness,
-                    });
-
-                    bulb.brightness = (bc.brightness as f32 / LIFX_BRIGHTNESS_MAX) as f64;
-
-
+                if let LiColor::Single(_) = bulb.color {
+                    bulb.cache_color(color);
                     bulb.power_level.update(power);
                 }
                 bulb.name.update(label.0);
@@ -738,9 +1178,85 @@ impl Manager {
                 debug!("Received, but ignored {:?}", unknown);
             }
         }
+        bulb.update_zones();
         Ok(())
     }
 
+    fn handle_ext_message(msg: ExtMessage, bulb: &mut BulbInfo) {
+        match msg {
+            ExtMessage::StateHevCycle { duration_s, remaining_s, last_power } => {
+                bulb.hev_cycle.update(HevCycleState {
+                    duration: duration_s,
+                    remaining: remaining_s,
+                    last_power,
+                });
+            }
+            ExtMessage::StateLastHevCycleResult { result } => bulb.hev_last_result.update(result),
+            ExtMessage::StateHevCycleConfiguration { indication, duration_s } => {
+                bulb.hev_config.update(HevCycleConfig {
+                    indication,
+                    duration: duration_s,
+                });
+            }
+            ExtMessage::StateDeviceChain { tiles, .. } => {
+                bulb.tiles = Some(tiles.clone());
+                bulb.tile_chain.update(tiles);
+                return;
+            }
+            ExtMessage::State64 { tile_index, x, y, width, colors } => {
+                bulb.tile_colors
+                    .entry(tile_index)
+                    .or_insert_with(|| RefreshableData::empty(
+                        Duration::from_secs(15),
+                        ExtMessage::Get64 { tile_index, length: 1, x, y, width },
+                    ))
+                    .update(colors);
+                return;
+            }
+            ExtMessage::StateExtendedColorZones { zones_count, zone_index, colors } => {
+                bulb.extended_zones.update(zones_count);
+                if let LiColor::Multi(ref mut d) = bulb.color {
+                    let v = d.data.get_or_insert_with(Vec::new);
+                    v.resize(zones_count as usize, None);
+                    for (i, color) in colors.into_iter().enumerate() {
+                        if let Some(zone) = v.get_mut(zone_index as usize + i) {
+                            *zone = Some(color);
+                        }
+                    }
+                }
+                bulb.update_zones();
+                return;
+            }
+            unknown => {
+                debug!("Received, but ignored {:?}", unknown);
+                return;
+            }
+        }
+        bulb.hev = bulb.hev_status();
+    }
+
+    /// Polls the bulb `target` until `check` returns a value or `deadline` passes.
+    ///
+    /// Handlers waiting on several bulbs share one deadline, so a request waits once rather
+    /// than once per bulb. The bulbs lock is only held for each check, so the worker can keep
+    /// recording replies; callers must not hold it themselves.
+    pub(crate) fn wait_for_bulb<T, F>(&self, target: u64, deadline: Instant, mut check: F) -> Option<T>
+    where
+        F: FnMut(&BulbInfo) -> Option<T>,
+    {
+        loop {
+            if let Ok(bulbs) = self.bulbs.lock() {
+                if let Some(value) = bulbs.get(&target).and_then(|bulb| check(bulb)) {
+                    return Some(value);
+                }
+            }
+            if Instant::now() >= deadline {
+                return None;
+            }
+            thread::sleep(Duration::from_millis(20));
+        }
+    }
+
     fn worker(
         recv_sock: UdpSocket,
         source: u32,
@@ -833,6 +1349,9 @@ impl Manager {
         let bytes = rawmsg.pack()
             .map_err(|e| lifx_rs::lan::Error::Io(std::io::Error::new(std::io::ErrorKind::Other, format!("Failed to pack message: {:?}", e))))?;
 
+        // A socket bound to one interface can't send to the broadcast address of another
+        let bound_ip = self.sock.local_addr().ok().map(|addr| addr.ip()).filter(|ip| !ip.is_unspecified());
+
         for addr in get_if_addrs()
             .map_err(|e| lifx_rs::lan::Error::Io(std::io::Error::new(std::io::ErrorKind::Other, format!("Failed to get network interfaces: {:?}", e))))? {
             match addr.addr {
@@ -840,25 +1359,57 @@ impl Manager {
                     broadcast: Some(bcast),
                     ..
                 }) => {
-                    if addr.ip().is_loopback() {
+                    if addr.ip().is_loopback() || bound_ip.map_or(false, |ip| ip != addr.ip()) {
                         continue;
                     }
                     let addr = SocketAddr::new(IpAddr::V4(bcast), 56700);
                     info!("Discovering bulbs on LAN {:?}", addr);
-                    self.sock.send_to(&bytes, &addr)?;
+                    // One unusable interface shouldn't stop discovery on the others
+                    if let Err(e) = self.sock.send_to(&bytes, &addr) {
+                        warn!("Failed to broadcast discovery to {}: {}", addr, e);
+                    }
                 }
                 _ => {}
             }
         }
 
+        static_devices::probe(&self.sock, self.source, &self.static_devices);
+        self.last_static_probe = Instant::now();
         self.last_discovery = Instant::now();
 
         Ok(())
     }
 
+    /// Searches the LAN again once `discovery_interval` has passed since the last discovery,
+    /// and probes configured devices that are missing more often than that.
+    fn discover_if_due(&mut self) {
+        if self.last_discovery.elapsed() >= self.discovery_interval {
+            if let Err(e) = self.discover() {
+                error!("Error during periodic discovery: {:?}", e);
+            }
+        } else if !self.static_devices.is_empty() && self.last_static_probe.elapsed() >= static_devices::PROBE_INTERVAL {
+            if let Ok(bulbs) = self.bulbs.lock() {
+                static_devices::probe(&self.sock, self.source, static_devices::missing(&self.static_devices, &bulbs));
+            }
+            self.last_static_probe = Instant::now();
+        }
+    }
+
     fn refresh(&self) {
-        if let Ok(bulbs) = self.bulbs.lock() {
-            for bulb in bulbs.values() {
+        if let Ok(mut bulbs) = self.bulbs.lock() {
+            if let Some(purge_after) = self.purge_after {
+                bulbs.retain(|_, bulb| {
+                    let keep = bulb.last_seen.elapsed() <= purge_after;
+                    if !keep {
+                        info!("Forgetting bulb {} ({}), not seen for {:?}", bulb.id, bulb.addr, bulb.last_seen.elapsed());
+                    }
+                    keep
+                });
+            }
+            for bulb in bulbs.values_mut() {
+                bulb.update_liveness(self.offline_after);
+            }
+            for bulb in bulbs.values_mut() {
                 match bulb.query_for_missing_info(&self.sock){
                     Ok(_missing_info) => {
                     },
@@ -878,53 +1429,224 @@ pub struct Config {
     pub secret_key: Option<String>,
     pub port: u16,
     pub auth_required: bool,
+    /// JSON file used to persist scenes across restarts. Scenes are kept in memory only when unset.
+    #[serde(default)]
+    pub scenes_path: Option<String>,
+    /// Seconds between LAN discoveries. Defaults to 300.
+    #[serde(default)]
+    pub discovery_interval_secs: Option<u64>,
+    /// Seconds of silence after which a bulb is reported with `connected: false`. Defaults to 30.
+    #[serde(default)]
+    pub offline_after_secs: Option<u64>,
+    /// Seconds of silence after which a bulb is removed from the list. Bulbs are kept forever when unset.
+    #[serde(default)]
+    pub purge_after_secs: Option<u64>,
+    /// Bulbs to reach by unicast, for networks where broadcast discovery does not find them.
+    #[serde(default)]
+    pub static_devices: Vec<StaticDevice>,
+    /// IP address the HTTP API listens on. Defaults to all interfaces.
+    #[serde(default)]
+    pub http_bind_address: Option<String>,
+    /// IP address of the interface bulbs are reached through. Defaults to all interfaces.
+    #[serde(default)]
+    pub lan_bind_address: Option<String>,
+    /// Local UDP port for the LAN protocol; 0, the default, picks a free ephemeral port.
+    #[serde(default)]
+    pub lan_port: Option<u16>,
 }
 
-pub fn start(config: Config) {
+impl Config {
+    pub fn discovery_interval(&self) -> Duration {
+        Duration::from_secs(self.discovery_interval_secs.unwrap_or(DEFAULT_DISCOVERY_INTERVAL_SECS))
+    }
 
-    // Log authentication status
-    if config.auth_required && config.secret_key.is_some() {
-        info!("Starting LIFX API server with authentication enabled");
-    } else if !config.auth_required {
-        warn!("Starting LIFX API server WITHOUT authentication - API is publicly accessible");
+    pub fn offline_after(&self) -> Duration {
+        Duration::from_secs(self.offline_after_secs.unwrap_or(DEFAULT_OFFLINE_AFTER_SECS))
     }
 
-    if let Err(e) = sudo::with_env(&["SECRET_KEY"]) {
-        error!("Failed to preserve SECRET_KEY environment variable: {}", e);
-        std::process::exit(1);
+    pub fn purge_after(&self) -> Option<Duration> {
+        self.purge_after_secs.map(Duration::from_secs)
     }
-    
-    if let Err(e) = sudo::escalate_if_needed() {
-        error!("Failed to escalate privileges: {}", e);
-        std::process::exit(1);
+
+    /// Address the HTTP API binds to, from `http_bind_address` and `port`.
+    pub fn http_addr(&self) -> crate::error::Result<SocketAddr> {
+        Ok(SocketAddr::new(parse_bind_address("http_bind_address", self.http_bind_address.as_deref())?, self.port))
     }
 
+    /// Address the LAN socket binds to, from `lan_bind_address` and `lan_port`.
+    pub fn lan_addr(&self) -> crate::error::Result<SocketAddr> {
+        let port = self.lan_port.unwrap_or(DEFAULT_LAN_PORT);
+        Ok(SocketAddr::new(parse_bind_address("lan_bind_address", self.lan_bind_address.as_deref())?, port))
+    }
+}
 
-    let mgr = Manager::new();
+/// Parses a configured bind address, defaulting to all IPv4 interfaces.
+fn parse_bind_address(field: &str, address: Option<&str>) -> crate::error::Result<IpAddr> {
+    match address.map(str::trim).filter(|a| !a.is_empty()) {
+        None => Ok(IpAddr::V4(Ipv4Addr::UNSPECIFIED)),
+        Some(address) => address.parse().map_err(|_| {
+            error::LifxError::ConfigError(format!("{} must be an IP address, got '{}'", field, address))
+        }),
+    }
+}
 
-    match mgr {
-        Ok(mgr) => {
-            let mgr_arc = Arc::new(Mutex::new(mgr));
+// (PUT) SetState
+// https://api.lifx.com/v1/lights/:selector/state
+fn handle_set_state(request: &rouille::Request, mgr: &Manager, selector: &Selector, bulbs_vec: &[&BulbInfo]) -> Response {
+    let input = try_or_400!(post_input!(request, {
+        power: Option<String>,
+        color: Option<String>,
+        brightness: Option<f64>,
+        duration: Option<f64>,
+        infrared: Option<f64>,
+        fast: Option<bool>
+    }));
+
+    let color_spec = match input.color.as_deref().map(ColorSpec::parse).transpose() {
+        Ok(spec) => spec,
+        Err(e) => {
+            return Response::text(json!({
+                "error": e.to_string()
+            }).to_string()).with_status_code(400);
+        }
+    };
 
-            let th_arc_mgr = Arc::clone(&mgr_arc);
+    let duration = (input.duration.unwrap_or(0.0).max(0.0) * 1000.0) as u32;
+    let power = match input.power.as_deref() {
+        Some("on") => Some(PowerLevel::Enabled),
+        Some("off") => Some(PowerLevel::Standby),
+        _ => None,
+    };
+    let infrared = input.infrared.map(color::fraction_to_lifx);
+
+    // Color, with brightness folded in so both land in a single SetColor
+    let color_for = |bulb: &BulbInfo| -> Option<HSBK> {
+        match (color_spec, input.brightness) {
+            (Some(mut spec), brightness) => {
+                if let Some(brightness) = brightness {
+                    spec.brightness = Some(color::fraction_to_lifx(brightness));
+                }
+                Some(spec.apply_to(color::base_color(bulb.lifx_color.as_ref())))
+            }
+            // Brightness alone keeps the current hue, saturation and kelvin
+            (None, Some(brightness)) => {
+                let current = bulb.lifx_color.as_ref();
+                Some(HSBK {
+                    hue: current.map_or(0, |c| c.hue),
+                    saturation: current.map_or(0, |c| c.saturation),
+                    brightness: color::fraction_to_lifx(brightness),
+                    kelvin: current.map_or(6500, |c| c.kelvin),
+                })
+            }
+            (None, None) => None,
+        }
+    };
 
-            thread::spawn(move || {
+    let sent_at = Instant::now();
+    let sent: Vec<Result<(), failure::Error>> = bulbs_vec.iter().map(|bulb| {
+        if let Some(level) = power {
+            bulb.set_power_with_duration(&mgr.sock, level, duration)?;
+        }
+        if let Some(color) = color_for(bulb) {
+            bulb.set_color_in_zones(&mgr.sock, color, duration, selector.zones_for(bulb))?;
+        }
+        if let Some(brightness) = infrared {
+            bulb.set_infrared(&mgr.sock, brightness)?;
+        }
+        Ok(())
+    }).collect();
+
+    // Return results in proper format
+    #[derive(Serialize)]
+    struct SingleStateResult {
+        id: String,
+        label: String,
+        status: String,
+    }
+
+    #[derive(Serialize)]
+    struct SingleStateResponse {
+        results: Vec<SingleStateResult>,
+    }
+
+    let fast = input.fast.unwrap_or(false);
+    let statuses = delivery::confirm_unless_fast(&mgr.sock, bulbs_vec, &sent, sent_at, fast);
+    let mut results = Vec::new();
+    for ((bulb, result), status) in bulbs_vec.iter().zip(&sent).zip(statuses) {
+        if let Err(e) = result {
+            warn!("Failed to set state of {}: {}", bulb.id, e);
+        }
+        results.push(SingleStateResult {
+            id: bulb.id.clone(),
+            label: bulb.label.clone(),
+            status: status.map_or("error", |s| s.as_str()).to_string(),
+        });
+    }
+
+    let all_sent = sent.iter().all(Result::is_ok);
+    accepted_or_json(fast, all_sent, &SingleStateResponse { results })
+}
+
+/// Answers a `fast` request with an empty 202 once every message went out, and with `response`
+/// otherwise, so errors found before sending are still reported.
+fn accepted_or_json<T: Serialize>(fast: bool, all_sent: bool, response: &T) -> Response {
+    if fast && all_sent {
+        Response::text("").with_status_code(202)
+    } else {
+        Response::json(response)
+    }
+}
+
+/// Loads the scenes from `scenes_path`, or keeps them in memory only when it is unset.
+///
+/// An unreadable scene file fails with `LifxError::ConfigError` instead of starting without
+/// it, so scenes created afterwards can't overwrite the old ones. See
+/// `ScenesHandler::recover_storage` for moving such a file aside.
+fn load_scenes_handler(scenes_path: Option<&str>) -> error::Result<ScenesHandler> {
+    let path = match scenes_path {
+        Some(path) => path,
+        None => return Ok(ScenesHandler::new()),
+    };
+
+    let handler = ScenesHandler::with_storage(path)?;
+    info!("Loaded scenes from {}", path);
+    Ok(handler)
+}
+
+/// Starts discovery and the HTTP API on background threads and returns once both are bound.
+///
+/// Never escalates privileges: binding a privileged port is left to the caller. Fails with
+/// `LifxError::ConfigError` when an address is invalid or cannot be bound.
+pub fn start(config: Config) -> error::Result<()> {
+
+    // Log authentication status
+    if config.auth_required && config.secret_key.is_some() {
+        info!("Starting LIFX API server with authentication enabled");
+    } else if !config.auth_required {
+        warn!("Starting LIFX API server WITHOUT authentication - API is publicly accessible");
+    }
+
+    let http_addr = config.http_addr()?;
+    let scenes_handler = Arc::new(load_scenes_handler(config.scenes_path.as_deref())?);
+
+    let mgr = Manager::new(&config);
+
+    match mgr {
+        Ok(mgr) => {
+            let mgr_arc = Arc::new(Mutex::new(mgr));
+
+            let th_arc_mgr = Arc::clone(&mgr_arc);
+
+            thread::spawn(move || {
                 loop{
-                    let mut lock = match th_arc_mgr.lock() {
-                        Ok(l) => l,
-                        Err(e) => {
-                            error!("Failed to acquire lock: {}", e);
-                            thread::sleep(Duration::from_millis(1000));
-                            continue;
+                    // Released before sleeping, so requests only wait for the refresh itself
+                    match th_arc_mgr.lock() {
+                        Ok(mut mgr) => {
+                            mgr.discover_if_due();
+                            mgr.refresh();
                         }
-                    };
-                    let mgr = &mut *lock;  
-                
-                    // if Instant::now() - mgr.last_discovery > Duration::from_secs(300) {
-                    //     mgr.discover().unwrap();
-                    // }
-            
-                    mgr.refresh();
+                        Err(e) => error!("Failed to acquire lock: {}", e),
+                    }
                     thread::sleep(Duration::from_millis(1000));
                 }
         
@@ -936,9 +1658,6 @@ pub fn start(config: Config) {
             // Initialize rate limiter
             let rate_limiter = Arc::new(RateLimiter::new());
             
-            // Initialize scenes handler
-            let scenes_handler = Arc::new(ScenesHandler::new());
-            
             // Spawn cleanup thread for rate limiter
             let cleanup_limiter = Arc::clone(&rate_limiter);
             thread::spawn(move || {
@@ -948,9 +1667,11 @@ pub fn start(config: Config) {
                 }
             });
         
-            thread::spawn(move || {
+            let router = Router::api();
+        
+            let server = {
                 let scenes_handler = scenes_handler.clone();
-                rouille::start_server(format!("0.0.0.0:{}", config.port).as_str(), move |request| {
+                rouille::Server::new(http_addr, move |request| {
         
                     // Use centralized authentication middleware
                     match authenticate_request(request, config.secret_key.as_deref(), &rate_limiter) {
@@ -963,59 +1684,64 @@ pub fn start(config: Config) {
         
         
         
-                    let mut response = Response::text("hello world");
+                    let (endpoint, params) = match router.resolve(request.method(), &request.url()) {
+                        RouteMatch::Found(endpoint, params) => (endpoint, params),
+                        RouteMatch::NotFound => {
+                            return Response::text(json!({ "error": "Not found" }).to_string())
+                                .with_status_code(404);
+                        }
+                        RouteMatch::MethodNotAllowed(allowed) => {
+                            return Response::text(json!({ "error": "Method not allowed" }).to_string())
+                                .with_status_code(405)
+                                .with_additional_header("Allow", allowed.join(", "));
+                        }
+                    };
         
-                    let mut lock = match th2_arc_mgr.lock() {
-                        Ok(l) => l,
+                    // Serve the request on a handle of its own, so handlers waiting for acks or
+                    // replies block neither other requests nor discovery
+                    let handle = match th2_arc_mgr.lock() {
+                        Ok(lock) => {
+                            lock.refresh();
+                            lock.try_clone()
+                        }
                         Err(e) => {
                             error!("Failed to acquire lock: {}", e);
                             return Response::text("Internal Server Error").with_status_code(500);
                         }
                     };
-                    let mgr = &mut *lock;  
-        
-                
-                    mgr.refresh();
-        
-        
-                    let urls = request.url().to_string();
-                    let split = urls.split("/");
-                    let vec: Vec<&str> = split.collect();
-        
-                    let mut selector = "";
-        
-                    if vec.len() >= 3 {
-                        selector = vec[3];
-                    }
-            
+                    let mut handle = match handle {
+                        Ok(handle) => handle,
+                        Err(e) => {
+                            error!("Failed to clone LAN socket: {}", e);
+                            return Response::text("Internal Server Error").with_status_code(500);
+                        }
+                    };
+                    let mgr = &mut handle;
         
         
-                    // Scenes API endpoints (handle before selector-based endpoints)
-                    // GET /v1/scenes
-                    if request.url() == "/v1/scenes" && request.method() == "GET" {
-                        match scenes_handler.list_scenes() {
-                            Ok(scenes_response) => return Response::json(&scenes_response),
-                            Err(e) => return Response::text(json!({ "error": e.to_string() }).to_string()).with_status_code(500),
+                    match endpoint {
+                        // GET /v1/scenes
+                        Endpoint::ListScenes => {
+                            match scenes_handler.list_scenes() {
+                                Ok(scenes_response) => Response::json(&scenes_response),
+                                Err(e) => Response::text(json!({ "error": e.to_string() }).to_string()).with_status_code(500),
+                            }
                         }
-                    }
-                    
-                    // POST /v1/scenes
-                    if request.url() == "/v1/scenes" && request.method() == "POST" {
-                        let body = try_or_400!(rouille::input::plain_text_body(request));
-                        let input: CreateSceneRequest = try_or_400!(serde_json::from_str(&body));
                         
-                        match scenes_handler.create_scene(input) {
-                            Ok(scene_response) => return Response::json(&scene_response),
-                            Err(e) => return Response::text(json!({ "error": e.to_string() }).to_string()).with_status_code(500),
+                        // POST /v1/scenes
+                        Endpoint::CreateScene => {
+                            let body = try_or_400!(rouille::input::plain_text_body(request));
+                            let input: CreateSceneRequest = try_or_400!(serde_json::from_str(&body));
+                            
+                            match scenes_handler.create_scene(input) {
+                                Ok(scene_response) => Response::json(&scene_response),
+                                Err(e) => Response::text(json!({ "error": e.to_string() }).to_string()).with_status_code(500),
+                            }
                         }
-                    }
-                    
-                    // PUT /v1/scenes/:uuid/activate
-                    if request.url().contains("/scenes/") && request.url().contains("/activate") && request.method() == "PUT" {
-                        let url_string = request.url().to_string();
-                        let url_parts: Vec<&str> = url_string.split('/').collect();
-                        if url_parts.len() >= 4 {
-                            let uuid = url_parts[3];
+                        
+                        // PUT /v1/scenes/:uuid/activate
+                        Endpoint::ActivateScene => {
+                            let uuid = params.get("uuid").unwrap_or_default();
                             let body = try_or_400!(rouille::input::plain_text_body(request));
                             let input: ActivateSceneRequest = if body.is_empty() {
                                 ActivateSceneRequest { duration: None, fast: None }
@@ -1023,629 +1749,381 @@ pub fn start(config: Config) {
                                 try_or_400!(serde_json::from_str(&body))
                             };
                             
+                            let fast = input.fast.unwrap_or(false);
                             match scenes_handler.activate_scene(mgr, uuid, input) {
-                                Ok(activate_response) => return Response::json(&activate_response),
-                                Err(e) => return Response::text(json!({ "error": e.to_string() }).to_string()).with_status_code(404),
-                            }
-                        }
-                    }
-                    
-                    // DELETE /v1/scenes/:uuid
-                    if request.url().contains("/scenes/") && request.method() == "DELETE" {
-                        let url_string = request.url().to_string();
-                        let url_parts: Vec<&str> = url_string.split('/').collect();
-                        if url_parts.len() >= 4 {
-                            let uuid = url_parts[3];
-                            if scenes_handler.delete_scene(uuid).unwrap_or(false) {
-                                return Response::text(json!({ "status": "deleted" }).to_string());
-                            } else {
-                                return Response::text(json!({ "error": "Scene not found" }).to_string()).with_status_code(404);
+                                Ok(activate_response) => {
+                                    let all_sent = activate_response.results.iter().all(|r| r.status == "ok");
+                                    accepted_or_json(fast, all_sent, &activate_response)
+                                }
+                                Err(e) => Response::text(json!({ "error": e.to_string() }).to_string()).with_status_code(404),
                             }
                         }
-                    }
-                    
-                    // POST /v1/scenes/capture
-                    if request.url() == "/v1/scenes/capture" && request.method() == "POST" {
-                        let body = try_or_400!(rouille::input::plain_text_body(request));
-                        let input: serde_json::Value = try_or_400!(serde_json::from_str(&body));
-                        let name = input.get("name")
-                            .and_then(|v| v.as_str())
-                            .unwrap_or("Captured Scene")
-                            .to_string();
                         
-                        match scenes_handler.capture_current_state(mgr, name) {
-                            Ok(scene_response) => return Response::json(&scene_response),
-                            Err(e) => return Response::text(json!({ "error": e.to_string() }).to_string()).with_status_code(500),
+                        // DELETE /v1/scenes/:uuid
+                        Endpoint::DeleteScene => {
+                            let uuid = params.get("uuid").unwrap_or_default();
+                            match scenes_handler.delete_scene(uuid) {
+                                Ok(true) => Response::text(json!({ "status": "deleted" }).to_string()),
+                                Ok(false) => Response::text(json!({ "error": "Scene not found" }).to_string()).with_status_code(404),
+                                Err(e) => Response::text(json!({ "error": e.to_string() }).to_string()).with_status_code(500),
+                            }
                         }
-                    }
-                    
-                    // (PUT) SetStates
-                    // https://api.lifx.com/v1/lights/states
-                    if request.url().contains("/lights/states") && request.method() == "PUT" {
-                        let body = try_or_400!(rouille::input::plain_text_body(request));
-                        let input: StatesRequest = try_or_400!(serde_json::from_str(&body));
                         
-                        let handler = SetStatesHandler::new();
-                        let states_response = handler.handle_request(mgr, input);
-                        response = Response::json(&states_response);
-                    } else {
-                        // For other endpoints, we need bulbs_vec
-                        let mut bulbs_vec: Vec<&BulbInfo> = Vec::new();
-        
-                        let bulbs = match mgr.bulbs.lock() {
-                            Ok(guard) => guard,
-                            Err(e) => {
-                                eprintln!("Failed to acquire bulbs lock: {}", e);
-                                return Response::text("Internal Server Error").with_status_code(500);
+                        // POST /v1/scenes/capture
+                        Endpoint::CaptureScene => {
+                            let body = try_or_400!(rouille::input::plain_text_body(request));
+                            let input: serde_json::Value = try_or_400!(serde_json::from_str(&body));
+                            let name = input.get("name")
+                                .and_then(|v| v.as_str())
+                                .unwrap_or("Captured Scene")
+                                .to_string();
+                            
+                            match scenes_handler.capture_current_state(mgr, name) {
+                                Ok(scene_response) => Response::json(&scene_response),
+                                Err(e) => Response::text(json!({ "error": e.to_string() }).to_string()).with_status_code(500),
                             }
-                        };
+                        }
                         
+                        // (PUT) SetStates
+                        // https://api.lifx.com/v1/lights/states
+                        Endpoint::SetStates => {
+                            let body = try_or_400!(rouille::input::plain_text_body(request));
+                            let input: StatesRequest = try_or_400!(serde_json::from_str(&body));
+                            let fast = input.is_fast();
                             
-                        for bulb in bulbs.values() {
-                            bulbs_vec.push(bulb);
+                            let handler = SetStatesHandler::new().with_scenes(Arc::clone(&scenes_handler));
+                            let states_response = handler.handle_request(mgr, input);
+                            let all_sent = states_response.results.iter().all(|r| r.status == "ok");
+                            accepted_or_json(fast, all_sent, &states_response)
                         }
-        
-                        if selector == "all"{
                         
+                        // GET /v1/color?string=...
+                        Endpoint::Color => {
+                            let string = match request.get_param("string") {
+                                Some(string) => string,
+                                None => {
+                                    let e = error::LifxError::MissingField("string".to_string());
+                                    return Response::text(json!({ "error": e.to_string() }).to_string()).with_status_code(400);
+                                }
+                            };
+                            match ColorSpec::parse(&string) {
+                                Ok(spec) => Response::json(&ColorDescription::from(spec)),
+                                Err(e) => Response::text(json!({
+                                    "error": e.to_string(),
+                                    "string": string
+                                }).to_string()).with_status_code(400),
+                            }
                         }
-        
-                        if selector.contains("group_id:"){
-                            bulbs_vec = bulbs_vec
-                            .into_iter()
-                            .filter(|b| b.lifx_group.as_ref().map_or(false, |g| g.id.contains(&selector.replace("group_id:", ""))))
-                            .collect();
+                        
+                        // GET /v1/groups, GET /v1/locations
+                        Endpoint::ListGroups | Endpoint::ListLocations => {
+                            let collection = if endpoint == Endpoint::ListGroups { Collection::Group } else { Collection::Location };
+                            match GroupsHandler::new(collection).list(mgr) {
+                                Ok(groups) => Response::json(&groups),
+                                Err(e) => Response::text(json!({ "error": e.to_string() }).to_string()).with_status_code(500),
+                            }
                         }
-        
-                        if selector.contains("location_id:"){
-                            bulbs_vec = bulbs_vec
-                            .into_iter()
-                            .filter(|b| b.lifx_location.as_ref().map_or(false, |l| l.id.contains(&selector.replace("location_id:", ""))))
-                            .collect();
+
+                        // PUT /v1/groups/:id, PUT /v1/locations/:id
+                        Endpoint::RenameGroup | Endpoint::RenameLocation => {
+                            let collection = if endpoint == Endpoint::RenameGroup { Collection::Group } else { Collection::Location };
+                            let body = try_or_400!(rouille::input::plain_text_body(request));
+                            let input: GroupNameRequest = try_or_400!(serde_json::from_str(&body));
+                            if let Err(e) = input.validate() {
+                                return Response::text(json!({ "error": e.to_string() }).to_string()).with_status_code(400);
+                            }
+
+                            match GroupsHandler::new(collection).rename(mgr, params.get("id").unwrap_or_default(), input) {
+                                Ok(response) => Response::json(&response),
+                                Err(e @ error::LifxError::ValidationError(_)) => Response::text(json!({ "error": e.to_string() }).to_string()).with_status_code(400),
+                                Err(e @ error::LifxError::DeviceNotFound(_)) => Response::text(json!({ "error": e.to_string() }).to_string()).with_status_code(404),
+                                Err(e) => Response::text(json!({ "error": e.to_string() }).to_string()).with_status_code(500),
+                            }
                         }
-        
-                        if selector.contains("id:"){
-                            bulbs_vec = bulbs_vec
-                            .into_iter()
-                            .filter(|b| b.id.contains(&selector.replace("id:", "")))
-                            .collect();
+
+                        // POST /v1/onboarding
+                        Endpoint::Onboard => {
+                            let body = try_or_400!(rouille::input::plain_text_body(request));
+                            let input: OnboardingRequest = try_or_400!(serde_json::from_str(&body));
+                            if let Err(e) = input.validate() {
+                                return Response::text(json!({ "error": e.to_string() }).to_string()).with_status_code(400);
+                            }
+
+                            let handler = OnboardingHandler::new();
+                            Response::json(&handler.handle_onboarding(mgr, input))
                         }
-        
-                    // (PUT) SetState
-                    // https://api.lifx.com/v1/lights/:selector/state
-                    if request.url().contains("/state"){
-        
-                        let input = try_or_400!(post_input!(request, {
-                            power: Option<String>,
-                            color: Option<String>,
-                            brightness: Option<f64>,
-                            duration: Option<f64>,
-                            infrared: Option<f64>,
-                            fast: Option<bool>
-                        }));
-        
-        
-                        // Power
-                        if input.power.is_some() {
-                            let power = match input.power {
-                                Some(p) => p,
-                                None => {
-                                    return Response::text(json!({
-                                        "error": "Missing power value"
-                                    }).to_string()).with_status_code(400);
-                                }
+
+                        // Endpoints addressing lights through a :selector
+                        _ => {
+                            let selector = match Selector::parse(params.get("selector").unwrap_or_default()) {
+                                Ok(selector) => selector,
+                                Err(e) => return Response::text(json!({ "error": e.to_string() }).to_string()).with_status_code(400),
                             };
-                            if power == format!("on"){
-                                for bulb in &bulbs_vec {
-                                    bulb.set_power(&mgr.sock, PowerLevel::Enabled);
-                                }
-                            } 
-                
-                            if power == format!("off"){
-                                for bulb in &bulbs_vec {
-                                    bulb.set_power(&mgr.sock, PowerLevel::Standby);
-                                }
-                            } 
-                        }
         
-                        // Color
-                        if input.color.is_some() {
-                            let cc = match input.color {
-                                Some(c) => c,
-                                None => {
-                                    return Response::text(json!({
-                                        "error": "Missing color value"
-                                    }).to_string()).with_status_code(400);
+                            // Work on a snapshot so handlers waiting for replies don't block the receive worker
+                            let selected: Vec<BulbInfo> = match mgr.bulbs.lock() {
+                                Ok(bulbs) => selector.select(&bulbs, Some(&*scenes_handler)).into_iter().cloned().collect(),
+                                Err(e) => {
+                                    eprintln!("Failed to acquire bulbs lock: {}", e);
+                                    return Response::text("Internal Server Error").with_status_code(500);
                                 }
                             };
-        
-        
-        
-                            for bulb in &bulbs_vec {
-        
-        
-                                let mut kelvin = 6500;
-                                let mut brightness = LIFX_BRIGHTNESS_MAX as u16;
-                                let mut saturation = 0;
-                                let mut hue = 0;
-        
-                                let mut duration = 0;
-                                if input.duration.is_some(){
-                                    duration = input.duration.unwrap_or(0.0) as u32;
-                                }
-        
-                                if let Some(lifxc) = bulb.lifx_color.as_ref() {
-                                    kelvin = lifxc.kelvin;
-                                    brightness = lifxc.brightness;
-                                    saturation = lifxc.saturation;
-                                    hue = lifxc.hue;
-                                }
-                            
-                                if cc.contains("white"){
-                                    let hbsk_set = HSBK {
-                                        hue: HUE_RED,
-                                        saturation: 0,
-                                        brightness: brightness,
-                                        kelvin: kelvin,
-                                    };
-                                    bulb.set_color(&mgr.sock, hbsk_set, duration);
-                                }
-        
-                                if cc.contains("red"){
-                                    let hbsk_set = HSBK {
-                                        hue: HUE_RED,
-                                        saturation: LIFX_SATURATION_MAX as u16,
-                                        brightness: brightness,
-                                        kelvin: kelvin,
-                                    };
-                                    bulb.set_color(&mgr.sock, hbsk_set, duration);
-                                }
-        
-                                if cc.contains("orange"){
-                                    let hbsk_set = HSBK {
-                                        hue: HUE_ORANGE,
-                                        saturation: LIFX_SATURATION_MAX as u16,
-                                        brightness: brightness,
-                                        kelvin: kelvin,
-                                    };
-                                    bulb.set_color(&mgr.sock, hbsk_set, duration);
-                                }
-        
-                                if cc.contains("yellow"){
-                                    let hbsk_set = HSBK {
-                                        hue: HUE_YELLOW,
-                                        saturation: LIFX_SATURATION_MAX as u16,
-                                        brightness: brightness,
-                                        kelvin: kelvin,
-                                    };
-                                    bulb.set_color(&mgr.sock, hbsk_set, duration);
+                            let bulbs_vec: Vec<&BulbInfo> = selected.iter().collect();
+        
+                            match endpoint {
+                                // (PUT) SetState
+                                // https://api.lifx.com/v1/lights/:selector/state
+                                Endpoint::SetState => handle_set_state(request, mgr, &selector, &bulbs_vec),
+
+                                // PUT /v1/lights/:selector/zones
+                                Endpoint::SetZones => {
+                                    let body = try_or_400!(rouille::input::plain_text_body(request));
+                                    let input: ZonesRequest = try_or_400!(serde_json::from_str(&body));
+                                    if let Err(e) = input.validate() {
+                                        return Response::text(json!({ "error": e.to_string() }).to_string()).with_status_code(400);
+                                    }
+
+                                    let handler = ZonesHandler::new();
+                                    let fast = input.fast.unwrap_or(false);
+                                    let zones_response = handler.handle_zones(mgr, &bulbs_vec, &selector, input);
+                                    accepted_or_json(fast, zones_response.results.iter().all(|r| r.status == "ok"), &zones_response)
                                 }
         
-                                if cc.contains("cyan"){
-                                    let hbsk_set = HSBK {
-                                        hue: HUE_CYAN,
-                                        saturation: LIFX_SATURATION_MAX as u16,
-                                        brightness: brightness,
-                                        kelvin: kelvin,
-                                    };
-                                    bulb.set_color(&mgr.sock, hbsk_set, duration);
+                                // ListLights
+                                // https://api.lifx.com/v1/lights/:selector
+                                Endpoint::ListLights => Response::json(&bulbs_vec),
+                                
+                                // POST /v1/lights/:selector/effects/pulse
+                                Endpoint::EffectsPulse => {
+                                    let body = try_or_400!(rouille::input::plain_text_body(request));
+                                    let input: EffectRequest = try_or_400!(serde_json::from_str(&body));
+                                    
+                                    let handler = EffectsHandler::new();
+                                    let fast = input.fast.unwrap_or(false);
+                                    let effects_response = handler.handle_pulse(mgr, &bulbs_vec, input);
+                                    accepted_or_json(fast, effects_response.results.iter().all(|r| r.status == "ok"), &effects_response)
                                 }
-        
-                                if cc.contains("green"){
-                                    let hbsk_set = HSBK {
-                                        hue: HUE_GREEN,
-                                        saturation: LIFX_SATURATION_MAX as u16,
-                                        brightness: brightness,
-                                        kelvin: kelvin,
-                                    };
-                                    bulb.set_color(&mgr.sock, hbsk_set, duration);
+                                
+                                // POST /v1/lights/:selector/effects/breathe
+                                Endpoint::EffectsBreathe => {
+                                    let body = try_or_400!(rouille::input::plain_text_body(request));
+                                    let input: EffectRequest = try_or_400!(serde_json::from_str(&body));
+                                    
+                                    let handler = EffectsHandler::new();
+                                    let fast = input.fast.unwrap_or(false);
+                                    let effects_response = handler.handle_breathe(mgr, &bulbs_vec, input);
+                                    accepted_or_json(fast, effects_response.results.iter().all(|r| r.status == "ok"), &effects_response)
                                 }
-        
-                                if cc.contains("blue"){
-                                    let hbsk_set = HSBK {
-                                        hue: HUE_BLUE,
-                                        saturation: LIFX_SATURATION_MAX as u16,
-                                        brightness: brightness,
-                                        kelvin: kelvin,
-                                    };
-                                    bulb.set_color(&mgr.sock, hbsk_set, duration);
+                                
+                                // POST /v1/lights/:selector/effects/strobe
+                                Endpoint::EffectsStrobe => {
+                                    let body = try_or_400!(rouille::input::plain_text_body(request));
+                                    let input: EffectRequest = try_or_400!(serde_json::from_str(&body));
+                                    
+                                    let handler = EffectsHandler::new();
+                                    let fast = input.fast.unwrap_or(false);
+                                    let effects_response = handler.handle_strobe(mgr, &bulbs_vec, input);
+                                    accepted_or_json(fast, effects_response.results.iter().all(|r| r.status == "ok"), &effects_response)
                                 }
-        
-                                if cc.contains("purple"){
-                                    let hbsk_set = HSBK {
-                                        hue: HUE_PURPLE,
-                                        saturation: LIFX_SATURATION_MAX as u16,
-                                        brightness: brightness,
-                                        kelvin: kelvin,
+                                
+                                // POST /v1/lights/:selector/effects/move
+                                // POST /v1/lights/:selector/effects/morph
+                                // POST /v1/lights/:selector/effects/flame
+                                Endpoint::EffectsMove | Endpoint::EffectsMorph | Endpoint::EffectsFlame => {
+                                    let body = try_or_400!(rouille::input::plain_text_body(request));
+                                    let input: FirmwareEffectRequest = if body.trim().is_empty() {
+                                        FirmwareEffectRequest::default()
+                                    } else {
+                                        try_or_400!(serde_json::from_str(&body))
                                     };
-                                    bulb.set_color(&mgr.sock, hbsk_set, duration);
-                                }
-        
-                                if cc.contains("pink"){
-                                    let hbsk_set = HSBK {
-                                        hue: HUE_PINK,
-                                        saturation: 25000,
-                                        brightness: brightness,
-                                        kelvin: kelvin,
+                                    if let Err(e) = input.validate() {
+                                        return Response::text(json!({ "error": e.to_string() }).to_string()).with_status_code(400);
+                                    }
+                                    
+                                    let fast = input.fast.unwrap_or(false);
+                                    let handler = EffectsHandler::new();
+                                    let response = match endpoint {
+                                        Endpoint::EffectsMove => handler.handle_move(mgr, &bulbs_vec, input),
+                                        Endpoint::EffectsMorph => handler.handle_morph(mgr, &bulbs_vec, input),
+                                        _ => handler.handle_flame(mgr, &bulbs_vec, input),
                                     };
-                                    bulb.set_color(&mgr.sock, hbsk_set, duration);
+                                    accepted_or_json(fast, response.results.iter().all(|r| r.status == "ok"), &response)
                                 }
-        
-        
-                                if cc.contains("hue:"){
-        
-                                    let hue_split = cc.split("hue:");
-                                    let hue_vec: Vec<&str> = hue_split.collect();
-                                    let new_hue = match parse_u16_safe(&hue_vec[1]) {
-                                        Ok(h) => h,
-                                        Err(e) => {
-                                            error!("Error parsing hue: {}", e);
-                                            continue;
-                                        }
-                                    }; 
-                                    let hbsk_set = HSBK {
-                                        hue: new_hue,
-                                        saturation: saturation,
-                                        brightness: brightness,
-                                        kelvin: kelvin,
+                                
+                                // POST /v1/lights/:selector/effects/off
+                                Endpoint::EffectsOff => {
+                                    let body = try_or_400!(rouille::input::plain_text_body(request));
+                                    let input: EffectsOffRequest = if body.trim().is_empty() {
+                                        EffectsOffRequest::default()
+                                    } else {
+                                        try_or_400!(serde_json::from_str(&body))
                                     };
-                                    bulb.set_color(&mgr.sock, hbsk_set, duration);
+                                    
+                                    let handler = EffectsHandler::new();
+                                    let fast = input.fast.unwrap_or(false);
+                                    let effects_response = handler.handle_off(mgr, &bulbs_vec, input);
+                                    accepted_or_json(fast, effects_response.results.iter().all(|r| r.status == "ok"), &effects_response)
                                 }
-        
-                                if cc.contains("saturation:"){
-                                    let saturation_split = cc.split("saturation:");
-                                    let saturation_vec: Vec<&str> = saturation_split.collect();
-                                    let new_saturation_float = match parse_f64_safe(&saturation_vec[1]) {
-                                        Ok(s) => s,
-                                        Err(e) => {
-                                            error!("Error parsing saturation: {}", e);
-                                            continue;
-                                        }
-                                    }; 
-                                    let new_saturation: u16 = (f64::from(100) * new_saturation_float) as u16;
-                                    let hbsk_set = HSBK {
-                                        hue: hue,
-                                        saturation: new_saturation,
-                                        brightness: brightness,
-                                        kelvin: kelvin,
-                                    };
-                                    bulb.set_color(&mgr.sock, hbsk_set, duration);
+                                
+                                // POST /v1/lights/:selector/state/delta
+                                Endpoint::StateDelta => {
+                                    let body = try_or_400!(rouille::input::plain_text_body(request));
+                                    let input: StateDeltaRequest = try_or_400!(serde_json::from_str(&body));
+                                    if let Err(e) = input.validate() {
+                                        return Response::text(json!({ "error": e.to_string() }).to_string()).with_status_code(400);
+                                    }
+                                    
+                                    let handler = StateDeltaHandler::new();
+                                    let fast = input.fast.unwrap_or(false);
+                                    let delta_response = handler.handle_delta(mgr, &bulbs_vec, input);
+                                    accepted_or_json(fast, delta_response.results.iter().all(|r| r.status == "ok"), &delta_response)
                                 }
-        
-                                if cc.contains("brightness:"){
-                                    let brightness_split = cc.split("brightness:");
-                                    let brightness_vec: Vec<&str> = brightness_split.collect();
-                                    let new_brightness_float = match parse_f64_safe(&brightness_vec[1]) {
-                                        Ok(b) => b,
-                                        Err(e) => {
-                                            error!("Error parsing brightness: {}", e);
-                                            continue;
-                                        }
-                                    }; 
-                                    let new_brightness: u16 = (LIFX_BRIGHTNESS_MAX * new_brightness_float as f32) as u16;
-                                    let hbsk_set = HSBK {
-                                        hue: hue,
-                                        saturation: saturation,
-                                        brightness: new_brightness,
-                                        kelvin: kelvin,
+                                
+                                // POST /v1/lights/:selector/toggle
+                                Endpoint::Toggle => {
+                                    let body = try_or_400!(rouille::input::plain_text_body(request));
+                                    let input: ToggleRequest = if body.trim().is_empty() {
+                                        ToggleRequest::default()
+                                    } else {
+                                        try_or_400!(serde_json::from_str(&body))
                                     };
-                                    bulb.set_color(&mgr.sock, hbsk_set, duration);
+                                    if let Err(e) = input.validate() {
+                                        return Response::text(json!({ "error": e.to_string() }).to_string()).with_status_code(400);
+                                    }
+                                    
+                                    let handler = ToggleHandler::new();
+                                    let fast = input.fast.unwrap_or(false);
+                                    let toggle_response = handler.handle_toggle(mgr, &bulbs_vec, input);
+                                    accepted_or_json(fast, toggle_response.results.iter().all(|r| r.status == "ok"), &toggle_response)
                                 }
-        
-                                if cc.contains("kelvin:"){
-                                    let kelvin_split = cc.split("kelvin:");
-                                    let kelvin_vec: Vec<&str> = kelvin_split.collect();
-                                    let new_kelvin = match parse_u16_safe(&kelvin_vec[1]) {
-                                        Ok(k) => k,
-                                        Err(e) => {
-                                            error!("Error parsing kelvin: {}", e);
-                                            continue;
-                                        }
-                                    }; 
-                                    let hbsk_set = HSBK {
-                                        hue: hue,
-                                        saturation: 0,
-                                        brightness: brightness,
-                                        kelvin: new_kelvin,
-                                    };
-                                    bulb.set_color(&mgr.sock, hbsk_set, duration);
+                                
+                                // PUT /v1/lights/:selector/label
+                                Endpoint::SetLabel => {
+                                    let body = try_or_400!(rouille::input::plain_text_body(request));
+                                    let input: LabelRequest = try_or_400!(serde_json::from_str(&body));
+                                    if let Err(e) = input.validate() {
+                                        return Response::text(json!({ "error": e.to_string() }).to_string()).with_status_code(400);
+                                    }
+
+                                    let handler = LabelHandler::new();
+                                    Response::json(&handler.handle_label(mgr, &bulbs_vec, input))
                                 }
-        
-                                if cc.contains("rgb:"){
-        
-        
-                                    let rgb_split = cc.split("rgb:");
-                                    let rgb_vec: Vec<&str> = rgb_split.collect();
-                                    let rgb_parts = rgb_vec[1].to_string();
-        
-                                    let rgb_part_split = rgb_parts.split(",");
-                                    let rgb_parts_vec: Vec<&str> = rgb_part_split.collect();
-        
-                                    let red_int = match parse_i64_safe(&rgb_parts_vec[0]) {
-                                        Ok(r) => r,
-                                        Err(e) => {
-                                            error!("Error parsing red value: {}", e);
-                                            continue;
-                                        }
-                                    };
-                                    let red_float: f32 = (red_int) as f32;
-        
-                                    let green_int = match parse_i64_safe(&rgb_parts_vec[1]) {
-                                        Ok(g) => g,
-                                        Err(e) => {
-                                            error!("Error parsing green value: {}", e);
-                                            continue;
-                                        }
-                                    };
-                                    let green_float: f32 = (green_int) as f32;
-        
-                                    let blue_int = match parse_i64_safe(&rgb_parts_vec[2]) {
-                                        Ok(b) => b,
-                                        Err(e) => {
-                                            error!("Error parsing blue value: {}", e);
-                                            continue;
-                                        }
-                                    };
-                                    let blue_float: f32 = (blue_int) as f32;
-        
-                                    let rgb = Srgb::new(red_float / 255.0, green_float / 255.0, blue_float / 255.0);
-                                    let hcc: Hsv = rgb.into_color();
-        
-                                    // Convert HSV to LIFX HSBK format (16-bit values)
-                                    let hbsk_set = HSBK {
-                                        hue: ((hcc.hue.into_positive_degrees() * LIFX_HUE_DEGREE_FACTOR) as u32 % 0x10000) as u16,
-                                        saturation: (hcc.saturation * LIFX_SATURATION_MAX) as u16,
-                                        brightness: brightness,
-                                        kelvin: kelvin,
-                                    };
 
-        
-                                    bulb.set_color(&mgr.sock, hbsk_set, duration);
-        
+                                // POST /v1/lights/:selector/group, POST /v1/lights/:selector/location
+                                Endpoint::CreateGroup | Endpoint::CreateLocation => {
+                                    let collection = if endpoint == Endpoint::CreateGroup { Collection::Group } else { Collection::Location };
+                                    let body = try_or_400!(rouille::input::plain_text_body(request));
+                                    let input: GroupNameRequest = try_or_400!(serde_json::from_str(&body));
+                                    if let Err(e) = input.validate() {
+                                        return Response::text(json!({ "error": e.to_string() }).to_string()).with_status_code(400);
+                                    }
+
+                                    Response::json(&GroupsHandler::new(collection).create(mgr, &bulbs_vec, input))
                                 }
-        
-                                if cc.contains("#"){
-                                    debug!("Processing color conversion");
-                                    let hex_split = cc.split("#");
-                                    let hex_vec: Vec<&str> = hex_split.collect();
-                                    let hex = hex_vec[1].to_string();
-        
-                                    let rgb2 = match Rgb::from_hex_str(format!("#{}", hex).as_str()) {
-                                        Ok(rgb) => rgb,
-                                        Err(_) => {
-                                            error!("Error parsing hex color: {}", hex);
-                                            continue;
-                                        }
-                                    };
-                                    // Rgb { r: 255.0, g: 204.0, b: 0.0 }
-        
-                                    debug!("RGB values: {:?}", rgb2);
-        
-                                    let red_int = match parse_i64_safe(&rgb2.get_red().to_string()) {
-                                        Ok(r) => r,
-                                        Err(e) => {
-                                            error!("Error parsing red from hex: {}", e);
-                                            continue;
-                                        }
-                                    };
-                                    let red_float: f32 = (red_int) as f32;
-        
-                                    let green_int = match parse_i64_safe(&rgb2.get_green().to_string()) {
-                                        Ok(g) => g,
-                                        Err(e) => {
-                                            error!("Error parsing green from hex: {}", e);
-                                            continue;
-                                        }
-                                    };
-                                    let green_float: f32 = (green_int) as f32;
-        
-                                    let blue_int = match parse_i64_safe(&rgb2.get_blue().to_string()) {
-                                        Ok(b) => b,
-                                        Err(e) => {
-                                            error!("Error parsing blue from hex: {}", e);
-                                            continue;
-                                        }
-                                    };
-                                    let blue_float: f32 = (blue_int) as f32;
-        
-        
-                                    debug!("red_float: {:?}", red_float);
-                                    debug!("green_float: {:?}", green_float);
-                                    debug!("blue_float: {:?}", blue_float);
-        
-                    
-                                    let rgb = Srgb::new(red_float / 255.0, green_float / 255.0, blue_float / 255.0);
-                                    let hcc: Hsv = rgb.into_color();
 
-                                    debug!("HSV values: {:?}", hcc);
-        
-                                    // Convert HSV to LIFX HSBK format (16-bit values)
-                                    let hbsk_set = HSBK {
-                                        hue: ((hcc.hue.into_positive_degrees() * LIFX_HUE_DEGREE_FACTOR) as u32 % 0x10000) as u16,
-                                        saturation: (hcc.saturation * LIFX_SATURATION_MAX) as u16,
-                                        brightness: brightness,
-                                        kelvin: kelvin,
-                                    };
+                                // PUT /v1/lights/:selector/group, PUT /v1/lights/:selector/location
+                                Endpoint::JoinGroup | Endpoint::JoinLocation => {
+                                    let collection = if endpoint == Endpoint::JoinGroup { Collection::Group } else { Collection::Location };
+                                    let body = try_or_400!(rouille::input::plain_text_body(request));
+                                    let input: GroupAssignRequest = try_or_400!(serde_json::from_str(&body));
+                                    if let Err(e) = input.validate() {
+                                        return Response::text(json!({ "error": e.to_string() }).to_string()).with_status_code(400);
+                                    }
 
-                                    debug!("HBSK values: {:?}", hbsk_set);
-        
-        
-        
-                                    bulb.set_color(&mgr.sock, hbsk_set, duration);
-        
+                                    match GroupsHandler::new(collection).join(mgr, &bulbs_vec, input) {
+                                        Ok(response) => Response::json(&response),
+                                        Err(e @ error::LifxError::DeviceNotFound(_)) => Response::text(json!({ "error": e.to_string() }).to_string()).with_status_code(404),
+                                        Err(e) => Response::text(json!({ "error": e.to_string() }).to_string()).with_status_code(500),
+                                    }
                                 }
-        
-                            }
-                        }
-        
-        
-                        // Brightness
-                        if input.brightness.is_some() {
-                            let brightness = match input.brightness {
-                                Some(b) => b,
-                                None => {
-                                    return Response::text(json!({
-                                        "error": "Missing brightness value"
-                                    }).to_string()).with_status_code(400);
+
+                                // POST /v1/lights/:selector/cycle
+                                Endpoint::Cycle => {
+                                    let body = try_or_400!(rouille::input::plain_text_body(request));
+                                    let input: CycleRequest = try_or_400!(serde_json::from_str(&body));
+                                    if let Err(e) = input.validate() {
+                                        return Response::text(json!({ "error": e.to_string() }).to_string()).with_status_code(400);
+                                    }
+                                    
+                                    let handler = CycleHandler::new();
+                                    let fast = input.fast.unwrap_or(false);
+                                    let cycle_response = handler.handle_cycle(mgr, &bulbs_vec, input);
+                                    accepted_or_json(fast, cycle_response.results.iter().all(|r| r.status == "ok"), &cycle_response)
                                 }
-                            };
-        
-                            for bulb in &bulbs_vec {
-        
-        
-                                let mut kelvin = 6500;
-                                let mut saturation = 0;
-                                let mut hue = 0;
-        
-                                let mut duration = 0;
-                                if input.duration.is_some(){
-                                    duration = input.duration.unwrap_or(0.0) as u32;
+                                
+                                // POST /v1/lights/:selector/clean
+                                Endpoint::Clean => {
+                                    let body = try_or_400!(rouille::input::plain_text_body(request));
+                                    let input: CleanRequest = try_or_400!(serde_json::from_str(&body));
+                                    
+                                    let handler = CleanHandler::new();
+                                    let fast = input.fast.unwrap_or(false);
+                                    let clean_response = handler.handle_clean(mgr, &bulbs_vec, input);
+                                    accepted_or_json(fast, clean_response.results.iter().all(|r| r.status == "ok"), &clean_response)
                                 }
         
-                                if let Some(lifxc) = bulb.lifx_color.as_ref() {
-                                    kelvin = lifxc.kelvin;
-                                    saturation = lifxc.saturation;
-                                    hue = lifxc.hue;
+                                // GET /v1/lights/:selector/tiles
+                                Endpoint::GetTiles => {
+                                    let handler = TilesHandler::new();
+                                    Response::json(&handler.handle_get(mgr, &bulbs_vec))
                                 }
-                                
-                                let new_brightness_float = match parse_f64_safe(&brightness.to_string()) {
-                                    Ok(b) => b,
-                                    Err(e) => {
-                                        error!("Error parsing brightness: {}", e);
-                                        continue;
+
+                                // PUT /v1/lights/:selector/tiles
+                                Endpoint::SetTiles => {
+                                    let body = try_or_400!(rouille::input::plain_text_body(request));
+                                    let input: TilesRequest = try_or_400!(serde_json::from_str(&body));
+                                    if let Err(e) = input.validate() {
+                                        return Response::text(json!({ "error": e.to_string() }).to_string()).with_status_code(400);
                                     }
-                                }; 
-                                let new_brightness: u16 = (LIFX_BRIGHTNESS_MAX * new_brightness_float as f32) as u16;
-                                let hbsk_set = HSBK {
-                                    hue: hue,
-                                    saturation: saturation,
-                                    brightness: new_brightness,
-                                    kelvin: kelvin,
-                                };
-                                bulb.set_color(&mgr.sock, hbsk_set, duration);
-        
-                            }
-        
-                        }
-        
-                        // Infrared
-                        if input.infrared.is_some() {
-                            let infrared_val = match input.infrared {
-                                Some(i) => i,
-                                None => {
-                                    return Response::text(json!({
-                                        "error": "Missing infrared value"
-                                    }).to_string()).with_status_code(400);
-                                }
-                            };
-                            let new_brightness: u16 = (LIFX_BRIGHTNESS_MAX * infrared_val as f32) as u16;
-        
-                            for bulb in &bulbs_vec {
-                                bulb.set_infrared(&mgr.sock, new_brightness);
-                            }
-                        }
-        
-        
-                        // Return results in proper format
-                        #[derive(Serialize)]
-                        struct SingleStateResult {
-                            id: String,
-                            label: String,
-                            status: String,
-                        }
 
-                        #[derive(Serialize)]
-                        struct SingleStateResponse {
-                            results: Vec<SingleStateResult>,
-                        }
+                                    let handler = TilesHandler::new();
+                                    let fast = input.fast.unwrap_or(false);
+                                    let tiles_response = handler.handle_set(mgr, &bulbs_vec, input);
+                                    accepted_or_json(fast, tiles_response.results.iter().all(|r| r.status == "ok"), &tiles_response)
+                                }
 
-                        let mut results = Vec::new();
-                        for bulb in &bulbs_vec {
-                            results.push(SingleStateResult {
-                                id: bulb.id.clone(),
-                                label: bulb.label.clone(),
-                                status: "ok".to_string(),
-                            });
-                        }
+                                // GET /v1/lights/:selector/info
+                                Endpoint::Info => {
+                                    let handler = InfoHandler::new();
+                                    Response::json(&handler.handle_info(mgr, &bulbs_vec))
+                                }
 
-                        response = Response::json(&SingleStateResponse { results });
-        
-                    }
-        
+                                // GET /v1/lights/:selector/clean
+                                Endpoint::CleanStatus => {
+                                    let handler = CleanHandler::new();
+                                    Response::json(&handler.handle_status(mgr, &bulbs_vec))
+                                }
         
-                        // ListLights
-                        // https://api.lifx.com/v1/lights/:selector
-                        if request.url().contains("/v1/lights/") && !request.url().contains("/state") && !request.url().contains("/effects") && !request.url().contains("/cycle") && !request.url().contains("/clean"){
-                            response = Response::json(&bulbs_vec.clone());
-                        }
-                        
-                        // Effects API endpoints
-                        // POST /v1/lights/:selector/effects/pulse
-                        if request.url().contains("/effects/pulse") && request.method() == "POST" {
-                            let body = try_or_400!(rouille::input::plain_text_body(request));
-                            let input: EffectRequest = try_or_400!(serde_json::from_str(&body));
-                            
-                            let handler = EffectsHandler::new();
-                            let effects_response = handler.handle_pulse(mgr, &bulbs_vec, input);
-                            response = Response::json(&effects_response);
-                        }
-                        
-                        // POST /v1/lights/:selector/effects/breathe
-                        if request.url().contains("/effects/breathe") && request.method() == "POST" {
-                            let body = try_or_400!(rouille::input::plain_text_body(request));
-                            let input: EffectRequest = try_or_400!(serde_json::from_str(&body));
-                            
-                            let handler = EffectsHandler::new();
-                            let effects_response = handler.handle_breathe(mgr, &bulbs_vec, input);
-                            response = Response::json(&effects_response);
-                        }
-                        
-                        // POST /v1/lights/:selector/effects/strobe
-                        if request.url().contains("/effects/strobe") && request.method() == "POST" {
-                            let body = try_or_400!(rouille::input::plain_text_body(request));
-                            let input: EffectRequest = try_or_400!(serde_json::from_str(&body));
-                            
-                            let handler = EffectsHandler::new();
-                            let effects_response = handler.handle_strobe(mgr, &bulbs_vec, input);
-                            response = Response::json(&effects_response);
-                        }
-                        
-                        // Cycle API endpoint
-                        // POST /v1/lights/:selector/cycle
-                        if request.url().contains("/cycle") && request.method() == "POST" {
-                            let body = try_or_400!(rouille::input::plain_text_body(request));
-                            let input: CycleRequest = try_or_400!(serde_json::from_str(&body));
-                            
-                            let handler = CycleHandler::new();
-                            let cycle_response = handler.handle_cycle(mgr, &bulbs_vec, input);
-                            response = Response::json(&cycle_response);
-                        }
-                        
-                        // Clean API endpoint
-                        // POST /v1/lights/:selector/clean
-                        if request.url().contains("/clean") && request.method() == "POST" {
-                            let body = try_or_400!(rouille::input::plain_text_body(request));
-                            let input: CleanRequest = try_or_400!(serde_json::from_str(&body));
-                            
-                            let handler = CleanHandler::new();
-                            let clean_response = handler.handle_clean(mgr, &bulbs_vec, input);
-                            response = Response::json(&clean_response);
+                                Endpoint::ListScenes | Endpoint::CreateScene | Endpoint::ActivateScene |
+                                Endpoint::DeleteScene | Endpoint::CaptureScene | Endpoint::SetStates |
+                                Endpoint::Color | Endpoint::ListGroups | Endpoint::ListLocations |
+                                Endpoint::RenameGroup | Endpoint::RenameLocation | Endpoint::Onboard => unreachable!(),
+                            }
                         }
-                    } // Close the else block here
-        
-        
-                    // Mutex locks will be automatically dropped when they go out of scope
-        
-                    return response;
-                });
-            });
-
+                    }
+                })
+            };
 
+            match server {
+                Ok(server) => {
+                    info!("HTTP API listening on {}", server.server_addr());
+                    thread::spawn(move || server.run());
+                }
+                Err(e) => {
+                    return Err(error::LifxError::ConfigError(format!(
+                        "Failed to bind HTTP server on {}: {}", http_addr, e
+                    )));
+                }
+            }
         },
         Err(e) => {
-            error!("Server error: {:?}", e);
+            error!("Server error: {}", e);
+            return Err(e);
         }
     }
 
+    Ok(())
+
 
 
 
@@ -1660,6 +2138,7 @@ pub fn start(config: Config) {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use palette::{Hsv, Srgb, IntoColor};
     use std::net::{IpAddr, Ipv4Addr};
 
     #[test]
@@ -1673,6 +2152,7 @@ mod tests {
         assert_eq!(bulb.source, source);
         assert_eq!(bulb.target, target);
         assert_eq!(bulb.addr, addr);
+        assert_eq!(bulb.id, "563412efcdab");
         assert_eq!(bulb.connected, true);
         assert_eq!(bulb.power, "off");
         assert_eq!(bulb.brightness, 0.0);
@@ -1701,6 +2181,161 @@ mod tests {
         assert!(bulb.last_seen > initial_last_seen);
     }
 
+    #[test]
+    fn test_bulb_liveness() {
+        let addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(192, 168, 1, 100)), 56700);
+        let mut bulb = BulbInfo::new(0x12345678, 0xABCDEF123456, addr);
+
+        bulb.update_liveness(Duration::from_secs(30));
+        assert!(bulb.connected);
+        assert_eq!(bulb.seconds_since_seen, 0);
+
+        bulb.last_seen = Instant::now() - Duration::from_secs(45);
+        bulb.last_seen_at = UNIX_EPOCH + Duration::from_secs(1450295893);
+        bulb.update_liveness(Duration::from_secs(30));
+        assert!(!bulb.connected);
+        assert_eq!(bulb.seconds_since_seen, 45);
+        assert_eq!(bulb.lifx_last_seen, "2015-12-16T19:58:13.000+00:00");
+
+        // Hearing from the bulb again brings it back
+        bulb.update(addr);
+        bulb.update_liveness(Duration::from_secs(30));
+        assert!(bulb.connected);
+    }
+
+    #[test]
+    fn test_acknowledged_changes_are_cached() {
+        let device = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let sock = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let mut bulb = BulbInfo::new(0x12345678, 0xABCDEF123456, device.local_addr().unwrap());
+        bulb.color = LiColor::Single(RefreshableData::empty(Duration::from_secs(15), Message::LightGet));
+        let red = HSBK { hue: 0, saturation: 65535, brightness: 32768, kelvin: 3500 };
+
+        bulb.set_power(&sock, PowerLevel::Enabled).unwrap();
+        bulb.set_color(&sock, red, 0).unwrap();
+        // Nothing changes until the bulb acknowledges
+        assert_eq!(bulb.power, "off");
+        assert!(bulb.lifx_color.is_none());
+
+        for sequence in 0..2 {
+            let message = bulb.delivery.acknowledge(sequence).unwrap();
+            bulb.apply_acknowledged(message);
+        }
+        assert_eq!(bulb.power, "on");
+        assert_eq!(bulb.power_level.as_ref(), Some(&PowerLevel::Enabled));
+        assert_eq!(bulb.lifx_color.as_ref().map(|c| c.brightness), Some(32768));
+        assert!((bulb.brightness - 0.5).abs() < 0.001);
+    }
+
+    #[test]
+    fn test_acknowledged_zones_are_cached() {
+        let device = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let sock = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let mut bulb = BulbInfo::new(0x12345678, 0xABCDEF123456, device.local_addr().unwrap());
+        let mut zones = RefreshableData::empty(Duration::from_secs(15), Message::GetColorZones { start_index: 0, end_index: 255 });
+        let red = HSBK { hue: 0, saturation: 65535, brightness: 65535, kelvin: 3500 };
+        zones.update(vec![Some(red); 4]);
+        bulb.color = LiColor::Multi(zones);
+        let blue = HSBK { hue: 43690, saturation: 65535, brightness: 65535, kelvin: 3500 };
+
+        bulb.set_color_zones(&sock, 1, 2, blue, 0, ApplicationRequest::Apply).unwrap();
+        let message = bulb.delivery.acknowledge(0).unwrap();
+        bulb.apply_acknowledged(message);
+
+        let hues: Vec<u16> = bulb.zones.as_ref().unwrap().iter().map(|zone| zone.hue).collect();
+        assert_eq!(hues, vec![0, 43690, 43690, 0]);
+    }
+
+    #[test]
+    fn test_extended_zones_are_not_queried_again_without_reply() {
+        let device = UdpSocket::bind("127.0.0.1:0").unwrap();
+        device.set_read_timeout(Some(Duration::from_millis(200))).unwrap();
+        let sock = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let mut bulb = BulbInfo::new(0x12345678, 0xABCDEF123456, device.local_addr().unwrap());
+        bulb.color = LiColor::Multi(RefreshableData::empty(Duration::from_secs(15), Message::GetColorZones { start_index: 0, end_index: 255 }));
+
+        bulb.query_for_missing_info(&sock).unwrap();
+        bulb.query_for_missing_info(&sock).unwrap();
+
+        let extended = ExtMessage::GetExtendedColorZones.message_type();
+        let mut buf = [0; 1024];
+        let mut queries = 0;
+        while let Ok(len) = device.recv(&mut buf) {
+            let (header, _) = protocol::decode_packet(&buf[..len]).unwrap();
+            if header.message_type == extended {
+                queries += 1;
+            }
+        }
+        assert_eq!(queries, 1);
+        assert!(!bulb.supports_extended_multizone());
+    }
+
+    #[test]
+    fn test_acknowledged_extended_zones_are_cached() {
+        let device = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let sock = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let mut bulb = BulbInfo::new(0x12345678, 0xABCDEF123456, device.local_addr().unwrap());
+        let mut zones = RefreshableData::empty(Duration::from_secs(15), Message::GetColorZones { start_index: 0, end_index: 255 });
+        let red = HSBK { hue: 0, saturation: 65535, brightness: 65535, kelvin: 3500 };
+        zones.update(vec![Some(red); 4]);
+        bulb.color = LiColor::Multi(zones);
+        bulb.extended_zones.update(4);
+        let green = HSBK { hue: 21845, saturation: 65535, brightness: 65535, kelvin: 3500 };
+        let blue = HSBK { hue: 43690, saturation: 65535, brightness: 65535, kelvin: 3500 };
+
+        let message = ExtMessage::SetExtendedColorZones { duration_ms: 0, apply: true, zone_index: 1, colors: vec![green, blue] };
+        bulb.send_ext(&sock, &message).unwrap();
+        let message = bulb.delivery.acknowledge(0).unwrap();
+        bulb.apply_acknowledged(message);
+
+        let hues: Vec<u16> = bulb.zones.as_ref().unwrap().iter().map(|zone| zone.hue).collect();
+        assert_eq!(hues, vec![0, 21845, 43690, 0]);
+    }
+
+    #[test]
+    fn test_ids_are_stable() {
+        assert_eq!(serial_from_target(0x0000_5634_12d5_73d0), "d073d5123456");
+        // The two spare bytes of the target are not part of the serial
+        assert_eq!(serial_from_target(0xffff_5634_12d5_73d0), "d073d5123456");
+
+        let addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(192, 168, 1, 100)), 56700);
+        let first = BulbInfo::new(0x1111, 0x5634_12d5_73d0, addr);
+        let second = BulbInfo::new(0x2222, 0x5634_12d5_73d0, addr);
+        assert_eq!(first.id, second.id);
+        assert_eq!(first.uuid, second.uuid);
+
+        let uuid = &first.uuid;
+        assert_eq!(uuid.len(), 36);
+        assert_eq!(&uuid[14..15], "8");
+        assert!(matches!(&uuid[19..20], "8" | "9" | "a" | "b"));
+        assert_ne!(uuid, &uuid_from_serial("d073d5123457"));
+    }
+
+    #[test]
+    fn test_group_and_location_ids_are_hex() {
+        let mut group = [0u8; 16];
+        group[0] = 0x1a;
+        group[15] = 0xff;
+        assert_eq!(hex_id(&group), "1a0000000000000000000000000000ff");
+
+        // Debug formatting used to render both of these as "1111"
+        assert_ne!(hex_id(&[1, 11, 1]), hex_id(&[11, 1, 1]));
+    }
+
+    #[test]
+    fn test_format_timestamp() {
+        assert_eq!(format_timestamp(UNIX_EPOCH), "1970-01-01T00:00:00.000+00:00");
+        assert_eq!(
+            format_timestamp(UNIX_EPOCH + Duration::from_millis(1450295893867)),
+            "2015-12-16T19:58:13.867+00:00"
+        );
+        // Leap day
+        assert_eq!(
+            format_timestamp(UNIX_EPOCH + Duration::from_secs(1709164800)),
+            "2024-02-29T00:00:00.000+00:00"
+        );
+    }
+
     #[test]
     fn test_refreshable_data_empty() {
         let data: RefreshableData<String> = RefreshableData::empty(
@@ -1762,6 +2397,7 @@ mod tests {
         let group = LifxGroup {
             id: "group123".to_string(),
             name: "Living Room".to_string(),
+            updated_at: 0,
         };
         
         assert_eq!(group.id, "group123");
@@ -1773,6 +2409,7 @@ mod tests {
         let location = LifxLocation {
             id: "loc456".to_string(),
             name: "Home".to_string(),
+            updated_at: 0,
         };
         
         assert_eq!(location.id, "loc456");
@@ -1785,6 +2422,7 @@ mod tests {
             secret_key: Some("test_secret".to_string()),
             port: 8080,
             auth_required: true,
+            ..Default::default()
         };
         
         assert_eq!(config.secret_key, Some("test_secret".to_string()));
@@ -1798,13 +2436,85 @@ mod tests {
             secret_key: None,
             port: 8080,
             auth_required: false,
+            ..Default::default()
         };
         
         assert_eq!(config.secret_key, None);
         assert_eq!(config.port, 8080);
         assert_eq!(config.auth_required, false);
+        assert_eq!(config.scenes_path, None);
     }
-    
+
+    #[test]
+    fn test_config_liveness_defaults() {
+        let config = Config::default();
+        assert_eq!(config.discovery_interval(), Duration::from_secs(300));
+        assert_eq!(config.offline_after(), Duration::from_secs(30));
+        assert_eq!(config.purge_after(), None);
+
+        let config: Config = serde_json::from_str(
+            r#"{"port": 8000, "authRequired": false, "discoveryIntervalSecs": 60, "purgeAfterSecs": 3600}"#
+        ).unwrap();
+        assert_eq!(config.discovery_interval(), Duration::from_secs(60));
+        assert_eq!(config.purge_after(), Some(Duration::from_secs(3600)));
+    }
+
+    #[test]
+    fn test_config_bind_addresses() {
+        let config = Config { port: 8000, ..Default::default() };
+        assert_eq!(config.http_addr().unwrap(), "0.0.0.0:8000".parse::<SocketAddr>().unwrap());
+        assert_eq!(config.lan_addr().unwrap(), "0.0.0.0:0".parse::<SocketAddr>().unwrap());
+
+        let config = Config {
+            port: 8000,
+            http_bind_address: Some("10.0.1.2".to_string()),
+            lan_bind_address: Some("::1".to_string()),
+            lan_port: Some(56700),
+            ..Default::default()
+        };
+        assert_eq!(config.http_addr().unwrap(), "10.0.1.2:8000".parse::<SocketAddr>().unwrap());
+        assert_eq!(config.lan_addr().unwrap(), "[::1]:56700".parse::<SocketAddr>().unwrap());
+
+        let config = Config { lan_bind_address: Some("iot0".to_string()), ..Default::default() };
+        assert!(matches!(config.lan_addr(), Err(error::LifxError::ConfigError(_))));
+    }
+
+    #[test]
+    fn test_manager_bound_to_loopback_starts() {
+        // Broadcasting from here to another interface's broadcast address fails with EINVAL
+        let config = Config { lan_bind_address: Some("127.0.0.1".to_string()), ..Default::default() };
+        let mut mgr = Manager::new(&config).unwrap();
+        assert!(mgr.discover().is_ok());
+    }
+
+    #[test]
+    fn test_config_static_devices() {
+        assert!(Config::default().static_devices.is_empty());
+
+        let config: Config = serde_json::from_str(
+            r#"{"port": 8000, "authRequired": false, "staticDevices": [
+                {"address": "10.0.20.15", "serial": "d073d5010203"},
+                {"address": "10.0.20.16:56700"}
+            ]}"#
+        ).unwrap();
+        assert_eq!(config.static_devices.len(), 2);
+        assert_eq!(config.static_devices[0].serial.as_deref(), Some("d073d5010203"));
+        assert_eq!(config.static_devices[1].serial, None);
+    }
+
+    #[test]
+    fn test_start_refuses_corrupt_scene_file() {
+        let path = std::env::temp_dir().join(format!("lifx-scenes-start-{:x}.json", rand::random::<u64>()));
+        std::fs::write(&path, "{ not valid json").unwrap();
+
+        let config = Config { scenes_path: Some(path.display().to_string()), ..Default::default() };
+        assert!(matches!(start(config), Err(error::LifxError::ConfigError(_))));
+        // Left in place for the operator to repair, not replaced with an empty list
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "{ not valid json");
+
+        let _ = std::fs::remove_file(&path);
+    }
+
     #[test]
     fn test_authentication_with_valid_token() {
         use std::sync::Arc;
@@ -4,18 +4,50 @@ use log::{info, warn, error};
 use rand::{thread_rng, Rng};
 use rand::distributions::Alphanumeric;
 
+/// Reads a number of seconds from the environment, ignoring unset or malformed values.
+fn env_secs(name: &str) -> Option<u64> {
+    let value = env::var(name).ok()?;
+    match value.trim().parse() {
+        Ok(secs) => Some(secs),
+        Err(_) => {
+            warn!("Ignoring {}={:?}, expected a number of seconds", name, value);
+            None
+        }
+    }
+}
+
+/// Reads `LIFX_STATIC_DEVICES`, a comma separated list of `address` or `address=serial` entries.
+fn env_static_devices() -> Vec<lifx_api_server::static_devices::StaticDevice> {
+    let value = env::var("LIFX_STATIC_DEVICES").unwrap_or_default();
+    value.split(',')
+        .map(str::trim)
+        .filter(|entry| !entry.is_empty())
+        .map(|entry| {
+            let mut parts = entry.splitn(2, '=');
+            lifx_api_server::static_devices::StaticDevice {
+                address: parts.next().unwrap_or_default().trim().to_string(),
+                serial: parts.next().map(|serial| serial.trim().to_string()),
+            }
+        })
+        .collect()
+}
+
 fn main() -> Result<(), Box<dyn std::error::Error>> {
     // Initialize logger with environment variable control
     env_logger::init();
 
-    if let Err(e) = sudo::with_env(&["SECRET_KEY"]) {
-        error!("Failed to preserve SECRET_KEY environment variable: {}", e);
-        std::process::exit(1);
-    }
-    
-    if let Err(e) = sudo::escalate_if_needed() {
-        error!("Failed to escalate privileges: {}", e);
-        std::process::exit(1);
+    // The library never escalates; only a privileged LAN port needs root
+    let lan_port: Option<u16> = env::var("LIFX_LAN_PORT").ok().and_then(|p| p.trim().parse().ok());
+    if lan_port.map_or(false, |port| port != 0 && port < 1024) {
+        if let Err(e) = sudo::with_env(&["SECRET_KEY", "LIFX_"]) {
+            error!("Failed to preserve environment variables: {}", e);
+            std::process::exit(1);
+        }
+
+        if let Err(e) = sudo::escalate_if_needed() {
+            error!("Failed to escalate privileges: {}", e);
+            std::process::exit(1);
+        }
     }
 
  
@@ -60,11 +92,22 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
     let config = lifx_api_server::Config { 
         secret_key,
         port: 8000,
-        auth_required
+        auth_required,
+        scenes_path: env::var("LIFX_SCENES_PATH").ok().filter(|p| !p.is_empty()),
+        discovery_interval_secs: env_secs("LIFX_DISCOVERY_INTERVAL"),
+        offline_after_secs: env_secs("LIFX_OFFLINE_AFTER"),
+        purge_after_secs: env_secs("LIFX_PURGE_AFTER"),
+        static_devices: env_static_devices(),
+        http_bind_address: env::var("LIFX_HTTP_BIND").ok().filter(|a| !a.is_empty()),
+        lan_bind_address: env::var("LIFX_LAN_BIND").ok().filter(|a| !a.is_empty()),
+        lan_port,
     };
 
     info!("Starting LIFX API server on port {}", config.port);
-    lifx_api_server::start(config);
+    if let Err(e) = lifx_api_server::start(config) {
+        error!("Failed to start LIFX API server: {}", e);
+        std::process::exit(1);
+    }
 
     // Now you can use curl to access the api
     // curl -X PUT "http://localhost:8089/v1/lights/all/state"      -H "Authorization: Bearer xxx"      -d "color=kelvin:9000"
@@ -0,0 +1,312 @@
+//! Wi-Fi onboarding of factory-reset bulbs.
+//!
+//! A bulb without Wi-Fi credentials runs its own access point (the soft AP) and answers on
+//! `172.16.0.1` to a host that has joined it. [`configure_access_point`] tells the bulb which
+//! network to join with SetAccessPoint; [`OnboardingHandler`] then waits for the bulb to show up
+//! on the main network through discovery.
+
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::io::ErrorKind;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr, UdpSocket};
+use std::thread;
+use std::time::{Duration, Instant};
+use log::{info, warn};
+use crate::error::{LifxError, Result};
+use crate::protocol::{decode_packet, encode_packet, ExtMessage, PacketHeader, PacketOptions, PASSPHRASE_SIZE, SSID_SIZE};
+use crate::static_devices::LIFX_PORT;
+use crate::{serial_from_target, BulbInfo, Manager};
+
+/// Where a bulb in soft AP mode answers.
+pub const SOFT_AP_ADDRESS: Ipv4Addr = Ipv4Addr::new(172, 16, 0, 1);
+/// Seconds to wait for an onboarded bulb to appear on the main network, unless the request says otherwise.
+pub const DEFAULT_JOIN_TIMEOUT: f64 = 60.0;
+/// Longest wait a request may ask for, as the request stays open until then.
+pub const MAX_JOIN_TIMEOUT: f64 = 600.0;
+
+const GET_SERVICE: u16 = 2;
+const STATE_SERVICE: u16 = 3;
+const ACKNOWLEDGEMENT: u16 = 45;
+/// SetAccessPoint interface number of the Wi-Fi client (station) interface.
+const STATION_INTERFACE: u8 = 2;
+const REPLY_TIMEOUT: Duration = Duration::from_secs(1);
+const ATTEMPTS: u32 = 3;
+/// How often discovery is repeated while waiting for the bulb to join.
+const DISCOVERY_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Wi-Fi security of the network the bulb should join.
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum WifiSecurity {
+    Open,
+    WepPsk,
+    WpaTkipPsk,
+    WpaAesPsk,
+    Wpa2AesPsk,
+    Wpa2TkipPsk,
+    Wpa2MixedPsk,
+}
+
+impl WifiSecurity {
+    /// The security type as sent in SetAccessPoint.
+    pub fn code(&self) -> u8 {
+        match self {
+            WifiSecurity::Open => 1,
+            WifiSecurity::WepPsk => 2,
+            WifiSecurity::WpaTkipPsk => 3,
+            WifiSecurity::WpaAesPsk => 4,
+            WifiSecurity::Wpa2AesPsk => 5,
+            WifiSecurity::Wpa2TkipPsk => 6,
+            WifiSecurity::Wpa2MixedPsk => 7,
+        }
+    }
+}
+
+impl Default for WifiSecurity {
+    fn default() -> Self {
+        WifiSecurity::Wpa2AesPsk
+    }
+}
+
+/// Body of `POST /v1/onboarding`.
+#[derive(Deserialize, Debug, Clone)]
+pub struct OnboardingRequest {
+    /// Soft AP address of the bulb, optionally with a port; `172.16.0.1` when omitted.
+    pub address: Option<String>,
+    pub ssid: String,
+    #[serde(default)]
+    pub passphrase: String,
+    #[serde(default)]
+    pub security: WifiSecurity,
+    /// Seconds to wait for the bulb to appear on the main network; `0` returns once the
+    /// credentials are acknowledged.
+    pub timeout: Option<f64>,
+}
+
+impl OnboardingRequest {
+    pub fn validate(&self) -> Result<()> {
+        if self.ssid.is_empty() || self.ssid.len() > SSID_SIZE {
+            return Err(LifxError::ValidationError(format!("ssid must be 1 to {} bytes", SSID_SIZE)));
+        }
+        if self.passphrase.len() > PASSPHRASE_SIZE {
+            return Err(LifxError::ValidationError(format!("passphrase must be at most {} bytes", PASSPHRASE_SIZE)));
+        }
+        if self.security != WifiSecurity::Open && self.passphrase.is_empty() {
+            return Err(LifxError::ValidationError("passphrase is required unless security is 'open'".to_string()));
+        }
+        if self.timeout.map_or(false, |t| !(0.0..=MAX_JOIN_TIMEOUT).contains(&t)) {
+            return Err(LifxError::ValidationError(format!("timeout must be between 0 and {} seconds", MAX_JOIN_TIMEOUT)));
+        }
+        self.soft_ap_addr().map(|_| ())
+    }
+
+    pub fn soft_ap_addr(&self) -> Result<SocketAddr> {
+        let address = match self.address.as_deref().map(str::trim) {
+            None | Some("") => return Ok(SocketAddr::new(IpAddr::V4(SOFT_AP_ADDRESS), LIFX_PORT)),
+            Some(address) => address,
+        };
+        if let Ok(addr) = address.parse::<SocketAddr>() {
+            return Ok(addr);
+        }
+        address.parse::<IpAddr>()
+            .map(|ip| SocketAddr::new(ip, LIFX_PORT))
+            .map_err(|_| LifxError::ValidationError(format!("address must be an IP address, got '{}'", address)))
+    }
+
+    fn join_timeout(&self) -> Duration {
+        Duration::from_secs_f64(self.timeout.unwrap_or(DEFAULT_JOIN_TIMEOUT))
+    }
+}
+
+#[derive(Serialize, Debug)]
+pub struct OnboardingResult {
+    /// Serial of the onboarded bulb, once it answered on its soft AP.
+    pub id: Option<String>,
+    /// `ok` once the bulb is seen on the main network, `configured` if it took the credentials
+    /// but has not appeared (yet), `unreachable` or `error` otherwise.
+    pub status: String,
+    /// Address the bulb was found at on the main network.
+    pub address: Option<String>,
+    pub message: Option<String>,
+}
+
+pub struct OnboardingHandler;
+
+impl OnboardingHandler {
+    pub fn new() -> Self {
+        OnboardingHandler
+    }
+
+    /// Sends the credentials to the bulb at `request.address`, then runs discovery until the
+    /// bulb shows up or the timeout passes.
+    ///
+    /// Only locks the bulbs briefly to look for the bulb, so `mgr` should be a handle of its own
+    /// (as every request gets one) for other requests and the refresh thread to carry on.
+    pub fn handle_onboarding(&self, mgr: &mut Manager, request: OnboardingRequest) -> OnboardingResult {
+        let failed = |status: &str, id: Option<String>, message: String| OnboardingResult {
+            id,
+            status: status.to_string(),
+            address: None,
+            message: Some(message),
+        };
+
+        let soft_ap = match request.soft_ap_addr() {
+            Ok(addr) => addr,
+            Err(e) => return failed("error", None, e.to_string()),
+        };
+        // A socket of our own, so replies from the soft AP don't go to the receive worker
+        let sock = match UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0)) {
+            Ok(sock) => sock,
+            Err(e) => return failed("error", None, format!("Failed to open onboarding socket: {}", e)),
+        };
+        let target = match configure_access_point(&sock, soft_ap, mgr.source, &request) {
+            Ok(target) => target,
+            Err(e) => return failed("unreachable", None, e.to_string()),
+        };
+        let id = serial_from_target(target);
+        info!("Bulb {} accepted Wi-Fi credentials for '{}'", id, request.ssid);
+
+        let configured_at = Instant::now();
+        let deadline = configured_at + request.join_timeout();
+        let mut last_discovery: Option<Instant> = None;
+        loop {
+            let joined = mgr.bulbs.lock().ok().and_then(|bulbs| rejoined(&bulbs, target, configured_at, soft_ap));
+            if let Some(addr) = joined {
+                return OnboardingResult {
+                    id: Some(id),
+                    status: "ok".to_string(),
+                    address: Some(addr.to_string()),
+                    message: None,
+                };
+            }
+            if Instant::now() >= deadline {
+                return failed("configured", Some(id), "Bulb has not appeared on the network yet".to_string());
+            }
+            if last_discovery.map_or(true, |at| at.elapsed() >= DISCOVERY_INTERVAL) {
+                if let Err(e) = mgr.discover() {
+                    warn!("Discovery while onboarding failed: {:?}", e);
+                }
+                last_discovery = Some(Instant::now());
+            }
+            thread::sleep(Duration::from_millis(200));
+        }
+    }
+}
+
+impl Default for OnboardingHandler {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Sends SetAccessPoint to a bulb in soft AP mode and waits for it to acknowledge.
+///
+/// The bulb is first asked for its service to learn its serial, which is returned. Each step
+/// is retried a few times, as the soft AP link is lossy.
+pub fn configure_access_point(sock: &UdpSocket, addr: SocketAddr, source: u32, request: &OnboardingRequest) -> Result<u64> {
+    sock.set_read_timeout(Some(REPLY_TIMEOUT))?;
+
+    let get_service = PacketOptions { source, res_required: true, ..Default::default() };
+    let service = exchange(sock, addr, &encode_packet(&get_service, GET_SERVICE, &[]), |header| {
+        header.message_type == STATE_SERVICE && header.source == source
+    })?;
+
+    let set_access_point = ExtMessage::SetAccessPoint {
+        interface: STATION_INTERFACE,
+        ssid: request.ssid.clone(),
+        passphrase: request.passphrase.clone(),
+        security: request.security.code(),
+    };
+    let options = PacketOptions { source, target: service.target, sequence: 1, ack_required: true, res_required: false };
+    exchange(sock, addr, &set_access_point.encode(&options), |header| {
+        header.message_type == ACKNOWLEDGEMENT && header.source == source && header.sequence == options.sequence
+    })?;
+
+    Ok(service.target)
+}
+
+/// Sends `packet` until a reply accepted by `matches` arrives, up to `ATTEMPTS` times.
+fn exchange<F>(sock: &UdpSocket, addr: SocketAddr, packet: &[u8], matches: F) -> Result<PacketHeader>
+where
+    F: Fn(&PacketHeader) -> bool,
+{
+    let mut buf = [0; 1024];
+    for _ in 0..ATTEMPTS {
+        sock.send_to(packet, addr)?;
+        let deadline = Instant::now() + REPLY_TIMEOUT;
+        while Instant::now() < deadline {
+            match sock.recv_from(&mut buf) {
+                Ok((len, _)) => {
+                    if let Some((header, _)) = decode_packet(&buf[..len]) {
+                        if matches(&header) {
+                            return Ok(header);
+                        }
+                    }
+                }
+                Err(ref e) if e.kind() == ErrorKind::WouldBlock || e.kind() == ErrorKind::TimedOut => break,
+                Err(e) => return Err(e.into()),
+            }
+        }
+    }
+    Err(LifxError::DeviceNotFound(format!("no reply from {} after {} attempts", addr, ATTEMPTS)))
+}
+
+/// Address of `target` if it has been heard from since `since` anywhere but its soft AP.
+fn rejoined(bulbs: &HashMap<u64, BulbInfo>, target: u64, since: Instant, soft_ap: SocketAddr) -> Option<SocketAddr> {
+    bulbs.get(&target)
+        .filter(|bulb| bulb.last_seen >= since && bulb.addr.ip() != soft_ap.ip())
+        .map(|bulb| bulb.addr)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(ssid: &str, passphrase: &str, security: WifiSecurity) -> OnboardingRequest {
+        OnboardingRequest {
+            address: None,
+            ssid: ssid.to_string(),
+            passphrase: passphrase.to_string(),
+            security,
+            timeout: None,
+        }
+    }
+
+    #[test]
+    fn test_onboarding_request_validation() {
+        let parsed: OnboardingRequest = serde_json::from_str(
+            r#"{"ssid": "IoT", "passphrase": "secret", "security": "wpa2_mixed_psk", "timeout": 0}"#
+        ).unwrap();
+        assert!(parsed.validate().is_ok());
+        assert_eq!(parsed.security.code(), 7);
+        assert_eq!(parsed.soft_ap_addr().unwrap(), "172.16.0.1:56700".parse::<SocketAddr>().unwrap());
+
+        assert!(request("IoT", "", WifiSecurity::Open).validate().is_ok());
+        assert!(request("IoT", "", WifiSecurity::Wpa2AesPsk).validate().is_err());
+        assert!(request("", "secret", WifiSecurity::Wpa2AesPsk).validate().is_err());
+        assert!(request(&"s".repeat(33), "secret", WifiSecurity::Wpa2AesPsk).validate().is_err());
+        assert!(request("IoT", &"p".repeat(65), WifiSecurity::Wpa2AesPsk).validate().is_err());
+
+        let mut too_long = request("IoT", "secret", WifiSecurity::Wpa2AesPsk);
+        too_long.timeout = Some(1e300);
+        assert!(too_long.validate().is_err());
+
+        let mut bad_address = request("IoT", "secret", WifiSecurity::Wpa2AesPsk);
+        bad_address.address = Some("lifx-bulb".to_string());
+        assert!(bad_address.validate().is_err());
+    }
+
+    #[test]
+    fn test_rejoined_ignores_the_soft_ap() {
+        let soft_ap = SocketAddr::new(IpAddr::V4(SOFT_AP_ADDRESS), LIFX_PORT);
+        let since = Instant::now();
+        let mut bulbs = HashMap::new();
+        bulbs.insert(7, BulbInfo::new(0x1234, 7, soft_ap));
+        assert_eq!(rejoined(&bulbs, 7, since, soft_ap), None);
+
+        let home: SocketAddr = "192.168.1.40:56700".parse().unwrap();
+        bulbs.get_mut(&7).unwrap().update(home);
+        assert_eq!(rejoined(&bulbs, 7, since, soft_ap), Some(home));
+        assert_eq!(rejoined(&bulbs, 8, since, soft_ap), None);
+    }
+}
@@ -0,0 +1,903 @@
+//! Encoding and decoding of LAN protocol packets that `lifx_rs::lan::Message` does not support.
+//!
+//! Packets are built by hand following the published LIFX LAN protocol: a 36 byte header
+//! (frame, frame address, protocol header) followed by a little-endian payload.
+
+use lifx_rs::lan::HSBK;
+use serde::Serialize;
+
+pub const HEADER_SIZE: usize = 36;
+const PROTOCOL_NUMBER: u16 = 1024;
+const ADDRESSABLE: u16 = 1 << 12;
+const TAGGED: u16 = 1 << 13;
+
+// Message types
+const GET_HEV_CYCLE: u16 = 142;
+const SET_HEV_CYCLE: u16 = 143;
+const STATE_HEV_CYCLE: u16 = 144;
+const GET_HEV_CYCLE_CONFIGURATION: u16 = 145;
+const SET_HEV_CYCLE_CONFIGURATION: u16 = 146;
+const STATE_HEV_CYCLE_CONFIGURATION: u16 = 147;
+const GET_LAST_HEV_CYCLE_RESULT: u16 = 148;
+const STATE_LAST_HEV_CYCLE_RESULT: u16 = 149;
+const SET_EXTENDED_COLOR_ZONES: u16 = 510;
+const SET_MULTI_ZONE_EFFECT: u16 = 508;
+const GET_EXTENDED_COLOR_ZONES: u16 = 511;
+const STATE_EXTENDED_COLOR_ZONES: u16 = 512;
+const GET_DEVICE_CHAIN: u16 = 701;
+const STATE_DEVICE_CHAIN: u16 = 702;
+const GET_64: u16 = 707;
+const STATE_64: u16 = 711;
+const SET_64: u16 = 715;
+const SET_TILE_EFFECT: u16 = 719;
+const SET_ACCESS_POINT: u16 = 305;
+
+/// Number of color slots in every extended multizone payload.
+pub const EXTENDED_ZONES_PER_MESSAGE: usize = 82;
+/// Number of tile slots in a StateDeviceChain payload.
+pub const CHAIN_SLOTS: usize = 16;
+/// Number of color slots in Get64 / State64 / Set64 payloads.
+pub const TILE_PIXELS: usize = 64;
+/// Maximum number of colors in a SetTileEffect palette.
+pub const TILE_EFFECT_PALETTE_SIZE: usize = 16;
+/// Size of the SSID field of SetAccessPoint.
+pub const SSID_SIZE: usize = 32;
+/// Size of the passphrase field of SetAccessPoint.
+pub const PASSPHRASE_SIZE: usize = 64;
+
+/// Frame fields needed to address a packet.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct PacketOptions {
+    pub source: u32,
+    /// Device serial as stored in `frame_addr.target`; `0` broadcasts to all devices.
+    pub target: u64,
+    pub sequence: u8,
+    pub ack_required: bool,
+    pub res_required: bool,
+}
+
+/// Header fields of a received packet.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PacketHeader {
+    pub size: u16,
+    pub tagged: bool,
+    pub source: u32,
+    pub target: u64,
+    pub ack_required: bool,
+    pub res_required: bool,
+    pub sequence: u8,
+    pub message_type: u16,
+}
+
+/// Builds a complete packet for `message_type` with the given payload.
+pub fn encode_packet(options: &PacketOptions, message_type: u16, payload: &[u8]) -> Vec<u8> {
+    let size = (HEADER_SIZE + payload.len()) as u16;
+    let mut flags = PROTOCOL_NUMBER | ADDRESSABLE;
+    if options.target == 0 {
+        flags |= TAGGED;
+    }
+
+    let mut packet = Vec::with_capacity(size as usize);
+    // Frame
+    packet.extend_from_slice(&size.to_le_bytes());
+    packet.extend_from_slice(&flags.to_le_bytes());
+    packet.extend_from_slice(&options.source.to_le_bytes());
+    // Frame address
+    packet.extend_from_slice(&options.target.to_le_bytes());
+    packet.extend_from_slice(&[0; 6]);
+    packet.push((options.res_required as u8) | ((options.ack_required as u8) << 1));
+    packet.push(options.sequence);
+    // Protocol header
+    packet.extend_from_slice(&[0; 8]);
+    packet.extend_from_slice(&message_type.to_le_bytes());
+    packet.extend_from_slice(&[0; 2]);
+
+    packet.extend_from_slice(payload);
+    packet
+}
+
+/// Splits a received packet into its header and payload.
+pub fn decode_packet(packet: &[u8]) -> Option<(PacketHeader, &[u8])> {
+    let mut reader = Reader::new(packet);
+    let size = reader.u16()?;
+    let flags = reader.u16()?;
+    let source = reader.u32()?;
+    let target = reader.u64()?;
+    reader.skip(6)?;
+    let response_flags = reader.u8()?;
+    let sequence = reader.u8()?;
+    reader.skip(8)?;
+    let message_type = reader.u16()?;
+    reader.skip(2)?;
+
+    if (size as usize) < HEADER_SIZE || packet.len() < size as usize {
+        return None;
+    }
+
+    let header = PacketHeader {
+        size,
+        tagged: flags & TAGGED != 0,
+        source,
+        target,
+        ack_required: response_flags & 0b10 != 0,
+        res_required: response_flags & 0b01 != 0,
+        sequence,
+        message_type,
+    };
+    Some((header, &packet[HEADER_SIZE..size as usize]))
+}
+
+/// Outcome of the last HEV cycle as reported by StateLastHevCycleResult.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum HevCycleResult {
+    Success,
+    Busy,
+    InterruptedByReset,
+    InterruptedByHomekit,
+    InterruptedByLan,
+    InterruptedByCloud,
+    None,
+    Unknown(u8),
+}
+
+impl From<u8> for HevCycleResult {
+    fn from(value: u8) -> Self {
+        match value {
+            0 => HevCycleResult::Success,
+            1 => HevCycleResult::Busy,
+            2 => HevCycleResult::InterruptedByReset,
+            3 => HevCycleResult::InterruptedByHomekit,
+            4 => HevCycleResult::InterruptedByLan,
+            5 => HevCycleResult::InterruptedByCloud,
+            255 => HevCycleResult::None,
+            other => HevCycleResult::Unknown(other),
+        }
+    }
+}
+
+impl From<HevCycleResult> for u8 {
+    fn from(result: HevCycleResult) -> u8 {
+        match result {
+            HevCycleResult::Success => 0,
+            HevCycleResult::Busy => 1,
+            HevCycleResult::InterruptedByReset => 2,
+            HevCycleResult::InterruptedByHomekit => 3,
+            HevCycleResult::InterruptedByLan => 4,
+            HevCycleResult::InterruptedByCloud => 5,
+            HevCycleResult::None => 255,
+            HevCycleResult::Unknown(other) => other,
+        }
+    }
+}
+
+/// Firmware effects of multizone devices.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MultiZoneEffectType {
+    Off = 0,
+    Move = 1,
+}
+
+impl MultiZoneEffectType {
+    fn from_u8(value: u8) -> Option<Self> {
+        match value {
+            0 => Some(MultiZoneEffectType::Off),
+            1 => Some(MultiZoneEffectType::Move),
+            _ => None,
+        }
+    }
+}
+
+/// Firmware effects of matrix devices.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TileEffectType {
+    Off = 0,
+    Morph = 2,
+    Flame = 3,
+}
+
+impl TileEffectType {
+    fn from_u8(value: u8) -> Option<Self> {
+        match value {
+            0 => Some(TileEffectType::Off),
+            2 => Some(TileEffectType::Morph),
+            3 => Some(TileEffectType::Flame),
+            _ => None,
+        }
+    }
+}
+
+/// One device of a tile chain as reported by StateDeviceChain.
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct TileDevice {
+    pub accel_meas_x: i16,
+    pub accel_meas_y: i16,
+    pub accel_meas_z: i16,
+    pub user_x: f32,
+    pub user_y: f32,
+    pub width: u8,
+    pub height: u8,
+    pub device_version_vendor: u32,
+    pub device_version_product: u32,
+    pub firmware_build: u64,
+    pub firmware_version_minor: u16,
+    pub firmware_version_major: u16,
+}
+
+impl TileDevice {
+    fn write(&self, payload: &mut Vec<u8>) {
+        payload.extend_from_slice(&self.accel_meas_x.to_le_bytes());
+        payload.extend_from_slice(&self.accel_meas_y.to_le_bytes());
+        payload.extend_from_slice(&self.accel_meas_z.to_le_bytes());
+        payload.extend_from_slice(&[0; 2]);
+        payload.extend_from_slice(&self.user_x.to_le_bytes());
+        payload.extend_from_slice(&self.user_y.to_le_bytes());
+        payload.push(self.width);
+        payload.push(self.height);
+        payload.push(0);
+        payload.extend_from_slice(&self.device_version_vendor.to_le_bytes());
+        payload.extend_from_slice(&self.device_version_product.to_le_bytes());
+        payload.extend_from_slice(&[0; 4]);
+        payload.extend_from_slice(&self.firmware_build.to_le_bytes());
+        payload.extend_from_slice(&[0; 8]);
+        payload.extend_from_slice(&self.firmware_version_minor.to_le_bytes());
+        payload.extend_from_slice(&self.firmware_version_major.to_le_bytes());
+        payload.extend_from_slice(&[0; 4]);
+    }
+
+    fn read(reader: &mut Reader) -> Option<TileDevice> {
+        let accel_meas_x = reader.i16()?;
+        let accel_meas_y = reader.i16()?;
+        let accel_meas_z = reader.i16()?;
+        reader.skip(2)?;
+        let user_x = reader.f32()?;
+        let user_y = reader.f32()?;
+        let width = reader.u8()?;
+        let height = reader.u8()?;
+        reader.skip(1)?;
+        let device_version_vendor = reader.u32()?;
+        let device_version_product = reader.u32()?;
+        reader.skip(4)?;
+        let firmware_build = reader.u64()?;
+        reader.skip(8)?;
+        let firmware_version_minor = reader.u16()?;
+        let firmware_version_major = reader.u16()?;
+        reader.skip(4)?;
+
+        Some(TileDevice {
+            accel_meas_x,
+            accel_meas_y,
+            accel_meas_z,
+            user_x,
+            user_y,
+            width,
+            height,
+            device_version_vendor,
+            device_version_product,
+            firmware_build,
+            firmware_version_minor,
+            firmware_version_major,
+        })
+    }
+}
+
+/// Messages encoded locally because `lifx_rs::lan::Message` lacks them.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ExtMessage {
+    GetHevCycle,
+    SetHevCycle { enable: bool, duration_s: u32 },
+    StateHevCycle { duration_s: u32, remaining_s: u32, last_power: bool },
+    GetHevCycleConfiguration,
+    SetHevCycleConfiguration { indication: bool, duration_s: u32 },
+    StateHevCycleConfiguration { indication: bool, duration_s: u32 },
+    GetLastHevCycleResult,
+    StateLastHevCycleResult { result: HevCycleResult },
+    /// Sets up to 82 zones starting at `zone_index`; `apply: false` buffers the change until the
+    /// next message that applies.
+    SetExtendedColorZones { duration_ms: u32, apply: bool, zone_index: u16, colors: Vec<HSBK> },
+    GetExtendedColorZones,
+    StateExtendedColorZones { zones_count: u16, zone_index: u16, colors: Vec<HSBK> },
+    GetDeviceChain,
+    /// `tiles` holds the `tile_count` devices of the chain, starting at `start_index`.
+    StateDeviceChain { start_index: u8, tiles: Vec<TileDevice>, tile_count: u8 },
+    /// Requests a State64 for each of `length` tiles from `tile_index`.
+    Get64 { tile_index: u8, length: u8, x: u8, y: u8, width: u8 },
+    State64 { tile_index: u8, x: u8, y: u8, width: u8, colors: Vec<HSBK> },
+    /// Sets up to 64 pixels, row by row `width` pixels wide from (`x`, `y`), on `length` tiles.
+    Set64 { tile_index: u8, length: u8, x: u8, y: u8, width: u8, duration_ms: u32, colors: Vec<HSBK> },
+    /// `duration_ns` of `0` runs the effect until it is turned off. For Move, `parameters[1]` is
+    /// the direction: `0` away from the controller, `1` towards it.
+    SetMultiZoneEffect {
+        instance_id: u32,
+        effect: MultiZoneEffectType,
+        speed_ms: u32,
+        duration_ns: u64,
+        parameters: [u32; 8],
+    },
+    /// `duration_ns` of `0` runs the effect until it is turned off; the palette holds up to 16 colors.
+    SetTileEffect {
+        instance_id: u32,
+        effect: TileEffectType,
+        speed_ms: u32,
+        duration_ns: u64,
+        palette: Vec<HSBK>,
+    },
+    /// Wi-Fi credentials for a bulb in soft AP mode. `ssid` and `passphrase` are NUL padded to
+    /// 32 and 64 bytes; `security` is the LIFX security type, e.g. `5` for WPA2 AES PSK.
+    SetAccessPoint { interface: u8, ssid: String, passphrase: String, security: u8 },
+}
+
+impl ExtMessage {
+    pub fn message_type(&self) -> u16 {
+        match self {
+            ExtMessage::GetHevCycle => GET_HEV_CYCLE,
+            ExtMessage::SetHevCycle { .. } => SET_HEV_CYCLE,
+            ExtMessage::StateHevCycle { .. } => STATE_HEV_CYCLE,
+            ExtMessage::GetHevCycleConfiguration => GET_HEV_CYCLE_CONFIGURATION,
+            ExtMessage::SetHevCycleConfiguration { .. } => SET_HEV_CYCLE_CONFIGURATION,
+            ExtMessage::StateHevCycleConfiguration { .. } => STATE_HEV_CYCLE_CONFIGURATION,
+            ExtMessage::GetLastHevCycleResult => GET_LAST_HEV_CYCLE_RESULT,
+            ExtMessage::StateLastHevCycleResult { .. } => STATE_LAST_HEV_CYCLE_RESULT,
+            ExtMessage::SetExtendedColorZones { .. } => SET_EXTENDED_COLOR_ZONES,
+            ExtMessage::GetExtendedColorZones => GET_EXTENDED_COLOR_ZONES,
+            ExtMessage::StateExtendedColorZones { .. } => STATE_EXTENDED_COLOR_ZONES,
+            ExtMessage::GetDeviceChain => GET_DEVICE_CHAIN,
+            ExtMessage::StateDeviceChain { .. } => STATE_DEVICE_CHAIN,
+            ExtMessage::Get64 { .. } => GET_64,
+            ExtMessage::State64 { .. } => STATE_64,
+            ExtMessage::Set64 { .. } => SET_64,
+            ExtMessage::SetMultiZoneEffect { .. } => SET_MULTI_ZONE_EFFECT,
+            ExtMessage::SetTileEffect { .. } => SET_TILE_EFFECT,
+            ExtMessage::SetAccessPoint { .. } => SET_ACCESS_POINT,
+        }
+    }
+
+    /// Whether this message asks the device for a State reply.
+    pub fn is_query(&self) -> bool {
+        matches!(
+            self,
+            ExtMessage::GetHevCycle
+                | ExtMessage::GetHevCycleConfiguration
+                | ExtMessage::GetLastHevCycleResult
+                | ExtMessage::GetExtendedColorZones
+                | ExtMessage::GetDeviceChain
+                | ExtMessage::Get64 { .. }
+        )
+    }
+
+    pub fn payload(&self) -> Vec<u8> {
+        let mut payload = Vec::new();
+        match *self {
+            ExtMessage::GetHevCycle
+            | ExtMessage::GetHevCycleConfiguration
+            | ExtMessage::GetLastHevCycleResult
+            | ExtMessage::GetExtendedColorZones
+            | ExtMessage::GetDeviceChain => {}
+            ExtMessage::SetHevCycle { enable, duration_s } => {
+                payload.push(enable as u8);
+                payload.extend_from_slice(&duration_s.to_le_bytes());
+            }
+            ExtMessage::StateHevCycle { duration_s, remaining_s, last_power } => {
+                payload.extend_from_slice(&duration_s.to_le_bytes());
+                payload.extend_from_slice(&remaining_s.to_le_bytes());
+                payload.push(last_power as u8);
+            }
+            ExtMessage::SetHevCycleConfiguration { indication, duration_s }
+            | ExtMessage::StateHevCycleConfiguration { indication, duration_s } => {
+                payload.push(indication as u8);
+                payload.extend_from_slice(&duration_s.to_le_bytes());
+            }
+            ExtMessage::StateLastHevCycleResult { result } => {
+                payload.push(result.into());
+            }
+            ExtMessage::SetExtendedColorZones { duration_ms, apply, zone_index, ref colors } => {
+                payload.extend_from_slice(&duration_ms.to_le_bytes());
+                payload.push(apply as u8);
+                payload.extend_from_slice(&zone_index.to_le_bytes());
+                write_zone_colors(&mut payload, colors);
+            }
+            ExtMessage::StateExtendedColorZones { zones_count, zone_index, ref colors } => {
+                payload.extend_from_slice(&zones_count.to_le_bytes());
+                payload.extend_from_slice(&zone_index.to_le_bytes());
+                write_zone_colors(&mut payload, colors);
+            }
+            ExtMessage::StateDeviceChain { start_index, ref tiles, tile_count } => {
+                payload.push(start_index);
+                let blank = TileDevice::default();
+                for slot in 0..CHAIN_SLOTS {
+                    tiles.get(slot).unwrap_or(&blank).write(&mut payload);
+                }
+                payload.push(tile_count);
+            }
+            ExtMessage::Get64 { tile_index, length, x, y, width } => {
+                payload.extend_from_slice(&[tile_index, length, 0, x, y, width]);
+            }
+            ExtMessage::State64 { tile_index, x, y, width, ref colors } => {
+                payload.extend_from_slice(&[tile_index, 0, x, y, width]);
+                write_tile_colors(&mut payload, colors);
+            }
+            ExtMessage::Set64 { tile_index, length, x, y, width, duration_ms, ref colors } => {
+                payload.extend_from_slice(&[tile_index, length, 0, x, y, width]);
+                payload.extend_from_slice(&duration_ms.to_le_bytes());
+                write_tile_colors(&mut payload, colors);
+            }
+            ExtMessage::SetMultiZoneEffect { instance_id, effect, speed_ms, duration_ns, parameters } => {
+                payload.extend_from_slice(&instance_id.to_le_bytes());
+                payload.push(effect as u8);
+                payload.extend_from_slice(&[0; 2]);
+                payload.extend_from_slice(&speed_ms.to_le_bytes());
+                payload.extend_from_slice(&duration_ns.to_le_bytes());
+                payload.extend_from_slice(&[0; 8]);
+                for parameter in &parameters {
+                    payload.extend_from_slice(&parameter.to_le_bytes());
+                }
+            }
+            ExtMessage::SetTileEffect { instance_id, effect, speed_ms, duration_ns, ref palette } => {
+                payload.extend_from_slice(&[0; 2]);
+                payload.extend_from_slice(&instance_id.to_le_bytes());
+                payload.push(effect as u8);
+                payload.extend_from_slice(&speed_ms.to_le_bytes());
+                payload.extend_from_slice(&duration_ns.to_le_bytes());
+                payload.extend_from_slice(&[0; 8]);
+                // Effect parameters, unused by morph and flame
+                payload.extend_from_slice(&[0; 32]);
+                let palette = &palette[..palette.len().min(TILE_EFFECT_PALETTE_SIZE)];
+                payload.push(palette.len() as u8);
+                for color in palette {
+                    write_hsbk(&mut payload, color);
+                }
+                payload.resize(payload.len() + (TILE_EFFECT_PALETTE_SIZE - palette.len()) * 8, 0);
+            }
+            ExtMessage::SetAccessPoint { interface, ref ssid, ref passphrase, security } => {
+                payload.push(interface);
+                write_padded(&mut payload, ssid, SSID_SIZE);
+                write_padded(&mut payload, passphrase, PASSPHRASE_SIZE);
+                payload.push(security);
+            }
+        }
+        payload
+    }
+
+    pub fn encode(&self, options: &PacketOptions) -> Vec<u8> {
+        encode_packet(options, self.message_type(), &self.payload())
+    }
+
+    /// Decodes a payload of one of the message types handled here.
+    ///
+    /// Returns `None` for other message types (left to `lifx_rs`) and for truncated payloads.
+    pub fn decode(message_type: u16, payload: &[u8]) -> Option<ExtMessage> {
+        let mut reader = Reader::new(payload);
+        let message = match message_type {
+            GET_HEV_CYCLE => ExtMessage::GetHevCycle,
+            SET_HEV_CYCLE => ExtMessage::SetHevCycle {
+                enable: reader.bool()?,
+                duration_s: reader.u32()?,
+            },
+            STATE_HEV_CYCLE => ExtMessage::StateHevCycle {
+                duration_s: reader.u32()?,
+                remaining_s: reader.u32()?,
+                last_power: reader.bool()?,
+            },
+            GET_HEV_CYCLE_CONFIGURATION => ExtMessage::GetHevCycleConfiguration,
+            SET_HEV_CYCLE_CONFIGURATION => ExtMessage::SetHevCycleConfiguration {
+                indication: reader.bool()?,
+                duration_s: reader.u32()?,
+            },
+            STATE_HEV_CYCLE_CONFIGURATION => ExtMessage::StateHevCycleConfiguration {
+                indication: reader.bool()?,
+                duration_s: reader.u32()?,
+            },
+            GET_LAST_HEV_CYCLE_RESULT => ExtMessage::GetLastHevCycleResult,
+            STATE_LAST_HEV_CYCLE_RESULT => ExtMessage::StateLastHevCycleResult {
+                result: reader.u8()?.into(),
+            },
+            SET_EXTENDED_COLOR_ZONES => ExtMessage::SetExtendedColorZones {
+                duration_ms: reader.u32()?,
+                apply: reader.u8()? != 0,
+                zone_index: reader.u16()?,
+                colors: reader.zone_colors()?,
+            },
+            GET_EXTENDED_COLOR_ZONES => ExtMessage::GetExtendedColorZones,
+            STATE_EXTENDED_COLOR_ZONES => ExtMessage::StateExtendedColorZones {
+                zones_count: reader.u16()?,
+                zone_index: reader.u16()?,
+                colors: reader.zone_colors()?,
+            },
+            GET_DEVICE_CHAIN => ExtMessage::GetDeviceChain,
+            STATE_DEVICE_CHAIN => {
+                let start_index = reader.u8()?;
+                let mut tiles = Vec::with_capacity(CHAIN_SLOTS);
+                for _ in 0..CHAIN_SLOTS {
+                    tiles.push(TileDevice::read(&mut reader)?);
+                }
+                let tile_count = reader.u8()?;
+                tiles.truncate(tile_count as usize);
+                ExtMessage::StateDeviceChain { start_index, tiles, tile_count }
+            }
+            GET_64 => {
+                let (tile_index, length) = (reader.u8()?, reader.u8()?);
+                reader.skip(1)?;
+                ExtMessage::Get64 { tile_index, length, x: reader.u8()?, y: reader.u8()?, width: reader.u8()? }
+            }
+            STATE_64 => {
+                let tile_index = reader.u8()?;
+                reader.skip(1)?;
+                ExtMessage::State64 {
+                    tile_index,
+                    x: reader.u8()?,
+                    y: reader.u8()?,
+                    width: reader.u8()?,
+                    colors: reader.tile_colors()?,
+                }
+            }
+            SET_64 => {
+                let (tile_index, length) = (reader.u8()?, reader.u8()?);
+                reader.skip(1)?;
+                ExtMessage::Set64 {
+                    tile_index,
+                    length,
+                    x: reader.u8()?,
+                    y: reader.u8()?,
+                    width: reader.u8()?,
+                    duration_ms: reader.u32()?,
+                    colors: reader.tile_colors()?,
+                }
+            }
+            SET_MULTI_ZONE_EFFECT => {
+                let instance_id = reader.u32()?;
+                let effect = MultiZoneEffectType::from_u8(reader.u8()?)?;
+                reader.skip(2)?;
+                let speed_ms = reader.u32()?;
+                let duration_ns = reader.u64()?;
+                reader.skip(8)?;
+                let mut parameters = [0; 8];
+                for parameter in parameters.iter_mut() {
+                    *parameter = reader.u32()?;
+                }
+                ExtMessage::SetMultiZoneEffect { instance_id, effect, speed_ms, duration_ns, parameters }
+            }
+            SET_TILE_EFFECT => {
+                reader.skip(2)?;
+                let instance_id = reader.u32()?;
+                let effect = TileEffectType::from_u8(reader.u8()?)?;
+                let speed_ms = reader.u32()?;
+                let duration_ns = reader.u64()?;
+                reader.skip(8 + 32)?;
+                let count = (reader.u8()? as usize).min(TILE_EFFECT_PALETTE_SIZE);
+                let mut palette = Vec::with_capacity(count);
+                for _ in 0..count {
+                    palette.push(reader.hsbk()?);
+                }
+                reader.skip((TILE_EFFECT_PALETTE_SIZE - count) * 8)?;
+                ExtMessage::SetTileEffect { instance_id, effect, speed_ms, duration_ns, palette }
+            }
+            SET_ACCESS_POINT => ExtMessage::SetAccessPoint {
+                interface: reader.u8()?,
+                ssid: reader.string(SSID_SIZE)?,
+                passphrase: reader.string(PASSPHRASE_SIZE)?,
+                security: reader.u8()?,
+            },
+            _ => return None,
+        };
+        Some(message)
+    }
+}
+
+fn write_hsbk(payload: &mut Vec<u8>, color: &HSBK) {
+    payload.extend_from_slice(&color.hue.to_le_bytes());
+    payload.extend_from_slice(&color.saturation.to_le_bytes());
+    payload.extend_from_slice(&color.brightness.to_le_bytes());
+    payload.extend_from_slice(&color.kelvin.to_le_bytes());
+}
+
+/// Writes the count byte followed by all 82 color slots, zero padded.
+fn write_zone_colors(payload: &mut Vec<u8>, colors: &[HSBK]) {
+    let colors = &colors[..colors.len().min(EXTENDED_ZONES_PER_MESSAGE)];
+    payload.push(colors.len() as u8);
+    for color in colors {
+        write_hsbk(payload, color);
+    }
+    payload.resize(payload.len() + (EXTENDED_ZONES_PER_MESSAGE - colors.len()) * 8, 0);
+}
+
+/// Writes `value` into a NUL padded field of `size` bytes, truncating longer values.
+fn write_padded(payload: &mut Vec<u8>, value: &str, size: usize) {
+    let bytes = &value.as_bytes()[..value.len().min(size)];
+    payload.extend_from_slice(bytes);
+    payload.resize(payload.len() + size - bytes.len(), 0);
+}
+
+/// Writes exactly 64 color slots, zero padded.
+fn write_tile_colors(payload: &mut Vec<u8>, colors: &[HSBK]) {
+    let colors = &colors[..colors.len().min(TILE_PIXELS)];
+    for color in colors {
+        write_hsbk(payload, color);
+    }
+    payload.resize(payload.len() + (TILE_PIXELS - colors.len()) * 8, 0);
+}
+
+/// Little-endian cursor over a payload; every read returns `None` once the data runs out.
+struct Reader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn new(data: &'a [u8]) -> Self {
+        Reader { data, pos: 0 }
+    }
+
+    fn take(&mut self, len: usize) -> Option<&'a [u8]> {
+        let bytes = self.data.get(self.pos..self.pos + len)?;
+        self.pos += len;
+        Some(bytes)
+    }
+
+    fn skip(&mut self, len: usize) -> Option<()> {
+        self.take(len).map(|_| ())
+    }
+
+    fn u8(&mut self) -> Option<u8> {
+        self.take(1).map(|b| b[0])
+    }
+
+    fn bool(&mut self) -> Option<bool> {
+        self.u8().map(|b| b != 0)
+    }
+
+    fn u16(&mut self) -> Option<u16> {
+        self.take(2).map(|b| u16::from_le_bytes([b[0], b[1]]))
+    }
+
+    fn u32(&mut self) -> Option<u32> {
+        self.take(4).map(|b| u32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+    }
+
+    fn hsbk(&mut self) -> Option<HSBK> {
+        Some(HSBK {
+            hue: self.u16()?,
+            saturation: self.u16()?,
+            brightness: self.u16()?,
+            kelvin: self.u16()?,
+        })
+    }
+
+    /// Reads the count byte and the 82 color slots, keeping only the used ones.
+    fn zone_colors(&mut self) -> Option<Vec<HSBK>> {
+        let count = (self.u8()? as usize).min(EXTENDED_ZONES_PER_MESSAGE);
+        let mut colors = Vec::with_capacity(count);
+        for _ in 0..count {
+            colors.push(self.hsbk()?);
+        }
+        self.skip((EXTENDED_ZONES_PER_MESSAGE - count) * 8)?;
+        Some(colors)
+    }
+
+    fn tile_colors(&mut self) -> Option<Vec<HSBK>> {
+        (0..TILE_PIXELS).map(|_| self.hsbk()).collect()
+    }
+
+    /// Reads a NUL padded string field of `size` bytes.
+    fn string(&mut self, size: usize) -> Option<String> {
+        let bytes = self.take(size)?;
+        let end = bytes.iter().position(|&b| b == 0).unwrap_or(size);
+        Some(String::from_utf8_lossy(&bytes[..end]).into_owned())
+    }
+
+    fn i16(&mut self) -> Option<i16> {
+        self.u16().map(|v| v as i16)
+    }
+
+    fn f32(&mut self) -> Option<f32> {
+        self.u32().map(f32::from_bits)
+    }
+
+    fn u64(&mut self) -> Option<u64> {
+        self.take(8).map(|b| {
+            let mut bytes = [0; 8];
+            bytes.copy_from_slice(b);
+            u64::from_le_bytes(bytes)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_encode_header() {
+        let options = PacketOptions {
+            source: 0x72757374,
+            target: 0x0000_5634_12d5_73d0,
+            sequence: 7,
+            ack_required: true,
+            res_required: false,
+        };
+        let packet = ExtMessage::GetHevCycle.encode(&options);
+
+        assert_eq!(packet.len(), HEADER_SIZE);
+        assert_eq!(&packet[0..2], &[36, 0]);
+        // protocol 1024, addressable, not tagged
+        assert_eq!(u16::from_le_bytes([packet[2], packet[3]]), 0x1400);
+        assert_eq!(&packet[4..8], &0x72757374u32.to_le_bytes());
+        assert_eq!(&packet[8..14], &[0xd0, 0x73, 0xd5, 0x12, 0x34, 0x56]);
+        assert_eq!(packet[22], 0b10);
+        assert_eq!(packet[23], 7);
+        assert_eq!(u16::from_le_bytes([packet[32], packet[33]]), 142);
+    }
+
+    #[test]
+    fn test_broadcast_is_tagged() {
+        let packet = encode_packet(&PacketOptions::default(), 2, &[]);
+        assert_eq!(u16::from_le_bytes([packet[2], packet[3]]), 0x3400);
+    }
+
+    #[test]
+    fn test_packet_round_trip() {
+        let options = PacketOptions {
+            source: 42,
+            target: 0xd073d5,
+            sequence: 200,
+            ack_required: false,
+            res_required: true,
+        };
+        let message = ExtMessage::SetHevCycle { enable: true, duration_s: 7200 };
+        let packet = message.encode(&options);
+
+        let (header, payload) = decode_packet(&packet).unwrap();
+        assert_eq!(header.source, 42);
+        assert_eq!(header.target, 0xd073d5);
+        assert_eq!(header.sequence, 200);
+        assert!(header.res_required);
+        assert!(!header.ack_required);
+        assert!(!header.tagged);
+        assert_eq!(header.message_type, 143);
+        assert_eq!(payload, &[1, 0x20, 0x1c, 0, 0]);
+        assert_eq!(ExtMessage::decode(header.message_type, payload), Some(message));
+    }
+
+    #[test]
+    fn test_decode_hev_state() {
+        let message = ExtMessage::StateHevCycle { duration_s: 7200, remaining_s: 3600, last_power: false };
+        assert_eq!(ExtMessage::decode(144, &message.payload()), Some(message));
+
+        let result = ExtMessage::StateLastHevCycleResult { result: HevCycleResult::InterruptedByLan };
+        assert_eq!(result.payload(), vec![4]);
+        assert_eq!(ExtMessage::decode(149, &[255]), Some(ExtMessage::StateLastHevCycleResult {
+            result: HevCycleResult::None,
+        }));
+    }
+
+    #[test]
+    fn test_decode_rejects_truncated_and_unknown() {
+        assert_eq!(ExtMessage::decode(144, &[0, 0, 0]), None);
+        assert_eq!(ExtMessage::decode(102, &[]), None);
+        assert!(decode_packet(&[0; 10]).is_none());
+    }
+
+    #[test]
+    fn test_extended_color_zones_round_trip() {
+        let red = HSBK { hue: 0, saturation: 65535, brightness: 65535, kelvin: 3500 };
+        let blue = HSBK { hue: 43690, saturation: 65535, brightness: 32768, kelvin: 3500 };
+        let message = ExtMessage::SetExtendedColorZones {
+            duration_ms: 1000,
+            apply: true,
+            zone_index: 8,
+            colors: vec![red, blue],
+        };
+        let payload = message.payload();
+
+        assert_eq!(payload.len(), 4 + 1 + 2 + 1 + EXTENDED_ZONES_PER_MESSAGE * 8);
+        assert_eq!(payload[7], 2);
+        assert_eq!(ExtMessage::decode(510, &payload), Some(message));
+
+        let state = ExtMessage::StateExtendedColorZones { zones_count: 16, zone_index: 0, colors: vec![blue; 16] };
+        assert_eq!(ExtMessage::decode(512, &state.payload()), Some(state));
+        assert_eq!(ExtMessage::decode(512, &[16, 0, 0, 0, 1]), None);
+    }
+
+    #[test]
+    fn test_device_chain_round_trip() {
+        let tile = TileDevice {
+            accel_meas_x: -12,
+            accel_meas_y: 100,
+            accel_meas_z: 2000,
+            user_x: 1.5,
+            user_y: -0.5,
+            width: 8,
+            height: 8,
+            device_version_vendor: 1,
+            device_version_product: 55,
+            firmware_build: 1_548_977_726_000_000_000,
+            firmware_version_minor: 50,
+            firmware_version_major: 3,
+        };
+        let message = ExtMessage::StateDeviceChain { start_index: 0, tiles: vec![tile.clone(), tile], tile_count: 2 };
+        let payload = message.payload();
+
+        assert_eq!(payload.len(), 1 + CHAIN_SLOTS * 55 + 1);
+        assert_eq!(ExtMessage::decode(702, &payload), Some(message));
+    }
+
+    #[test]
+    fn test_tile_pixels_round_trip() {
+        let red = HSBK { hue: 0, saturation: 65535, brightness: 65535, kelvin: 3500 };
+        let set = ExtMessage::Set64 { tile_index: 2, length: 1, x: 0, y: 0, width: 8, duration_ms: 500, colors: vec![red; 3] };
+        let payload = set.payload();
+
+        assert_eq!(payload.len(), 6 + 4 + TILE_PIXELS * 8);
+        assert_eq!(&payload[0..6], &[2, 1, 0, 0, 0, 8]);
+        match ExtMessage::decode(715, &payload) {
+            Some(ExtMessage::Set64 { colors, duration_ms, .. }) => {
+                assert_eq!(duration_ms, 500);
+                assert_eq!(colors.len(), TILE_PIXELS);
+                assert_eq!(colors[2], red);
+                assert_eq!(colors[3].brightness, 0);
+            }
+            other => panic!("Unexpected decode result {:?}", other),
+        }
+
+        let get = ExtMessage::Get64 { tile_index: 0, length: 5, x: 0, y: 0, width: 8 };
+        assert_eq!(ExtMessage::decode(707, &get.payload()), Some(get));
+    }
+
+    #[test]
+    fn test_multizone_effect_payload() {
+        let mut parameters = [0; 8];
+        parameters[1] = 1;
+        let message = ExtMessage::SetMultiZoneEffect {
+            instance_id: 7,
+            effect: MultiZoneEffectType::Move,
+            speed_ms: 1000,
+            duration_ns: 0,
+            parameters,
+        };
+        let payload = message.payload();
+
+        assert_eq!(payload.len(), 59);
+        assert_eq!(payload[4], 1);
+        assert_eq!(&payload[7..11], &1000u32.to_le_bytes());
+        assert_eq!(&payload[31..35], &1u32.to_le_bytes());
+        assert_eq!(ExtMessage::decode(508, &payload), Some(message));
+    }
+
+    #[test]
+    fn test_tile_effect_payload() {
+        let red = HSBK { hue: 0, saturation: 65535, brightness: 65535, kelvin: 3500 };
+        let message = ExtMessage::SetTileEffect {
+            instance_id: 9,
+            effect: TileEffectType::Morph,
+            speed_ms: 5000,
+            duration_ns: 10_000_000_000,
+            palette: vec![red; 3],
+        };
+        let payload = message.payload();
+
+        assert_eq!(payload.len(), 188);
+        assert_eq!(payload[6], 2);
+        assert_eq!(payload[59], 3);
+        assert_eq!(ExtMessage::decode(719, &payload), Some(message));
+        assert_eq!(ExtMessage::decode(719, &[0; 10]), None);
+    }
+
+    #[test]
+    fn test_set_access_point_payload() {
+        let message = ExtMessage::SetAccessPoint {
+            interface: 2,
+            ssid: "IoT".to_string(),
+            passphrase: "correct horse".to_string(),
+            security: 5,
+        };
+        let payload = message.payload();
+
+        assert_eq!(payload.len(), 98);
+        assert_eq!(payload[0], 2);
+        assert_eq!(&payload[1..5], b"IoT\0");
+        assert_eq!(&payload[33..46], b"correct horse");
+        assert_eq!(payload[97], 5);
+        assert!(!message.is_query());
+        assert_eq!(ExtMessage::decode(305, &payload), Some(message));
+        assert_eq!(ExtMessage::decode(305, &payload[..97]), None);
+    }
+}
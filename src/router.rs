@@ -0,0 +1,344 @@
+use std::collections::HashMap;
+
+/// Every HTTP endpoint served by the API.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Endpoint {
+    ListScenes,
+    CreateScene,
+    CaptureScene,
+    ActivateScene,
+    DeleteScene,
+    SetStates,
+    ListLights,
+    SetState,
+    StateDelta,
+    Toggle,
+    SetLabel,
+    ListGroups,
+    RenameGroup,
+    CreateGroup,
+    JoinGroup,
+    ListLocations,
+    RenameLocation,
+    CreateLocation,
+    JoinLocation,
+    SetZones,
+    GetTiles,
+    SetTiles,
+    EffectsPulse,
+    EffectsBreathe,
+    EffectsStrobe,
+    EffectsMove,
+    EffectsMorph,
+    EffectsFlame,
+    EffectsOff,
+    Cycle,
+    Clean,
+    CleanStatus,
+    Info,
+    Onboard,
+    Color,
+}
+
+#[derive(Debug, Clone)]
+struct Route {
+    method: &'static str,
+    segments: Vec<Segment>,
+    endpoint: Endpoint,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Segment {
+    Literal(&'static str),
+    Param(&'static str),
+}
+
+/// Path parameters captured while matching a route, e.g. `selector` for `/v1/lights/:selector`.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct RouteParams {
+    params: HashMap<&'static str, String>,
+}
+
+impl RouteParams {
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.params.get(name).map(|s| s.as_str())
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum RouteMatch {
+    Found(Endpoint, RouteParams),
+    NotFound,
+    /// The path exists but not for this method; holds the methods that are allowed.
+    MethodNotAllowed(Vec<&'static str>),
+}
+
+/// Route table matching a method and a path pattern such as `/v1/lights/:selector/state`.
+///
+/// When several patterns match a path, only the ones with the most literal segments are
+/// considered, so `/v1/lights/states` never falls through to `/v1/lights/:selector`.
+#[derive(Debug, Clone, Default)]
+pub struct Router {
+    routes: Vec<Route>,
+}
+
+impl Router {
+    pub fn new() -> Self {
+        Router { routes: Vec::new() }
+    }
+
+    /// The route table for every endpoint of the LIFX HTTP API.
+    pub fn api() -> Self {
+        let mut router = Router::new();
+        router
+            .add("GET", "/v1/scenes", Endpoint::ListScenes)
+            .add("POST", "/v1/scenes", Endpoint::CreateScene)
+            .add("POST", "/v1/scenes/capture", Endpoint::CaptureScene)
+            .add("PUT", "/v1/scenes/:uuid/activate", Endpoint::ActivateScene)
+            .add("DELETE", "/v1/scenes/:uuid", Endpoint::DeleteScene)
+            .add("PUT", "/v1/lights/states", Endpoint::SetStates)
+            .add("GET", "/v1/lights/:selector", Endpoint::ListLights)
+            .add("PUT", "/v1/lights/:selector/state", Endpoint::SetState)
+            .add("POST", "/v1/lights/:selector/state/delta", Endpoint::StateDelta)
+            .add("POST", "/v1/lights/:selector/toggle", Endpoint::Toggle)
+            .add("PUT", "/v1/lights/:selector/label", Endpoint::SetLabel)
+            .add("GET", "/v1/groups", Endpoint::ListGroups)
+            .add("PUT", "/v1/groups/:id", Endpoint::RenameGroup)
+            .add("POST", "/v1/lights/:selector/group", Endpoint::CreateGroup)
+            .add("PUT", "/v1/lights/:selector/group", Endpoint::JoinGroup)
+            .add("GET", "/v1/locations", Endpoint::ListLocations)
+            .add("PUT", "/v1/locations/:id", Endpoint::RenameLocation)
+            .add("POST", "/v1/lights/:selector/location", Endpoint::CreateLocation)
+            .add("PUT", "/v1/lights/:selector/location", Endpoint::JoinLocation)
+            .add("PUT", "/v1/lights/:selector/zones", Endpoint::SetZones)
+            .add("GET", "/v1/lights/:selector/tiles", Endpoint::GetTiles)
+            .add("PUT", "/v1/lights/:selector/tiles", Endpoint::SetTiles)
+            .add("POST", "/v1/lights/:selector/effects/pulse", Endpoint::EffectsPulse)
+            .add("POST", "/v1/lights/:selector/effects/breathe", Endpoint::EffectsBreathe)
+            .add("POST", "/v1/lights/:selector/effects/strobe", Endpoint::EffectsStrobe)
+            .add("POST", "/v1/lights/:selector/effects/move", Endpoint::EffectsMove)
+            .add("POST", "/v1/lights/:selector/effects/morph", Endpoint::EffectsMorph)
+            .add("POST", "/v1/lights/:selector/effects/flame", Endpoint::EffectsFlame)
+            .add("POST", "/v1/lights/:selector/effects/off", Endpoint::EffectsOff)
+            .add("POST", "/v1/lights/:selector/cycle", Endpoint::Cycle)
+            .add("POST", "/v1/lights/:selector/clean", Endpoint::Clean)
+            .add("GET", "/v1/lights/:selector/clean", Endpoint::CleanStatus)
+            .add("GET", "/v1/lights/:selector/info", Endpoint::Info)
+            .add("POST", "/v1/onboarding", Endpoint::Onboard)
+            .add("GET", "/v1/color", Endpoint::Color);
+        router
+    }
+
+    pub fn add(&mut self, method: &'static str, pattern: &'static str, endpoint: Endpoint) -> &mut Self {
+        let segments = split_path(pattern)
+            .map(|segment| match segment.strip_prefix(':') {
+                Some(name) => Segment::Param(name),
+                None => Segment::Literal(segment),
+            })
+            .collect();
+
+        self.routes.push(Route { method, segments, endpoint });
+        self
+    }
+
+    pub fn resolve(&self, method: &str, path: &str) -> RouteMatch {
+        let parts: Vec<&str> = split_path(path).collect();
+
+        let matching: Vec<(&Route, RouteParams)> = self.routes
+            .iter()
+            .filter_map(|route| match_segments(&route.segments, &parts).map(|params| (route, params)))
+            .collect();
+
+        let best = match matching.iter().map(|(route, _)| literal_count(route)).max() {
+            Some(best) => best,
+            None => return RouteMatch::NotFound,
+        };
+
+        let mut allowed = Vec::new();
+        for (route, params) in matching {
+            if literal_count(route) != best {
+                continue;
+            }
+            if route.method.eq_ignore_ascii_case(method) {
+                return RouteMatch::Found(route.endpoint, params);
+            }
+            if !allowed.contains(&route.method) {
+                allowed.push(route.method);
+            }
+        }
+
+        RouteMatch::MethodNotAllowed(allowed)
+    }
+}
+
+fn split_path(path: &str) -> impl Iterator<Item = &str> {
+    path.split('/').filter(|segment| !segment.is_empty())
+}
+
+fn literal_count(route: &Route) -> usize {
+    route.segments.iter().filter(|s| matches!(s, Segment::Literal(_))).count()
+}
+
+fn match_segments(segments: &[Segment], parts: &[&str]) -> Option<RouteParams> {
+    if segments.len() != parts.len() {
+        return None;
+    }
+
+    let mut params = RouteParams::default();
+    for (segment, part) in segments.iter().zip(parts) {
+        match segment {
+            Segment::Literal(literal) if literal == part => {}
+            Segment::Literal(_) => return None,
+            Segment::Param(name) => {
+                params.params.insert(*name, part.to_string());
+            }
+        }
+    }
+
+    Some(params)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn found(router: &Router, method: &str, path: &str) -> (Endpoint, RouteParams) {
+        match router.resolve(method, path) {
+            RouteMatch::Found(endpoint, params) => (endpoint, params),
+            other => panic!("Expected {} {} to match, got {:?}", method, path, other),
+        }
+    }
+
+    #[test]
+    fn test_selector_routes() {
+        let router = Router::api();
+
+        let (endpoint, params) = found(&router, "GET", "/v1/lights/all");
+        assert_eq!(endpoint, Endpoint::ListLights);
+        assert_eq!(params.get("selector"), Some("all"));
+
+        let (endpoint, params) = found(&router, "PUT", "/v1/lights/label:clean room/state");
+        assert_eq!(endpoint, Endpoint::SetState);
+        assert_eq!(params.get("selector"), Some("label:clean room"));
+
+        let (endpoint, params) = found(&router, "PUT", "/v1/lights/label:Strip|0-7/zones");
+        assert_eq!(endpoint, Endpoint::SetZones);
+        assert_eq!(params.get("selector"), Some("label:Strip|0-7"));
+
+        let (endpoint, _) = found(&router, "GET", "/v1/lights/all/tiles");
+        assert_eq!(endpoint, Endpoint::GetTiles);
+
+        let (endpoint, params) = found(&router, "POST", "/v1/lights/label:Desk/state/delta");
+        assert_eq!(endpoint, Endpoint::StateDelta);
+        assert_eq!(params.get("selector"), Some("label:Desk"));
+
+        let (endpoint, params) = found(&router, "POST", "/v1/lights/group:Kitchen/toggle");
+        assert_eq!(endpoint, Endpoint::Toggle);
+        assert_eq!(params.get("selector"), Some("group:Kitchen"));
+
+        let (endpoint, params) = found(&router, "PUT", "/v1/lights/id:d073d5000001/label");
+        assert_eq!(endpoint, Endpoint::SetLabel);
+        assert_eq!(params.get("selector"), Some("id:d073d5000001"));
+
+        let (endpoint, params) = found(&router, "PUT", "/v1/groups/4b4b4b4b4b4b4b4b4b4b4b4b4b4b4b4b");
+        assert_eq!(endpoint, Endpoint::RenameGroup);
+        assert_eq!(params.get("id"), Some("4b4b4b4b4b4b4b4b4b4b4b4b4b4b4b4b"));
+
+        let (endpoint, _) = found(&router, "POST", "/v1/lights/label:Desk/location");
+        assert_eq!(endpoint, Endpoint::CreateLocation);
+        let (endpoint, _) = found(&router, "PUT", "/v1/lights/label:Desk/location");
+        assert_eq!(endpoint, Endpoint::JoinLocation);
+        let (endpoint, _) = found(&router, "GET", "/v1/groups");
+        assert_eq!(endpoint, Endpoint::ListGroups);
+
+        let (endpoint, params) = found(&router, "GET", "/v1/lights/id:d073d5000001/info");
+        assert_eq!(endpoint, Endpoint::Info);
+        assert_eq!(params.get("selector"), Some("id:d073d5000001"));
+
+        let (endpoint, _) = found(&router, "POST", "/v1/onboarding");
+        assert_eq!(endpoint, Endpoint::Onboard);
+
+        let (endpoint, _) = found(&router, "POST", "/v1/lights/group:Kitchen/cycle");
+        assert_eq!(endpoint, Endpoint::Cycle);
+
+        let (endpoint, _) = found(&router, "POST", "/v1/lights/all/effects/breathe");
+        assert_eq!(endpoint, Endpoint::EffectsBreathe);
+
+        let (endpoint, _) = found(&router, "POST", "/v1/lights/all/effects/off");
+        assert_eq!(endpoint, Endpoint::EffectsOff);
+    }
+
+    #[test]
+    fn test_selector_containing_keywords_is_not_misrouted() {
+        let router = Router::api();
+
+        // Used to be routed to the clean / state handlers by substring matching
+        let (endpoint, params) = found(&router, "GET", "/v1/lights/label:clean");
+        assert_eq!(endpoint, Endpoint::ListLights);
+        assert_eq!(params.get("selector"), Some("label:clean"));
+
+        let (endpoint, _) = found(&router, "GET", "/v1/lights/label:statement");
+        assert_eq!(endpoint, Endpoint::ListLights);
+
+        let (endpoint, _) = found(&router, "POST", "/v1/lights/label:state/clean");
+        assert_eq!(endpoint, Endpoint::Clean);
+
+        let (endpoint, _) = found(&router, "GET", "/v1/lights/label:state/clean");
+        assert_eq!(endpoint, Endpoint::CleanStatus);
+    }
+
+    #[test]
+    fn test_literal_routes_take_precedence() {
+        let router = Router::api();
+
+        let (endpoint, _) = found(&router, "PUT", "/v1/lights/states");
+        assert_eq!(endpoint, Endpoint::SetStates);
+
+        let (endpoint, _) = found(&router, "POST", "/v1/scenes/capture");
+        assert_eq!(endpoint, Endpoint::CaptureScene);
+
+        let (endpoint, _) = found(&router, "GET", "/v1/color");
+        assert_eq!(endpoint, Endpoint::Color);
+
+        let (endpoint, params) = found(&router, "DELETE", "/v1/scenes/abc-123");
+        assert_eq!(endpoint, Endpoint::DeleteScene);
+        assert_eq!(params.get("uuid"), Some("abc-123"));
+
+        assert_eq!(
+            router.resolve("GET", "/v1/lights/states"),
+            RouteMatch::MethodNotAllowed(vec!["PUT"])
+        );
+    }
+
+    #[test]
+    fn test_not_found() {
+        let router = Router::api();
+
+        assert_eq!(router.resolve("GET", "/"), RouteMatch::NotFound);
+        assert_eq!(router.resolve("GET", "/v2/lights/all"), RouteMatch::NotFound);
+        assert_eq!(router.resolve("PUT", "/v1/lights/all/unknown"), RouteMatch::NotFound);
+        assert_eq!(router.resolve("PUT", "/v1/lights/all/state/extra"), RouteMatch::NotFound);
+    }
+
+    #[test]
+    fn test_method_not_allowed_lists_methods() {
+        let router = Router::api();
+
+        assert_eq!(
+            router.resolve("POST", "/v1/lights/all/state"),
+            RouteMatch::MethodNotAllowed(vec!["PUT"])
+        );
+        assert_eq!(
+            router.resolve("DELETE", "/v1/scenes"),
+            RouteMatch::MethodNotAllowed(vec!["GET", "POST"])
+        );
+    }
+
+    #[test]
+    fn test_trailing_slash_and_method_case() {
+        let router = Router::api();
+
+        let (endpoint, _) = found(&router, "get", "/v1/lights/all/");
+        assert_eq!(endpoint, Endpoint::ListLights);
+    }
+}
@@ -1,10 +1,14 @@
 use std::collections::HashMap;
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
 use std::sync::{Arc, Mutex};
-use std::time::{SystemTime, UNIX_EPOCH};
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
 use serde::{Deserialize, Serialize};
 use lifx_rs::lan::HSBK;
-use crate::{BulbInfo, Manager, LifxColor};
+use crate::{delivery, BulbInfo, Manager, LifxColor};
 use crate::error::{LifxError, Result};
+use crate::selector::{Selector, ZoneRange};
 use crate::mutex_utils::{safe_lock, safe_lock_monitored};
 use log::error;
 
@@ -70,15 +74,109 @@ pub struct ActivateResult {
 
 pub struct ScenesHandler {
     scenes: Arc<Mutex<HashMap<String, Scene>>>,
+    storage_path: Option<PathBuf>,
 }
 
 impl ScenesHandler {
     pub fn new() -> Self {
         ScenesHandler {
             scenes: Arc::new(Mutex::new(HashMap::new())),
+            storage_path: None,
         }
     }
 
+    /// Creates a handler whose scenes are loaded from and saved to a JSON file.
+    ///
+    /// A missing file is treated as an empty scene list. A file that exists but
+    /// cannot be read or parsed is reported as a `LifxError::ConfigError` and is
+    /// left untouched, see `recover_storage` for starting over without losing it.
+    pub fn with_storage<P: Into<PathBuf>>(path: P) -> Result<Self> {
+        let path = path.into();
+        let scenes = Self::load_scenes(&path)?;
+
+        Ok(ScenesHandler {
+            scenes: Arc::new(Mutex::new(scenes)),
+            storage_path: Some(path),
+        })
+    }
+
+    /// Moves an unreadable scene file aside and returns an empty handler backed by `path`.
+    ///
+    /// The original file is renamed to `<path>.corrupt-<unix timestamp>` so it can be
+    /// inspected or repaired by hand. Returns the handler and the backup location.
+    pub fn recover_storage<P: Into<PathBuf>>(path: P) -> Result<(Self, PathBuf)> {
+        let path = path.into();
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_err(|e| LifxError::ConfigError(format!("Time error: {}", e)))?
+            .as_secs();
+        let backup = PathBuf::from(format!("{}.corrupt-{}", path.display(), now));
+
+        fs::rename(&path, &backup).map_err(|e| LifxError::ConfigError(format!(
+            "Failed to move scene file {} to {}: {}", path.display(), backup.display(), e
+        )))?;
+
+        let handler = ScenesHandler {
+            scenes: Arc::new(Mutex::new(HashMap::new())),
+            storage_path: Some(path),
+        };
+        handler.persist(&HashMap::new())?;
+
+        Ok((handler, backup))
+    }
+
+    pub fn storage_path(&self) -> Option<&Path> {
+        self.storage_path.as_deref()
+    }
+
+    fn load_scenes(path: &Path) -> Result<HashMap<String, Scene>> {
+        if !path.exists() {
+            return Ok(HashMap::new());
+        }
+
+        let contents = fs::read_to_string(path).map_err(|e| LifxError::ConfigError(format!(
+            "Failed to read scene file {}: {}", path.display(), e
+        )))?;
+
+        if contents.trim().is_empty() {
+            return Ok(HashMap::new());
+        }
+
+        let scenes: Vec<Scene> = serde_json::from_str(&contents).map_err(|e| LifxError::ConfigError(format!(
+            "Scene file {} is corrupt: {}", path.display(), e
+        )))?;
+
+        Ok(scenes.into_iter().map(|scene| (scene.uuid.clone(), scene)).collect())
+    }
+
+    /// Writes all scenes to the storage file, if one is configured.
+    ///
+    /// The data is written to a temporary file in the same directory and then renamed
+    /// over the real one, so a crash mid-write never leaves a truncated scene file.
+    fn persist(&self, scenes: &HashMap<String, Scene>) -> Result<()> {
+        let path = match self.storage_path {
+            Some(ref path) => path,
+            None => return Ok(()),
+        };
+
+        let mut scenes_list: Vec<&Scene> = scenes.values().collect();
+        scenes_list.sort_by(|a, b| a.created_at.cmp(&b.created_at).then_with(|| a.uuid.cmp(&b.uuid)));
+        let json = serde_json::to_string_pretty(&scenes_list)?;
+
+        let tmp_path = PathBuf::from(format!("{}.tmp", path.display()));
+        let write_result = (|| -> std::io::Result<()> {
+            let mut file = fs::File::create(&tmp_path)?;
+            file.write_all(json.as_bytes())?;
+            file.sync_all()?;
+            fs::rename(&tmp_path, path)
+        })();
+
+        write_result.map_err(|e| {
+            let _ = fs::remove_file(&tmp_path);
+            LifxError::ConfigError(format!("Failed to write scene file {}: {}", path.display(), e))
+        })
+    }
+
     pub fn create_scene(&self, request: CreateSceneRequest) -> Result<SceneResponse> {
         let uuid = self.generate_uuid();
         let now = SystemTime::now()
@@ -94,14 +192,20 @@ impl ScenesHandler {
             updated_at: now,
         };
         
-        let mut scenes = self.scenes.lock()?
-        scenes.insert(uuid, scene.clone());
+        let mut scenes = self.scenes.lock()?;
+        scenes.insert(uuid.clone(), scene.clone());
+        
+        if let Err(e) = self.persist(&scenes) {
+            error!("Failed to save scene {}: {}", uuid, e);
+            scenes.remove(&uuid);
+            return Err(e);
+        }
         
         Ok(SceneResponse { scene })
     }
 
     pub fn list_scenes(&self) -> Result<ScenesListResponse> {
-        let scenes = self.scenes.lock()?
+        let scenes = self.scenes.lock()?;
         let scenes_list: Vec<Scene> = scenes.values().cloned().collect();
         
         Ok(ScenesListResponse { scenes: scenes_list })
@@ -114,9 +218,22 @@ impl ScenesHandler {
 
     pub fn delete_scene(&self, uuid: &str) -> Result<bool> {
         let mut scenes = self.scenes.lock()?;
-        Ok(scenes.remove(uuid).is_some())
+        let removed = match scenes.remove(uuid) {
+            Some(scene) => scene,
+            None => return Ok(false),
+        };
+        
+        if let Err(e) = self.persist(&scenes) {
+            error!("Failed to delete scene {}: {}", uuid, e);
+            scenes.insert(removed.uuid.clone(), removed);
+            return Err(e);
+        }
+        
+        Ok(true)
     }
 
+    /// Applies every state of the scene, then waits for acks through `delivery::confirm` unless
+    /// the request is `fast`, so the bulbs lock must not be held.
     pub fn activate_scene(
         &self,
         mgr: &Manager,
@@ -127,29 +244,45 @@ impl ScenesHandler {
             .ok_or_else(|| LifxError::SceneNotFound(uuid.to_string()))?;
         
         let duration = (request.duration.unwrap_or(1.0) * 1000.0) as u32;
-        let mut results = Vec::new();
-        
-        let bulbs = mgr.bulbs.lock()?
         
-        for state in &scene.states {
-            let matching_bulbs = self.filter_bulbs_by_selector(&bulbs, &state.selector);
-            
-            for bulb in matching_bulbs {
-                let result = self.apply_scene_state(mgr, bulb, state, duration);
+        // Work on a snapshot, so the bulbs lock is released before waiting for acks
+        let mut selected: Vec<(&SceneState, BulbInfo, Option<Vec<ZoneRange>>)> = Vec::new();
+        {
+            let bulbs = mgr.bulbs.lock()?;
+            for state in &scene.states {
+                let selector = match Selector::parse(&state.selector) {
+                    Ok(selector) => selector,
+                    Err(e) => {
+                        error!("Skipping scene state with invalid selector: {}", e);
+                        continue;
+                    }
+                };
                 
-                results.push(ActivateResult {
-                    id: bulb.id.clone(),
-                    label: bulb.label.clone(),
-                    status: if result.is_ok() { "ok".to_string() } else { "error".to_string() },
-                });
+                for bulb in selector.select(&bulbs, Some(self)) {
+                    let zones = selector.zones_for(bulb).map(|zones| zones.to_vec());
+                    selected.push((state, bulb.clone(), zones));
+                }
             }
         }
         
+        let sent_at = Instant::now();
+        let sent: Vec<_> = selected.iter()
+            .map(|(state, bulb, zones)| self.apply_scene_state(mgr, bulb, state, zones.as_deref(), duration))
+            .collect();
+        let bulbs: Vec<&BulbInfo> = selected.iter().map(|(_, bulb, _)| bulb).collect();
+        let statuses = delivery::confirm_unless_fast(&mgr.sock, &bulbs, &sent, sent_at, request.fast.unwrap_or(false));
+        
+        let results = bulbs.iter().zip(statuses).map(|(bulb, status)| ActivateResult {
+            id: bulb.id.clone(),
+            label: bulb.label.clone(),
+            status: status.map_or("error", |s| s.as_str()).to_string(),
+        }).collect();
+        
         Ok(ActivateSceneResponse { results })
     }
 
     pub fn capture_current_state(&self, mgr: &Manager, name: String) -> Result<SceneResponse> {
-        let bulbs = mgr.bulbs.lock()?
+        let bulbs = mgr.bulbs.lock()?;
         let mut states = Vec::new();
         
         for bulb in bulbs.values() {
@@ -177,6 +310,7 @@ impl ScenesHandler {
         mgr: &Manager,
         bulb: &BulbInfo,
         state: &SceneState,
+        zones: Option<&[ZoneRange]>,
         duration: u32,
     ) -> Result<()> {
         if let Some(ref power) = state.power {
@@ -198,7 +332,7 @@ impl ScenesHandler {
                 kelvin: color.kelvin,
             };
             
-            bulb.set_color(&mgr.sock, hsbk, duration)
+            bulb.set_color_in_zones(&mgr.sock, hsbk, duration, zones)
                 .map_err(|e| LifxError::FailureError(format!("Failed to set color: {:?}", e)))?;
         } else if state.brightness.is_some() || state.kelvin.is_some() {
             let current = bulb.lifx_color.as_ref();
@@ -214,58 +348,13 @@ impl ScenesHandler {
                     .unwrap_or(3500),
             };
             
-            bulb.set_color(&mgr.sock, hsbk, duration)
+            bulb.set_color_in_zones(&mgr.sock, hsbk, duration, zones)
                 .map_err(|e| LifxError::FailureError(format!("Failed to set color: {:?}", e)))?;
         }
         
         Ok(())
     }
 
-    fn filter_bulbs_by_selector<'a>(
-        &self,
-        bulbs: &'a HashMap<u64, BulbInfo>,
-        selector: &str,
-    ) -> Vec<&'a BulbInfo> {
-        let mut filtered = Vec::new();
-        
-        for bulb in bulbs.values() {
-            let matches = match selector {
-                "all" => true,
-                s if s.starts_with("id:") => {
-                    let id = s.strip_prefix("id:").unwrap_or("");
-                    bulb.id.contains(id)
-                },
-                s if s.starts_with("group_id:") => {
-                    let group_id = s.strip_prefix("group_id:").unwrap_or("");
-                    bulb.lifx_group.as_ref().map_or(false, |g| g.id.contains(group_id))
-                },
-                s if s.starts_with("group:") => {
-                    let group_name = s.strip_prefix("group:").unwrap_or("");
-                    bulb.lifx_group.as_ref().map_or(false, |g| g.name.contains(group_name))
-                },
-                s if s.starts_with("location_id:") => {
-                    let location_id = s.strip_prefix("location_id:").unwrap_or("");
-                    bulb.lifx_location.as_ref().map_or(false, |l| l.id.contains(location_id))
-                },
-                s if s.starts_with("location:") => {
-                    let location_name = s.strip_prefix("location:").unwrap_or("");
-                    bulb.lifx_location.as_ref().map_or(false, |l| l.name.contains(location_name))
-                },
-                s if s.starts_with("label:") => {
-                    let label = s.strip_prefix("label:").unwrap_or("");
-                    bulb.label.contains(label)
-                },
-                _ => false,
-            };
-            
-            if matches {
-                filtered.push(bulb);
-            }
-        }
-        
-        filtered
-    }
-
     fn generate_uuid(&self) -> String {
         use rand::{thread_rng, Rng};
         use rand::distributions::Alphanumeric;
@@ -399,4 +488,81 @@ mod tests {
         assert_eq!(state.power.as_ref().unwrap(), "on");
         assert_eq!(state.brightness.as_ref().unwrap(), &1.0);
     }
+    
+    fn temp_scene_path(name: &str) -> PathBuf {
+        use rand::{thread_rng, Rng};
+        let suffix: u64 = thread_rng().gen();
+        std::env::temp_dir().join(format!("lifx-scenes-{}-{:x}.json", name, suffix))
+    }
+    
+    #[test]
+    fn test_scenes_persist_across_handlers() {
+        let path = temp_scene_path("persist");
+        
+        let handler = ScenesHandler::with_storage(&path).unwrap();
+        let created = handler.create_scene(CreateSceneRequest {
+            name: "Evening".to_string(),
+            states: vec![],
+        }).unwrap();
+        let deleted = handler.create_scene(CreateSceneRequest {
+            name: "Morning".to_string(),
+            states: vec![],
+        }).unwrap();
+        assert!(handler.delete_scene(&deleted.scene.uuid).unwrap());
+        
+        // A fresh handler (i.e. a restarted server) sees the same scenes
+        let reloaded = ScenesHandler::with_storage(&path).unwrap();
+        let scenes = reloaded.list_scenes().unwrap().scenes;
+        assert_eq!(scenes.len(), 1);
+        assert_eq!(scenes[0].uuid, created.scene.uuid);
+        assert_eq!(scenes[0].name, "Evening");
+        assert!(!PathBuf::from(format!("{}.tmp", path.display())).exists());
+        
+        let _ = fs::remove_file(&path);
+    }
+    
+    #[test]
+    fn test_missing_scene_file_starts_empty() {
+        let path = temp_scene_path("missing");
+        
+        let handler = ScenesHandler::with_storage(&path).unwrap();
+        assert!(handler.list_scenes().unwrap().scenes.is_empty());
+        assert_eq!(handler.storage_path(), Some(path.as_path()));
+    }
+    
+    #[test]
+    fn test_corrupt_scene_file_reports_config_error() {
+        let path = temp_scene_path("corrupt");
+        fs::write(&path, "{ not valid json").unwrap();
+        
+        match ScenesHandler::with_storage(&path) {
+            Err(LifxError::ConfigError(msg)) => assert!(msg.contains("corrupt")),
+            Err(e) => panic!("Expected ConfigError, got {:?}", e),
+            Ok(_) => panic!("Corrupt scene file should not load"),
+        }
+        
+        // The corrupt file must not have been overwritten
+        assert_eq!(fs::read_to_string(&path).unwrap(), "{ not valid json");
+        
+        let _ = fs::remove_file(&path);
+    }
+    
+    #[test]
+    fn test_recover_storage_keeps_corrupt_file() {
+        let path = temp_scene_path("recover");
+        fs::write(&path, "garbage").unwrap();
+        
+        let (handler, backup) = ScenesHandler::recover_storage(&path).unwrap();
+        assert_eq!(fs::read_to_string(&backup).unwrap(), "garbage");
+        assert!(handler.list_scenes().unwrap().scenes.is_empty());
+        
+        handler.create_scene(CreateSceneRequest {
+            name: "Recovered".to_string(),
+            states: vec![],
+        }).unwrap();
+        assert_eq!(ScenesHandler::with_storage(&path).unwrap().list_scenes().unwrap().scenes.len(), 1);
+        
+        let _ = fs::remove_file(&path);
+        let _ = fs::remove_file(&backup);
+    }
 }
\ No newline at end of file
@@ -0,0 +1,395 @@
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use rand::seq::SliceRandom;
+use rand::thread_rng;
+use crate::BulbInfo;
+use crate::error::{LifxError, Result};
+use crate::scenes::ScenesHandler;
+
+/// What a single selector term matches on.
+#[derive(Debug, Clone, PartialEq)]
+pub enum SelectorKind {
+    All,
+    Id(String),
+    Label(String),
+    GroupId(String),
+    Group(String),
+    LocationId(String),
+    Location(String),
+    SceneId(String),
+}
+
+/// An inclusive range of zones on a multizone device, e.g. `0-5` in `id:d073d5|0-5`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ZoneRange {
+    pub start: u16,
+    pub end: u16,
+}
+
+impl ZoneRange {
+    pub fn contains(&self, zone: u16) -> bool {
+        zone >= self.start && zone <= self.end
+    }
+}
+
+/// One comma-separated part of a selector, e.g. `group:Kitchen:random`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SelectorTerm {
+    pub kind: SelectorKind,
+    pub zones: Vec<ZoneRange>,
+    pub random: bool,
+}
+
+/// A parsed LIFX cloud API selector.
+///
+/// Supports `all`, `id:`, `label:`, `group_id:`, `group:`, `location_id:`, `location:` and
+/// `scene_id:`, comma-separated lists of those, a `:random` suffix picking one matching light,
+/// and `|start-end` zone ranges. Ids are matched exactly and names case-insensitively.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Selector {
+    terms: Vec<SelectorTerm>,
+}
+
+impl Selector {
+    pub fn parse(selector: &str) -> Result<Selector> {
+        let selector = selector.trim();
+        if selector.is_empty() {
+            return Err(LifxError::ValidationError("Selector cannot be empty".to_string()));
+        }
+
+        let terms = selector
+            .split(',')
+            .map(|term| Self::parse_term(term.trim()))
+            .collect::<Result<Vec<_>>>()?;
+
+        Ok(Selector { terms })
+    }
+
+    fn parse_term(term: &str) -> Result<SelectorTerm> {
+        let invalid = |reason: &str| LifxError::ValidationError(format!("Invalid selector '{}': {}", term, reason));
+
+        let mut parts = term.split('|');
+        let base = parts.next().unwrap_or("");
+        let zones = parts
+            .map(|zones| Self::parse_zone_range(zones).ok_or_else(|| invalid("bad zone range")))
+            .collect::<Result<Vec<_>>>()?;
+
+        let (base, random) = match base.strip_suffix(":random") {
+            Some(base) => (base, true),
+            None if base == "random" => ("all", true),
+            None => (base, false),
+        };
+
+        let kind = if base == "all" {
+            SelectorKind::All
+        } else {
+            let (prefix, value) = base.split_once(':').ok_or_else(|| invalid("expected 'type:value'"))?;
+            if value.is_empty() {
+                return Err(invalid("value cannot be empty"));
+            }
+            let value = value.to_string();
+            match prefix {
+                "id" => SelectorKind::Id(value),
+                "label" => SelectorKind::Label(value),
+                "group_id" => SelectorKind::GroupId(value),
+                "group" => SelectorKind::Group(value),
+                "location_id" => SelectorKind::LocationId(value),
+                "location" => SelectorKind::Location(value),
+                "scene_id" => SelectorKind::SceneId(value),
+                _ => return Err(invalid("unknown selector type")),
+            }
+        };
+
+        Ok(SelectorTerm { kind, zones, random })
+    }
+
+    fn parse_zone_range(range: &str) -> Option<ZoneRange> {
+        let range = range.trim();
+        let (start, end) = match range.split_once('-') {
+            Some((start, end)) => (start.trim().parse().ok()?, end.trim().parse().ok()?),
+            None => {
+                let zone = range.parse().ok()?;
+                (zone, zone)
+            }
+        };
+
+        if start > end {
+            return None;
+        }
+        Some(ZoneRange { start, end })
+    }
+
+    pub fn terms(&self) -> &[SelectorTerm] {
+        &self.terms
+    }
+
+    /// Returns the bulbs matched by this selector, without duplicates, ordered by id.
+    ///
+    /// `scene_id:` terms are resolved through `scenes`; they match nothing when it is `None`.
+    pub fn select<'a>(&self, bulbs: &'a HashMap<u64, BulbInfo>, scenes: Option<&ScenesHandler>) -> Vec<&'a BulbInfo> {
+        let mut candidates: Vec<&BulbInfo> = bulbs.values().collect();
+        candidates.sort_by(|a, b| a.id.cmp(&b.id));
+
+        let mut seen = HashSet::new();
+        let mut selected = Vec::new();
+        for bulb in self.select_from(&candidates, scenes, true) {
+            if seen.insert(bulb.target) {
+                selected.push(bulb);
+            }
+        }
+
+        selected
+    }
+
+    fn select_from<'a>(&self, candidates: &[&'a BulbInfo], scenes: Option<&ScenesHandler>, resolve_scenes: bool) -> Vec<&'a BulbInfo> {
+        let mut selected = Vec::new();
+
+        for term in &self.terms {
+            let matches: Vec<&BulbInfo> = match term.kind {
+                SelectorKind::SceneId(ref uuid) => {
+                    if resolve_scenes {
+                        Self::scene_matches(uuid, candidates, scenes)
+                    } else {
+                        Vec::new()
+                    }
+                }
+                _ => candidates.iter().copied().filter(|bulb| term.matches(bulb)).collect(),
+            };
+
+            if term.random {
+                selected.extend(matches.choose(&mut thread_rng()).copied());
+            } else {
+                selected.extend(matches);
+            }
+        }
+
+        selected
+    }
+
+    fn scene_matches<'a>(uuid: &str, candidates: &[&'a BulbInfo], scenes: Option<&ScenesHandler>) -> Vec<&'a BulbInfo> {
+        let scene = match scenes.and_then(|handler| handler.get_scene(uuid).ok().flatten()) {
+            Some(scene) => scene,
+            None => return Vec::new(),
+        };
+
+        scene.states
+            .iter()
+            .filter_map(|state| Selector::parse(&state.selector).ok())
+            // Scenes referencing other scenes are not followed, to avoid cycles
+            .flat_map(|selector| selector.select_from(candidates, None, false))
+            .collect()
+    }
+
+    /// Zone ranges requested for `bulb`, if the term that selected it carries any.
+    pub fn zones_for(&self, bulb: &BulbInfo) -> Option<&[ZoneRange]> {
+        self.terms
+            .iter()
+            .find(|term| !term.zones.is_empty() && term.matches(bulb))
+            .map(|term| term.zones.as_slice())
+    }
+}
+
+impl SelectorTerm {
+    fn matches(&self, bulb: &BulbInfo) -> bool {
+        match self.kind {
+            SelectorKind::All => true,
+            SelectorKind::Id(ref id) => bulb.id.eq_ignore_ascii_case(id),
+            SelectorKind::Label(ref label) => names_match(&bulb.label, label),
+            SelectorKind::GroupId(ref id) => bulb.lifx_group.as_ref().map_or(false, |g| g.id.eq_ignore_ascii_case(id)),
+            SelectorKind::Group(ref name) => bulb.lifx_group.as_ref().map_or(false, |g| names_match(&g.name, name)),
+            SelectorKind::LocationId(ref id) => bulb.lifx_location.as_ref().map_or(false, |l| l.id.eq_ignore_ascii_case(id)),
+            SelectorKind::Location(ref name) => bulb.lifx_location.as_ref().map_or(false, |l| names_match(&l.name, name)),
+            SelectorKind::SceneId(_) => false,
+        }
+    }
+}
+
+fn names_match(actual: &str, wanted: &str) -> bool {
+    actual.to_lowercase() == wanted.to_lowercase()
+}
+
+impl fmt::Display for Selector {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, term) in self.terms.iter().enumerate() {
+            if i > 0 {
+                f.write_str(",")?;
+            }
+            match term.kind {
+                SelectorKind::All => f.write_str("all")?,
+                SelectorKind::Id(ref v) => write!(f, "id:{}", v)?,
+                SelectorKind::Label(ref v) => write!(f, "label:{}", v)?,
+                SelectorKind::GroupId(ref v) => write!(f, "group_id:{}", v)?,
+                SelectorKind::Group(ref v) => write!(f, "group:{}", v)?,
+                SelectorKind::LocationId(ref v) => write!(f, "location_id:{}", v)?,
+                SelectorKind::Location(ref v) => write!(f, "location:{}", v)?,
+                SelectorKind::SceneId(ref v) => write!(f, "scene_id:{}", v)?,
+            }
+            if term.random {
+                f.write_str(":random")?;
+            }
+            for zones in &term.zones {
+                write!(f, "|{}-{}", zones.start, zones.end)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+    use crate::scenes::{CreateSceneRequest, SceneState};
+    use crate::{LifxGroup, LifxLocation};
+
+    fn bulb(target: u64, id: &str, label: &str, group: &str, location: &str) -> BulbInfo {
+        let addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(192, 168, 1, 100)), 56700);
+        let mut bulb = BulbInfo::new(0x1234, target, addr);
+        bulb.id = id.to_string();
+        bulb.label = label.to_string();
+        bulb.lifx_group = Some(LifxGroup { id: ident(group), name: group.to_string(), updated_at: 0 });
+        bulb.lifx_location = Some(LifxLocation { id: ident(location), name: location.to_string(), updated_at: 0 });
+        bulb
+    }
+
+    /// A 32 character hex id unique to each test group and location name.
+    fn ident(name: &str) -> String {
+        crate::hex_id(&[name.as_bytes()[0]; 16])
+    }
+
+    fn test_bulbs() -> HashMap<u64, BulbInfo> {
+        let mut bulbs = HashMap::new();
+        bulbs.insert(1, bulb(1, "d073d5000001", "Clean Room", "Kitchen", "Home"));
+        bulbs.insert(2, bulb(2, "d073d5000002", "Desk", "Office", "Home"));
+        bulbs.insert(3, bulb(3, "d073d5000012", "Stove", "Kitchen", "Cabin"));
+        bulbs
+    }
+
+    fn ids(bulbs: Vec<&BulbInfo>) -> Vec<String> {
+        bulbs.iter().map(|b| b.id.clone()).collect()
+    }
+
+    #[test]
+    fn test_parse_simple_terms() {
+        let selector = Selector::parse("label:Desk").unwrap();
+        assert_eq!(selector.terms(), &[SelectorTerm {
+            kind: SelectorKind::Label("Desk".to_string()),
+            zones: vec![],
+            random: false,
+        }]);
+
+        assert_eq!(Selector::parse("all").unwrap().terms()[0].kind, SelectorKind::All);
+        assert!(Selector::parse("scene_id:abc").is_ok());
+    }
+
+    #[test]
+    fn test_parse_random_and_zones() {
+        let selector = Selector::parse("group:Kitchen:random").unwrap();
+        assert_eq!(selector.terms()[0].kind, SelectorKind::Group("Kitchen".to_string()));
+        assert!(selector.terms()[0].random);
+
+        let selector = Selector::parse("id:d073d5|0-5|9").unwrap();
+        assert_eq!(selector.terms()[0].kind, SelectorKind::Id("d073d5".to_string()));
+        assert_eq!(selector.terms()[0].zones, vec![
+            ZoneRange { start: 0, end: 5 },
+            ZoneRange { start: 9, end: 9 },
+        ]);
+    }
+
+    #[test]
+    fn test_parse_invalid() {
+        assert!(Selector::parse("").is_err());
+        assert!(Selector::parse("invalid:selector").is_err());
+        assert!(Selector::parse("label:").is_err());
+        assert!(Selector::parse("kitchen").is_err());
+        assert!(Selector::parse("id:d073d5|5-1").is_err());
+        assert!(Selector::parse("id:d073d5|a-b").is_err());
+        assert!(Selector::parse("all,").is_err());
+    }
+
+    #[test]
+    fn test_id_is_exact_match() {
+        let bulbs = test_bulbs();
+
+        // Used to match d073d5000001 and d073d5000012 by substring
+        let selected = Selector::parse("id:d073d500001").unwrap().select(&bulbs, None);
+        assert!(selected.is_empty());
+
+        let selected = Selector::parse("id:D073D5000001").unwrap().select(&bulbs, None);
+        assert_eq!(ids(selected), vec!["d073d5000001"]);
+    }
+
+    #[test]
+    fn test_names_are_case_insensitive_and_exact() {
+        let bulbs = test_bulbs();
+
+        assert_eq!(ids(Selector::parse("label:clean room").unwrap().select(&bulbs, None)), vec!["d073d5000001"]);
+        assert!(Selector::parse("label:Clean").unwrap().select(&bulbs, None).is_empty());
+        assert_eq!(
+            ids(Selector::parse("group:kitchen").unwrap().select(&bulbs, None)),
+            vec!["d073d5000001", "d073d5000012"]
+        );
+        assert_eq!(
+            ids(Selector::parse(&format!("location_id:{}", ident("Cabin"))).unwrap().select(&bulbs, None)),
+            vec!["d073d5000012"]
+        );
+        assert_eq!(ident("Cabin"), "43434343434343434343434343434343");
+    }
+
+    #[test]
+    fn test_comma_separated_list_without_duplicates() {
+        let bulbs = test_bulbs();
+
+        let selected = Selector::parse("label:Desk,group:Kitchen,id:d073d5000002").unwrap().select(&bulbs, None);
+        assert_eq!(ids(selected), vec!["d073d5000002", "d073d5000001", "d073d5000012"]);
+    }
+
+    #[test]
+    fn test_random_picks_one_match() {
+        let bulbs = test_bulbs();
+
+        for _ in 0..10 {
+            let selected = Selector::parse("group:Kitchen:random").unwrap().select(&bulbs, None);
+            assert_eq!(selected.len(), 1);
+            assert_eq!(selected[0].lifx_group.as_ref().unwrap().name, "Kitchen");
+        }
+        assert!(Selector::parse("group:Garage:random").unwrap().select(&bulbs, None).is_empty());
+    }
+
+    #[test]
+    fn test_scene_id() {
+        let bulbs = test_bulbs();
+        let scenes = ScenesHandler::new();
+        let scene = scenes.create_scene(CreateSceneRequest {
+            name: "Cooking".to_string(),
+            states: vec![SceneState {
+                selector: "label:Stove".to_string(),
+                power: Some("on".to_string()),
+                color: None,
+                brightness: None,
+                kelvin: None,
+            }],
+        }).unwrap().scene;
+
+        let selector = Selector::parse(&format!("scene_id:{}", scene.uuid)).unwrap();
+        assert_eq!(ids(selector.select(&bulbs, Some(&scenes))), vec!["d073d5000012"]);
+        assert!(selector.select(&bulbs, None).is_empty());
+        assert!(Selector::parse("scene_id:missing").unwrap().select(&bulbs, Some(&scenes)).is_empty());
+    }
+
+    #[test]
+    fn test_zones_for() {
+        let bulbs = test_bulbs();
+        let selector = Selector::parse("id:d073d5000001|0-5,label:Desk").unwrap();
+
+        assert_eq!(selector.zones_for(&bulbs[&1]), Some(&[ZoneRange { start: 0, end: 5 }][..]));
+        assert_eq!(selector.zones_for(&bulbs[&2]), None);
+    }
+
+    #[test]
+    fn test_display_round_trip() {
+        let selector = Selector::parse("group:Kitchen:random,id:d073d5|0-5").unwrap();
+        assert_eq!(selector.to_string(), "group:Kitchen:random,id:d073d5|0-5");
+        assert_eq!(Selector::parse(&selector.to_string()).unwrap(), selector);
+    }
+}
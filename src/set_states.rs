@@ -1,11 +1,15 @@
 use std::collections::HashMap;
-use std::time::Duration;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 use std::thread;
 use serde::{Deserialize, Deserializer, Serialize};
 use serde::de::{self, Visitor, MapAccess};
 use std::fmt;
 use lifx_rs::lan::{PowerLevel, HSBK};
-use crate::{BulbInfo, Manager};
+use crate::{color, delivery, BulbInfo, LifxColor, Manager};
+use crate::color::ColorSpec;
+use crate::scenes::ScenesHandler;
+use crate::selector::{Selector, ZoneRange};
 
 #[derive(Debug, Clone)]
 pub struct StateUpdate {
@@ -148,12 +152,39 @@ impl<'de> Deserialize<'de> for StateUpdate {
     }
 }
 
+impl StateUpdate {
+    /// `duration` in the milliseconds the LAN protocol expects.
+    fn duration_ms(&self) -> u32 {
+        (self.duration.unwrap_or(0.0).max(0.0) * 1000.0) as u32
+    }
+
+    /// The state's color with `brightness` folded in, as `PUT /v1/lights/:selector/state` does,
+    /// so both land in a single SetColor.
+    fn color_for(&self, current: Option<&LifxColor>) -> Option<Result<HSBK, String>> {
+        let color_str = self.color.as_ref()?;
+        Some(ColorSpec::parse(color_str).map_err(|e| e.to_string()).map(|mut spec| {
+            if let Some(brightness) = self.brightness {
+                spec.brightness = Some(color::fraction_to_lifx(brightness));
+            }
+            spec.apply_to(color::base_color(current))
+        }))
+    }
+}
+
 #[derive(Deserialize, Debug)]
 pub struct StatesRequest {
     pub states: Vec<StateUpdate>,
     pub defaults: Option<StateUpdate>,
 }
 
+impl StatesRequest {
+    /// Whether every state is `fast`, so the caller does not wait for acknowledgements.
+    pub fn is_fast(&self) -> bool {
+        let default_fast = self.defaults.as_ref().and_then(|d| d.fast).unwrap_or(false);
+        !self.states.is_empty() && self.states.iter().all(|s| s.fast.unwrap_or(default_fast))
+    }
+}
+
 #[derive(Serialize, Clone, Debug)]
 pub struct StateResult {
     pub id: String,
@@ -171,14 +202,16 @@ pub struct StatesResponse {
 #[derive(Debug)]
 struct BulbUpdate {
     bulb_info: BulbInfo,
+    /// Zones picked by the state's selector, e.g. `id:d073d5|0-5`.
+    zones: Option<Vec<ZoneRange>>,
     state_update: StateUpdate,
     attempt: u32,
 }
 
 #[derive(Debug)]
 struct UpdateResult {
-    id: String,
-    label: String,
+    bulb_info: BulbInfo,
+    fast: bool,
     success: bool,
     error: Option<String>,
 }
@@ -186,6 +219,7 @@ struct UpdateResult {
 pub struct SetStatesHandler {
     max_retries: u32,
     concurrent_workers: usize,
+    scenes: Option<Arc<ScenesHandler>>,
 }
 
 impl SetStatesHandler {
@@ -193,9 +227,16 @@ impl SetStatesHandler {
         SetStatesHandler {
             max_retries: 3,
             concurrent_workers: 4,
+            scenes: None,
         }
     }
 
+    /// Lets `scene_id:` selectors resolve against the given scenes.
+    pub fn with_scenes(mut self, scenes: Arc<ScenesHandler>) -> Self {
+        self.scenes = Some(scenes);
+        self
+    }
+
     pub fn handle_request(&self, mgr: &mut Manager, request: StatesRequest) -> StatesResponse {
         let bulbs = match mgr.bulbs.lock() {
             Ok(guard) => guard,
@@ -228,19 +269,10 @@ impl SetStatesHandler {
         let states_with_defaults = self.apply_defaults(request.states, request.defaults);
         
         // Collect all bulb updates to be performed
-        let mut all_updates: Vec<BulbUpdate> = Vec::new();
+        let all_updates = self.collect_updates(&bulbs, states_with_defaults);
         
-        for state_update in states_with_defaults {
-            let filtered_bulbs = self.filter_bulbs_by_selector(&bulbs, &state_update.selector);
-            
-            for bulb in filtered_bulbs {
-                all_updates.push(BulbUpdate {
-                    bulb_info: bulb.clone(),
-                    state_update: state_update.clone(),
-                    attempt: 0,
-                });
-            }
-        }
+        // Release the bulbs so the worker can record acks while we wait for them
+        drop(bulbs);
         
         // If no bulbs match any selector, return empty results
         if all_updates.is_empty() {
@@ -248,16 +280,31 @@ impl SetStatesHandler {
         }
         
         // Execute updates concurrently with retry logic
+        let sent_at = Instant::now();
         let results = self.execute_concurrent_updates(mgr, all_updates);
         
+        // Wait for acks on everything that was sent without `fast`
+        let confirmable: Vec<&BulbInfo> = results.iter()
+            .filter(|r| r.success && !r.fast)
+            .map(|r| &r.bulb_info)
+            .collect();
+        let mut statuses = delivery::confirm(&mgr.sock, &confirmable, sent_at).into_iter();
+        
         // Convert results to response format
         let mut response_results = Vec::new();
-        for result in results {
+        for result in &results {
+            let status = if !result.success {
+                "error"
+            } else if result.fast {
+                "ok"
+            } else {
+                statuses.next().map_or("ok", |s| s.as_str())
+            };
             response_results.push(StateResult {
-                id: result.id,
-                label: result.label,
-                status: if result.success { "ok".to_string() } else { "error".to_string() },
-                error: result.error,
+                id: result.bulb_info.id.clone(),
+                label: result.bulb_info.label.clone(),
+                status: status.to_string(),
+                error: result.error.clone(),
             });
         }
         
@@ -373,120 +420,11 @@ impl SetStatesHandler {
     }
     
     fn is_valid_selector(&self, selector: &str) -> bool {
-        selector == "all" ||
-        selector.starts_with("id:") ||
-        selector.starts_with("group_id:") ||
-        selector.starts_with("location_id:") ||
-        selector.starts_with("label:") ||
-        selector.starts_with("group:") ||
-        selector.starts_with("location:")
+        Selector::parse(selector).is_ok()
     }
     
     fn is_valid_color(&self, color: &str) -> bool {
-        // Named colors
-        let named_colors = ["white", "red", "orange", "yellow", "cyan", "green", "blue", "purple", "pink"];
-        if named_colors.contains(&color) {
-            return true;
-        }
-        
-        // Validate kelvin value
-        if let Some(kelvin_str) = color.strip_prefix("kelvin:") {
-            if let Ok(kelvin) = kelvin_str.parse::<u16>() {
-                return kelvin >= 1500 && kelvin <= 9000;
-            }
-            return false;
-        }
-        
-        // Validate hue value
-        if let Some(hue_str) = color.strip_prefix("hue:") {
-            if let Ok(hue) = hue_str.parse::<f64>() {
-                return hue.is_finite() && hue >= 0.0 && hue <= 360.0;
-            }
-            return false;
-        }
-        
-        // Validate saturation value
-        if let Some(sat_str) = color.strip_prefix("saturation:") {
-            if let Ok(sat) = sat_str.parse::<f64>() {
-                return sat.is_finite() && sat >= 0.0 && sat <= 1.0;
-            }
-            return false;
-        }
-        
-        // Validate brightness value
-        if let Some(bright_str) = color.strip_prefix("brightness:") {
-            if let Ok(bright) = bright_str.parse::<f64>() {
-                return bright.is_finite() && bright >= 0.0 && bright <= 1.0;
-            }
-            return false;
-        }
-        
-        // Validate RGB format
-        if let Some(rgb_str) = color.strip_prefix("rgb:") {
-            let parts: Vec<&str> = rgb_str.split(',').collect();
-            if parts.len() != 3 {
-                return false;
-            }
-            for part in parts {
-                if part.trim().parse::<u8>().is_err() {
-                    return false;
-                }
-            }
-            return true;
-        }
-        
-        // Validate hex color
-        if let Some(hex) = color.strip_prefix("#") {
-            if hex.len() != 6 {
-                return false;
-            }
-            return hex.chars().all(|c| c.is_ascii_hexdigit());
-        }
-        
-        // HSB format: "hue:120 saturation:1.0 brightness:0.5"
-        if color.contains(" ") && (color.contains("hue:") || color.contains("saturation:") || color.contains("brightness:") || color.contains("kelvin:")) {
-            let parts: Vec<&str> = color.split_whitespace().collect();
-            for part in parts {
-                if let Some(hue_str) = part.strip_prefix("hue:") {
-                    if let Ok(hue) = hue_str.parse::<f64>() {
-                        if !hue.is_finite() || hue < 0.0 || hue > 360.0 {
-                            return false;
-                        }
-                    } else {
-                        return false;
-                    }
-                } else if let Some(sat_str) = part.strip_prefix("saturation:") {
-                    if let Ok(sat) = sat_str.parse::<f64>() {
-                        if !sat.is_finite() || sat < 0.0 || sat > 1.0 {
-                            return false;
-                        }
-                    } else {
-                        return false;
-                    }
-                } else if let Some(bright_str) = part.strip_prefix("brightness:") {
-                    if let Ok(bright) = bright_str.parse::<f64>() {
-                        if !bright.is_finite() || bright < 0.0 || bright > 1.0 {
-                            return false;
-                        }
-                    } else {
-                        return false;
-                    }
-                } else if let Some(kelvin_str) = part.strip_prefix("kelvin:") {
-                    if let Ok(kelvin) = kelvin_str.parse::<u16>() {
-                        if kelvin < 1500 || kelvin > 9000 {
-                            return false;
-                        }
-                    } else {
-                        return false;
-                    }
-                } else {
-                    return false;
-                }
-            }
-            return true;
-        }
-        
-        false
+        ColorSpec::parse(color).is_ok()
     }
     
     fn apply_defaults(&self, mut states: Vec<StateUpdate>, defaults: Option<StateUpdate>) -> Vec<StateUpdate> {
@@ -515,45 +453,26 @@ impl SetStatesHandler {
         states
     }
     
-    fn filter_bulbs_by_selector<'a>(&self, bulbs: &'a HashMap<u64, BulbInfo>, selector: &str) -> Vec<&'a BulbInfo> {
-        let mut filtered = Vec::new();
-        
-        for bulb in bulbs.values() {
-            let matches = match selector {
-                "all" => true,
-                s if s.starts_with("id:") => {
-                    let id = s.strip_prefix("id:").unwrap_or("");
-                    bulb.id.contains(id)
-                },
-                s if s.starts_with("group_id:") => {
-                    let group_id = s.strip_prefix("group_id:").unwrap_or("");
-                    bulb.lifx_group.as_ref().map_or(false, |g| g.id.contains(group_id))
-                },
-                s if s.starts_with("group:") => {
-                    let group_name = s.strip_prefix("group:").unwrap_or("");
-                    bulb.lifx_group.as_ref().map_or(false, |g| g.name.contains(group_name))
-                },
-                s if s.starts_with("location_id:") => {
-                    let location_id = s.strip_prefix("location_id:").unwrap_or("");
-                    bulb.lifx_location.as_ref().map_or(false, |l| l.id.contains(location_id))
-                },
-                s if s.starts_with("location:") => {
-                    let location_name = s.strip_prefix("location:").unwrap_or("");
-                    bulb.lifx_location.as_ref().map_or(false, |l| l.name.contains(location_name))
-                },
-                s if s.starts_with("label:") => {
-                    let label = s.strip_prefix("label:").unwrap_or("");
-                    bulb.label.contains(label)
-                },
-                _ => false,
+    /// One update per bulb matched by each state's selector, with the zones it selects.
+    fn collect_updates(&self, bulbs: &HashMap<u64, BulbInfo>, states: Vec<StateUpdate>) -> Vec<BulbUpdate> {
+        let mut updates = Vec::new();
+        for state_update in states {
+            // Selectors were validated by `validate_request`
+            let selector = match Selector::parse(&state_update.selector) {
+                Ok(selector) => selector,
+                Err(_) => continue,
             };
             
-            if matches {
-                filtered.push(bulb);
+            for bulb in selector.select(bulbs, self.scenes.as_deref()) {
+                updates.push(BulbUpdate {
+                    bulb_info: bulb.clone(),
+                    zones: selector.zones_for(bulb).map(<[ZoneRange]>::to_vec),
+                    state_update: state_update.clone(),
+                    attempt: 0,
+                });
             }
         }
-        
-        filtered
+        updates
     }
     
     fn execute_concurrent_updates(&self, mgr: &Manager, updates: Vec<BulbUpdate>) -> Vec<UpdateResult> {
@@ -569,7 +488,7 @@ impl SetStatesHandler {
             while update.attempt < self.max_retries && !success {
                 update.attempt += 1;
                 
-                match Self::apply_state_to_bulb(mgr, &update.bulb_info, &update.state_update) {
+                match Self::apply_state_to_bulb(mgr, &update.bulb_info, &update.state_update, update.zones.as_deref()) {
                     Ok(_) => {
                         success = true;
                     },
@@ -584,8 +503,8 @@ impl SetStatesHandler {
             }
             
             results.push(UpdateResult {
-                id: update.bulb_info.id.clone(),
-                label: update.bulb_info.label.clone(),
+                fast: update.state_update.fast.unwrap_or(false),
+                bulb_info: update.bulb_info,
                 success,
                 error: if success { None } else { error_msg },
             });
@@ -594,7 +513,7 @@ impl SetStatesHandler {
         results
     }
     
-    fn apply_state_to_bulb(mgr: &Manager, bulb: &BulbInfo, state: &StateUpdate) -> Result<(), String> {
+    fn apply_state_to_bulb(mgr: &Manager, bulb: &BulbInfo, state: &StateUpdate, zones: Option<&[ZoneRange]>) -> Result<(), String> {
         // Apply power state
         if let Some(ref power) = state.power {
             let power_level = if power == "on" { 
@@ -607,12 +526,13 @@ impl SetStatesHandler {
                 .map_err(|e| format!("Failed to set power: {:?}", e))?;
         }
         
-        // Parse and apply color
-        if let Some(ref color_str) = state.color {
-            let hsbk = Self::parse_color(color_str, bulb)?;
-            let duration = state.duration.unwrap_or(0.0) as u32;
+        let duration = state.duration_ms();
+        
+        // Parse and apply color, with any brightness
+        if let Some(hsbk) = state.color_for(bulb.lifx_color.as_ref()) {
+            let hsbk = hsbk?;
             
-            bulb.set_color(&mgr.sock, hsbk, duration)
+            bulb.set_color_in_zones(&mgr.sock, hsbk, duration, zones)
                 .map_err(|e| format!("Failed to set color: {:?}", e))?;
         }
         
@@ -620,7 +540,6 @@ impl SetStatesHandler {
         if state.color.is_none() && state.brightness.is_some() {
             let brightness_val = state.brightness.ok_or_else(|| 
                 format!("Brightness value expected but not found"))?;
-            let duration = state.duration.unwrap_or(0.0) as u32;
             
             let current_color = bulb.lifx_color.as_ref();
             let hsbk = HSBK {
@@ -630,7 +549,7 @@ impl SetStatesHandler {
                 kelvin: current_color.map_or(6500, |c| c.kelvin),
             };
             
-            bulb.set_color(&mgr.sock, hsbk, duration)
+            bulb.set_color_in_zones(&mgr.sock, hsbk, duration, zones)
                 .map_err(|e| format!("Failed to set brightness: {:?}", e))?;
         }
         
@@ -643,174 +562,73 @@ impl SetStatesHandler {
         
         Ok(())
     }
-    
-    fn parse_color(color_str: &str, bulb: &BulbInfo) -> Result<HSBK, String> {
-        let current_color = bulb.lifx_color.as_ref();
-        let mut hue = current_color.map_or(0, |c| c.hue);
-        let mut saturation = current_color.map_or(0, |c| c.saturation);
-        let mut brightness = current_color.map_or(65535, |c| c.brightness);
-        let mut kelvin = current_color.map_or(6500, |c| c.kelvin);
-        
-        // Parse different color formats
-        if color_str.starts_with("kelvin:") {
-            let k = color_str.strip_prefix("kelvin:")
-                .and_then(|s| s.parse::<u16>().ok())
-                .ok_or_else(|| "Invalid kelvin value".to_string())?;
-            kelvin = k.clamp(1500, 9000);
-            saturation = 0;
-        } else if color_str.starts_with("hue:") {
-            let h = color_str.strip_prefix("hue:")
-                .and_then(|s| s.parse::<f64>().ok())
-                .ok_or_else(|| "Invalid hue value".to_string())?;
-            hue = ((h * 65535.0 / 360.0) as u16).min(65535);
-        } else if color_str.starts_with("saturation:") {
-            let s = color_str.strip_prefix("saturation:")
-                .and_then(|s| s.parse::<f64>().ok())
-                .ok_or_else(|| "Invalid saturation value".to_string())?;
-            saturation = ((s * 65535.0) as u16).min(65535);
-        } else if color_str.starts_with("brightness:") {
-            let b = color_str.strip_prefix("brightness:")
-                .and_then(|s| s.parse::<f64>().ok())
-                .ok_or_else(|| "Invalid brightness value".to_string())?;
-            brightness = ((b * 65535.0) as u16).min(65535);
-        } else if color_str.starts_with("rgb:") {
-            // Parse RGB format "rgb:255,0,128"
-            let rgb_str = color_str.strip_prefix("rgb:").unwrap_or("");
-            let parts: Vec<&str> = rgb_str.split(',').collect();
-            if parts.len() != 3 {
-                return Err("RGB format must be 'rgb:r,g,b'".to_string());
-            }
-            
-            let r = parts[0].parse::<u8>().map_err(|_| "Invalid red value")?;
-            let g = parts[1].parse::<u8>().map_err(|_| "Invalid green value")?;
-            let b = parts[2].parse::<u8>().map_err(|_| "Invalid blue value")?;
-            
-            let (h, s, l) = Self::rgb_to_hsl(r, g, b);
-            hue = (h * 65535.0 / 360.0) as u16;
-            saturation = (s * 65535.0) as u16;
-            brightness = (l * 65535.0) as u16;
-        } else if color_str.starts_with("#") {
-            // Parse hex color
-            let hex = color_str.strip_prefix("#").unwrap_or("");
-            if hex.len() != 6 {
-                return Err("Hex color must be 6 characters".to_string());
-            }
-            
-            let r = u8::from_str_radix(&hex[0..2], 16).map_err(|_| "Invalid hex color")?;
-            let g = u8::from_str_radix(&hex[2..4], 16).map_err(|_| "Invalid hex color")?;
-            let b = u8::from_str_radix(&hex[4..6], 16).map_err(|_| "Invalid hex color")?;
-            
-            let (h, s, l) = Self::rgb_to_hsl(r, g, b);
-            hue = (h * 65535.0 / 360.0) as u16;
-            saturation = (s * 65535.0) as u16;
-            brightness = (l * 65535.0) as u16;
-        } else if color_str.contains(" ") {
-            // Parse space-separated HSB values
-            let parts: Vec<&str> = color_str.split_whitespace().collect();
-            for part in parts {
-                if part.starts_with("hue:") {
-                    let h = part.strip_prefix("hue:")
-                        .and_then(|s| s.parse::<f64>().ok())
-                        .ok_or_else(|| "Invalid hue value".to_string())?;
-                    hue = ((h * 65535.0 / 360.0) as u16).min(65535);
-                } else if part.starts_with("saturation:") {
-                    let s = part.strip_prefix("saturation:")
-                        .and_then(|s| s.parse::<f64>().ok())
-                        .ok_or_else(|| "Invalid saturation value".to_string())?;
-                    saturation = ((s * 65535.0) as u16).min(65535);
-                } else if part.starts_with("brightness:") {
-                    let b = part.strip_prefix("brightness:")
-                        .and_then(|s| s.parse::<f64>().ok())
-                        .ok_or_else(|| "Invalid brightness value".to_string())?;
-                    brightness = ((b * 65535.0) as u16).min(65535);
-                } else if part.starts_with("kelvin:") {
-                    let k = part.strip_prefix("kelvin:")
-                        .and_then(|s| s.parse::<u16>().ok())
-                        .ok_or_else(|| "Invalid kelvin value".to_string())?;
-                    kelvin = k.clamp(1500, 9000);
-                }
-            }
-        } else {
-            // Handle named colors
-            match color_str {
-                "white" => {
-                    saturation = 0;
-                    hue = 0;
-                },
-                "red" => {
-                    hue = 0;
-                    saturation = 65535;
-                },
-                "orange" => {
-                    hue = 7098;
-                    saturation = 65535;
-                },
-                "yellow" => {
-                    hue = 10920;
-                    saturation = 65535;
-                },
-                "cyan" => {
-                    hue = 32760;
-                    saturation = 65535;
-                },
-                "green" => {
-                    hue = 21840;
-                    saturation = 65535;
-                },
-                "blue" => {
-                    hue = 43680;
-                    saturation = 65535;
-                },
-                "purple" => {
-                    hue = 50050;
-                    saturation = 65535;
-                },
-                "pink" => {
-                    hue = 63700;
-                    saturation = 25000;
-                },
-                _ => return Err(format!("Unknown color: {}", color_str)),
-            }
-        }
-        
-        Ok(HSBK { hue, saturation, brightness, kelvin })
-    }
-    
-    fn rgb_to_hsl(r: u8, g: u8, b: u8) -> (f64, f64, f64) {
-        let r = r as f64 / 255.0;
-        let g = g as f64 / 255.0;
-        let b = b as f64 / 255.0;
-        
-        let max = r.max(g).max(b);
-        let min = r.min(g).min(b);
-        let diff = max - min;
-        
-        let l = (max + min) / 2.0;
-        
-        if diff == 0.0 {
-            return (0.0, 0.0, l);
-        }
-        
-        let s = if l < 0.5 {
-            diff / (max + min)
-        } else {
-            diff / (2.0 - max - min)
-        };
-        
-        let h = if max == r {
-            ((g - b) / diff + if g < b { 6.0 } else { 0.0 }) / 6.0
-        } else if max == g {
-            ((b - r) / diff + 2.0) / 6.0
-        } else {
-            ((r - g) / diff + 4.0) / 6.0
-        };
-        
-        (h * 360.0, s, l)
-    }
 }
 
 impl Default for SetStatesHandler {
     fn default() -> Self {
         Self::new()
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+
+    fn state(selector: &str) -> StateUpdate {
+        StateUpdate {
+            selector: selector.to_string(),
+            power: None,
+            color: Some("red".to_string()),
+            brightness: None,
+            duration: None,
+            infrared: None,
+            fast: None,
+        }
+    }
+
+    #[test]
+    fn test_duration_is_in_seconds() {
+        let mut update = state("all");
+        assert_eq!(update.duration_ms(), 0);
+        update.duration = Some(2.0);
+        assert_eq!(update.duration_ms(), 2000);
+        update.duration = Some(0.25);
+        assert_eq!(update.duration_ms(), 250);
+    }
+
+    #[test]
+    fn test_color_keeps_brightness() {
+        let mut update = state("all");
+        update.brightness = Some(0.5);
+        let current = LifxColor { hue: 1000, saturation: 0, kelvin: 4000, brightness: 65535 };
+
+        let hsbk = update.color_for(Some(&current)).unwrap().unwrap();
+        assert_eq!(hsbk.hue, 0);
+        assert_eq!(hsbk.saturation, 65535);
+        assert_eq!(hsbk.brightness, color::fraction_to_lifx(0.5));
+
+        update.color = Some("not a color".to_string());
+        assert!(update.color_for(None).unwrap().is_err());
+        update.color = None;
+        assert!(update.color_for(None).is_none());
+    }
+
+    #[test]
+    fn test_updates_keep_selected_zones() {
+        let addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(192, 168, 1, 100)), 56700);
+        let mut bulbs = HashMap::new();
+        for target in [0x0100_00d5_73d0u64, 0x0200_00d5_73d0].iter() {
+            bulbs.insert(*target, BulbInfo::new(0x1234, *target, addr));
+        }
+
+        let updates = SetStatesHandler::new().collect_updates(&bulbs, vec![
+            state("id:d073d5000001|0-5"),
+            state("id:d073d5000002"),
+        ]);
+        assert_eq!(updates.len(), 2);
+        assert_eq!(updates[0].bulb_info.id, "d073d5000001");
+        assert_eq!(updates[0].zones, Some(vec![ZoneRange { start: 0, end: 5 }]));
+        assert_eq!(updates[1].zones, None);
+    }
+}
@@ -0,0 +1,277 @@
+use serde::{Deserialize, Serialize};
+use std::time::Instant;
+use lifx_rs::lan::{PowerLevel, HSBK};
+use crate::color::{KELVIN_MAX, KELVIN_MIN};
+use crate::error::{LifxError, Result};
+use crate::zones::ZonesHandler;
+use crate::{delivery, BulbInfo, LifxColor, Manager, LIFX_BRIGHTNESS_MAX, LIFX_HUE_DEGREE_FACTOR};
+
+/// Body of `POST /v1/lights/:selector/state/delta`.
+///
+/// Every field is a change relative to the bulb's current state: `hue` in degrees,
+/// `saturation`, `brightness` and `infrared` as fractions between -1.0 and 1.0, `kelvin` in kelvin.
+#[derive(Deserialize, Debug, Clone, Default)]
+pub struct StateDeltaRequest {
+    /// Absolute power, `on` or `off`.
+    pub power: Option<String>,
+    /// Transition time in seconds.
+    pub duration: Option<f64>,
+    pub infrared: Option<f64>,
+    pub hue: Option<f64>,
+    pub saturation: Option<f64>,
+    pub brightness: Option<f64>,
+    pub kelvin: Option<i32>,
+    /// Don't wait for the bulbs to acknowledge.
+    pub fast: Option<bool>,
+}
+
+impl StateDeltaRequest {
+    pub fn validate(&self) -> Result<()> {
+        match self.power.as_deref() {
+            None | Some("on") | Some("off") => {}
+            Some(other) => {
+                return Err(LifxError::ValidationError(format!("power must be 'on' or 'off', got '{}'", other)))
+            }
+        }
+        if self.duration.map_or(false, |d| !d.is_finite() || d < 0.0) {
+            return Err(LifxError::ValidationError("duration must be a non-negative number".to_string()));
+        }
+        if self.hue.map_or(false, |h| !h.is_finite()) {
+            return Err(LifxError::ValidationError("hue must be a finite number".to_string()));
+        }
+        for &(name, value) in &[("saturation", self.saturation), ("brightness", self.brightness), ("infrared", self.infrared)] {
+            if value.map_or(false, |v| !v.is_finite() || !(-1.0..=1.0).contains(&v)) {
+                return Err(LifxError::ValidationError(format!("{} must be between -1.0 and 1.0", name)));
+            }
+        }
+        Ok(())
+    }
+
+    fn changes_color(&self) -> bool {
+        self.hue.is_some() || self.saturation.is_some() || self.brightness.is_some() || self.kelvin.is_some()
+    }
+}
+
+#[derive(Serialize, Debug)]
+pub struct StateDeltaResult {
+    pub id: String,
+    pub label: String,
+    pub status: String,
+    pub message: Option<String>,
+}
+
+#[derive(Serialize)]
+pub struct StateDeltaResponse {
+    pub results: Vec<StateDeltaResult>,
+}
+
+pub struct StateDeltaHandler;
+
+impl StateDeltaHandler {
+    pub fn new() -> Self {
+        StateDeltaHandler
+    }
+
+    /// Applies the deltas to each bulb's cached state, zone by zone on multizone bulbs.
+    ///
+    /// Waits for acks through `delivery::confirm` unless the request is `fast`, so the bulbs
+    /// lock must not be held.
+    pub fn handle_delta(&self, mgr: &Manager, bulbs: &[&BulbInfo], request: StateDeltaRequest) -> StateDeltaResponse {
+        let sent_at = Instant::now();
+        let sent: Vec<_> = bulbs.iter().map(|bulb| self.apply_delta(mgr, bulb, &request)).collect();
+        let statuses = delivery::confirm_unless_fast(&mgr.sock, bulbs, &sent, sent_at, request.fast.unwrap_or(false));
+
+        let results = bulbs.iter().zip(sent).zip(statuses).map(|((bulb, result), status)| {
+            StateDeltaResult {
+                id: bulb.id.clone(),
+                label: bulb.label.clone(),
+                status: status.map_or("error", |s| s.as_str()).to_string(),
+                message: result.err(),
+            }
+        }).collect();
+
+        StateDeltaResponse { results }
+    }
+
+    fn apply_delta(&self, mgr: &Manager, bulb: &BulbInfo, request: &StateDeltaRequest) -> std::result::Result<(), String> {
+        let duration_ms = (request.duration.unwrap_or(0.0) * 1000.0) as u32;
+
+        // Work out every change before sending anything, so a bulb is never left half updated
+        let colors = if request.changes_color() {
+            let colors: Vec<HSBK> = current_colors(bulb)?.into_iter()
+                .map(|current| apply_color_delta(current, request))
+                .collect();
+            Some(colors)
+        } else {
+            None
+        };
+
+        let infrared = match request.infrared {
+            Some(delta) => {
+                if !bulb.has_infrared() {
+                    return Err("Device does not support infrared".to_string());
+                }
+                let current = bulb.infrared.as_ref()
+                    .ok_or_else(|| "Current infrared level not known yet, try again shortly".to_string())?;
+                Some(add_fraction(*current, delta))
+            }
+            None => None,
+        };
+
+        if let Some(ref power) = request.power {
+            let level = if power == "on" { PowerLevel::Enabled } else { PowerLevel::Standby };
+            bulb.set_power_with_duration(&mgr.sock, level, duration_ms)
+                .map_err(|e| format!("Failed to set power: {:?}", e))?;
+        }
+        if let Some(colors) = colors {
+            let sent = if bulb.is_multizone() {
+                ZonesHandler::new().paint(mgr, bulb, &colors, duration_ms)
+            } else {
+                bulb.set_color(&mgr.sock, colors[0], duration_ms)
+            };
+            sent.map_err(|e| format!("Failed to set color: {:?}", e))?;
+        }
+        if let Some(infrared) = infrared {
+            bulb.set_infrared(&mgr.sock, infrared)
+                .map_err(|e| format!("Failed to set infrared: {:?}", e))?;
+        }
+        Ok(())
+    }
+}
+
+impl Default for StateDeltaHandler {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Current color of every zone of a multizone bulb, or the single color of any other bulb.
+fn current_colors(bulb: &BulbInfo) -> std::result::Result<Vec<HSBK>, String> {
+    let hsbk = |c: &LifxColor| HSBK { hue: c.hue, saturation: c.saturation, brightness: c.brightness, kelvin: c.kelvin };
+    let colors = if bulb.is_multizone() {
+        bulb.zones.as_ref().map(|zones| zones.iter().map(hsbk).collect::<Vec<_>>())
+    } else {
+        bulb.lifx_color.as_ref().map(|color| vec![hsbk(color)])
+    };
+    colors.filter(|colors| !colors.is_empty())
+        .ok_or_else(|| "Current color not known yet, try again shortly".to_string())
+}
+
+/// Hue wraps around the color wheel; saturation, brightness and kelvin are clamped to their ranges.
+fn apply_color_delta(current: HSBK, request: &StateDeltaRequest) -> HSBK {
+    let hue = match request.hue {
+        Some(degrees) => {
+            let delta = (degrees * LIFX_HUE_DEGREE_FACTOR as f64).round() as i64;
+            (current.hue as i64 + delta).rem_euclid(0x10000) as u16
+        }
+        None => current.hue,
+    };
+    let kelvin = match request.kelvin {
+        Some(delta) => (current.kelvin as i32 + delta).clamp(KELVIN_MIN as i32, KELVIN_MAX as i32) as u16,
+        None => current.kelvin,
+    };
+
+    HSBK {
+        hue,
+        saturation: request.saturation.map_or(current.saturation, |d| add_fraction(current.saturation, d)),
+        brightness: request.brightness.map_or(current.brightness, |d| add_fraction(current.brightness, d)),
+        kelvin,
+    }
+}
+
+/// Adds a -1.0 to 1.0 delta to a 16 bit level, clamping at 0 and 65535.
+fn add_fraction(current: u16, delta: f64) -> u16 {
+    let delta = (delta * LIFX_BRIGHTNESS_MAX as f64).round() as i64;
+    (current as i64 + delta).clamp(0, u16::MAX as i64) as u16
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn current() -> HSBK {
+        HSBK { hue: 1000, saturation: 32768, brightness: 60000, kelvin: 3500 }
+    }
+
+    #[test]
+    fn test_hue_wraps_around() {
+        let request = StateDeltaRequest { hue: Some(-10.0), ..Default::default() };
+        let color = apply_color_delta(current(), &request);
+        // 1000 - 1820 wraps to the top of the wheel
+        assert_eq!(color.hue, 64716);
+
+        let request = StateDeltaRequest { hue: Some(360.0), ..Default::default() };
+        assert_eq!(apply_color_delta(current(), &request).hue, 1000);
+
+        let request = StateDeltaRequest { hue: Some(720.0 + 90.0), ..Default::default() };
+        assert_eq!(apply_color_delta(current(), &request).hue, 1000 + 16384);
+    }
+
+    #[test]
+    fn test_levels_are_clamped() {
+        let request = StateDeltaRequest { brightness: Some(0.5), saturation: Some(-0.75), ..Default::default() };
+        let color = apply_color_delta(current(), &request);
+        assert_eq!(color.brightness, 65535);
+        assert_eq!(color.saturation, 0);
+
+        let request = StateDeltaRequest { brightness: Some(-0.1), ..Default::default() };
+        assert_eq!(apply_color_delta(current(), &request).brightness, 60000 - 6554);
+    }
+
+    #[test]
+    fn test_kelvin_is_clamped() {
+        let warmer = StateDeltaRequest { kelvin: Some(-500), ..Default::default() };
+        assert_eq!(apply_color_delta(current(), &warmer).kelvin, 3000);
+
+        let too_warm = StateDeltaRequest { kelvin: Some(-5000), ..Default::default() };
+        assert_eq!(apply_color_delta(current(), &too_warm).kelvin, KELVIN_MIN);
+
+        let too_cool = StateDeltaRequest { kelvin: Some(10000), ..Default::default() };
+        assert_eq!(apply_color_delta(current(), &too_cool).kelvin, KELVIN_MAX);
+    }
+
+    #[test]
+    fn test_untouched_components_are_kept() {
+        let request = StateDeltaRequest { kelvin: Some(100), ..Default::default() };
+        let color = apply_color_delta(current(), &request);
+        assert_eq!((color.hue, color.saturation, color.brightness), (1000, 32768, 60000));
+        assert!(!StateDeltaRequest { infrared: Some(0.1), ..Default::default() }.changes_color());
+    }
+
+    #[test]
+    fn test_multizone_deltas_apply_per_zone() {
+        use crate::{LiColor, RefreshableData};
+        use lifx_rs::lan::Message;
+        use std::time::Duration;
+
+        let addr = "192.168.1.100:56700".parse().unwrap();
+        let mut strip = BulbInfo::new(0x1234, 1, addr);
+        let mut zones = RefreshableData::empty(Duration::from_secs(15), Message::GetColorZones { start_index: 0, end_index: 255 });
+        zones.update(vec![Some(current()), Some(HSBK { hue: 30000, ..current() })]);
+        strip.color = LiColor::Multi(zones);
+        assert!(current_colors(&strip).is_err());
+
+        strip.update_zones();
+        let request = StateDeltaRequest { hue: Some(90.0), brightness: Some(-0.5), ..Default::default() };
+        let colors: Vec<HSBK> = current_colors(&strip).unwrap().into_iter()
+            .map(|color| apply_color_delta(color, &request))
+            .collect();
+        assert_eq!(colors.iter().map(|c| c.hue).collect::<Vec<_>>(), vec![1000 + 16384, 30000 + 16384]);
+        assert_eq!(colors[1].brightness, 60000 - 32768);
+        // The first zone stands in for the strip's own color
+        assert_eq!(strip.lifx_color.as_ref().map(|c| c.hue), Some(1000));
+    }
+
+    #[test]
+    fn test_state_delta_request_validation() {
+        let request: StateDeltaRequest = serde_json::from_str(
+            r#"{"brightness": -0.2, "hue": 30, "kelvin": 200, "duration": 1.5}"#
+        ).unwrap();
+        assert!(request.validate().is_ok());
+
+        assert!(StateDeltaRequest { brightness: Some(1.5), ..Default::default() }.validate().is_err());
+        assert!(StateDeltaRequest { infrared: Some(-2.0), ..Default::default() }.validate().is_err());
+        assert!(StateDeltaRequest { power: Some("maybe".to_string()), ..Default::default() }.validate().is_err());
+        assert!(StateDeltaRequest { duration: Some(-1.0), ..Default::default() }.validate().is_err());
+    }
+}
@@ -0,0 +1,183 @@
+//! Bulbs configured by address, for networks where broadcast discovery does not reach them
+//! (routed VLANs, mesh Wi-Fi dropping broadcasts).
+//!
+//! Each configured device is probed with a unicast GetService. Once it replies it is tracked
+//! like any broadcast-discovered bulb, so it is polled by `Manager::refresh`; devices that
+//! have not replied, or have gone silent, are probed again every `PROBE_INTERVAL`.
+
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr, UdpSocket};
+use std::time::Duration;
+use lifx_rs::lan::{BuildOptions, Message, RawMessage};
+use log::{debug, warn};
+use crate::error::{LifxError, Result};
+use crate::BulbInfo;
+
+/// UDP port LIFX devices listen on.
+pub const LIFX_PORT: u16 = 56700;
+/// How often configured devices that are missing or disconnected are probed again.
+pub const PROBE_INTERVAL: Duration = Duration::from_secs(10);
+
+/// A bulb reachable by unicast, as given in `Config::static_devices`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct StaticDevice {
+    /// IP address, optionally with a port, e.g. `10.0.20.15` or `10.0.20.15:56700`.
+    pub address: String,
+    /// Serial number (MAC address) such as `d073d5010203`. When given only that bulb answers
+    /// the probe; otherwise whichever device owns the address does.
+    #[serde(default)]
+    pub serial: Option<String>,
+}
+
+impl StaticDevice {
+    pub fn socket_addr(&self) -> Result<SocketAddr> {
+        let address = self.address.trim();
+        if let Ok(addr) = address.parse::<SocketAddr>() {
+            return Ok(addr);
+        }
+        address.parse::<IpAddr>()
+            .map(|ip| SocketAddr::new(ip, LIFX_PORT))
+            .map_err(|_| LifxError::ConfigError(format!("Invalid static device address '{}'", self.address)))
+    }
+
+    /// Frame target of the bulb, 0 (all devices) when no serial is configured.
+    pub fn target(&self) -> Result<u64> {
+        self.serial.as_deref().map_or(Ok(0), serial_to_target)
+    }
+}
+
+/// Converts a serial such as `d073d5010203` (colons allowed) to the frame target the bulb
+/// reports, which holds the MAC address in its first six little-endian bytes.
+pub fn serial_to_target(serial: &str) -> Result<u64> {
+    let hex: String = serial.trim().chars().filter(|c| *c != ':').collect();
+    if hex.len() != 12 || !hex.chars().all(|c| c.is_ascii_hexdigit()) {
+        return Err(LifxError::ConfigError(format!(
+            "Invalid serial '{}', expected 12 hex digits such as d073d5010203", serial
+        )));
+    }
+
+    let mut bytes = [0u8; 8];
+    for (i, byte) in bytes.iter_mut().take(6).enumerate() {
+        *byte = u8::from_str_radix(&hex[i * 2..i * 2 + 2], 16)
+            .map_err(|e| LifxError::ConfigError(format!("Invalid serial '{}': {}", serial, e)))?;
+    }
+    Ok(u64::from_le_bytes(bytes))
+}
+
+/// A configured device with its address and target resolved.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) struct StaticProbe {
+    pub addr: SocketAddr,
+    pub target: u64,
+}
+
+impl StaticProbe {
+    /// Whether `bulb` is the device this probe is looking for.
+    fn matches(&self, bulb: &BulbInfo) -> bool {
+        if self.target != 0 {
+            bulb.target == self.target
+        } else {
+            bulb.addr.ip() == self.addr.ip()
+        }
+    }
+}
+
+/// Validates the configured devices, failing on the first bad address or serial.
+pub(crate) fn resolve(devices: &[StaticDevice]) -> Result<Vec<StaticProbe>> {
+    devices.iter()
+        .map(|device| Ok(StaticProbe { addr: device.socket_addr()?, target: device.target()? }))
+        .collect()
+}
+
+/// Devices not currently known as connected bulbs.
+pub(crate) fn missing<'a>(probes: &'a [StaticProbe], bulbs: &HashMap<u64, BulbInfo>) -> Vec<&'a StaticProbe> {
+    probes.iter()
+        .filter(|probe| !bulbs.values().any(|bulb| bulb.connected && probe.matches(bulb)))
+        .collect()
+}
+
+/// Sends a unicast GetService to each device. Replies are picked up by the receive worker.
+pub(crate) fn probe<'a, I>(sock: &UdpSocket, source: u32, probes: I)
+where
+    I: IntoIterator<Item = &'a StaticProbe>,
+{
+    for probe in probes {
+        let options = BuildOptions {
+            target: if probe.target != 0 { Some(probe.target) } else { None },
+            source,
+            ..Default::default()
+        };
+        let packet = match RawMessage::build(&options, Message::GetService).and_then(|m| m.pack()) {
+            Ok(packet) => packet,
+            Err(e) => {
+                warn!("Failed to build probe for {}: {:?}", probe.addr, e);
+                continue;
+            }
+        };
+        debug!("Probing static device {}", probe.addr);
+        if let Err(e) = sock.send_to(&packet, probe.addr) {
+            warn!("Failed to probe static device {}: {}", probe.addr, e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::Ipv4Addr;
+
+    fn device(address: &str, serial: Option<&str>) -> StaticDevice {
+        StaticDevice { address: address.to_string(), serial: serial.map(str::to_string) }
+    }
+
+    #[test]
+    fn test_address_defaults_to_lifx_port() {
+        let addr = device("10.0.20.15", None).socket_addr().unwrap();
+        assert_eq!(addr, SocketAddr::new(IpAddr::V4(Ipv4Addr::new(10, 0, 20, 15)), LIFX_PORT));
+
+        let addr = device("10.0.20.15:56800", None).socket_addr().unwrap();
+        assert_eq!(addr.port(), 56800);
+
+        assert!(device("bulb.local", None).socket_addr().is_err());
+    }
+
+    #[test]
+    fn test_serial_to_target() {
+        assert_eq!(serial_to_target("d073d5010203").unwrap(), 0x0302_01d5_73d0);
+        assert_eq!(serial_to_target("D0:73:D5:01:02:03").unwrap(), 0x0302_01d5_73d0);
+        assert_eq!(crate::serial_from_target(0x0302_01d5_73d0), "d073d5010203");
+        assert!(serial_to_target("d073d50102").is_err());
+        assert!(serial_to_target("d073d50102zz").is_err());
+
+        assert_eq!(device("10.0.20.15", None).target().unwrap(), 0);
+    }
+
+    #[test]
+    fn test_resolve_rejects_bad_entries() {
+        let devices = vec![device("10.0.20.15", Some("d073d5010203")), device("10.0.20.16", None)];
+        let probes = resolve(&devices).unwrap();
+        assert_eq!(probes.len(), 2);
+        assert_eq!(probes[1].target, 0);
+
+        let devices = vec![device("10.0.20.15", Some("nope"))];
+        assert!(matches!(resolve(&devices), Err(LifxError::ConfigError(_))));
+    }
+
+    #[test]
+    fn test_missing_devices() {
+        let addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(10, 0, 20, 15)), LIFX_PORT);
+        let mut bulbs = HashMap::new();
+        bulbs.insert(0x0302_01d5_73d0, BulbInfo::new(0x1234, 0x0302_01d5_73d0, addr));
+
+        let by_serial = StaticProbe { addr, target: 0x0302_01d5_73d0 };
+        let by_address = StaticProbe { addr, target: 0 };
+        let elsewhere = StaticProbe { addr: "10.0.20.99:56700".parse().unwrap(), target: 0 };
+        let probes = [by_serial, by_address, elsewhere];
+        assert_eq!(missing(&probes, &bulbs), vec![&elsewhere]);
+
+        // Disconnected bulbs are probed again
+        bulbs.values_mut().for_each(|bulb| bulb.connected = false);
+        assert_eq!(missing(&probes, &bulbs).len(), 3);
+    }
+}
@@ -0,0 +1,290 @@
+use serde::{Deserialize, Serialize};
+use std::time::{Duration, Instant};
+use lifx_rs::lan::HSBK;
+use crate::color::{parse_color, ColorSpec};
+use crate::error::{LifxError, Result};
+use crate::protocol::{ExtMessage, TileDevice, TILE_PIXELS};
+use crate::{delivery, BulbInfo, LifxColor, Manager};
+
+/// How long to wait for a device to report its chain or framebuffers.
+const REPLY_TIMEOUT: Duration = Duration::from_secs(2);
+/// Assumed tile size until the chain layout is known.
+const DEFAULT_TILE_SIZE: u8 = 8;
+
+/// Body of `PUT /v1/lights/:selector/tiles`.
+#[derive(Deserialize, Debug, Clone)]
+pub struct TilesRequest {
+    pub tiles: Vec<TilePixels>,
+    /// Transition time in seconds.
+    pub duration: Option<f64>,
+    /// Don't wait for the device to acknowledge.
+    pub fast: Option<bool>,
+}
+
+/// Pixels of one tile, row by row from the top left. A single color fills the whole tile.
+#[derive(Deserialize, Debug, Clone)]
+pub struct TilePixels {
+    pub index: u8,
+    pub colors: Vec<String>,
+}
+
+impl TilesRequest {
+    pub fn validate(&self) -> Result<()> {
+        if self.tiles.is_empty() {
+            return Err(LifxError::ValidationError("tiles cannot be empty".to_string()));
+        }
+        for tile in &self.tiles {
+            if tile.colors.is_empty() || tile.colors.len() > TILE_PIXELS {
+                return Err(LifxError::ValidationError(format!(
+                    "Tile {} needs between 1 and {} colors", tile.index, TILE_PIXELS
+                )));
+            }
+            for color in &tile.colors {
+                ColorSpec::parse(color)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Layout and pixels of one tile, as returned by `GET /v1/lights/:selector/tiles`.
+#[derive(Serialize, Debug)]
+pub struct TileState {
+    pub index: u8,
+    pub width: u8,
+    pub height: u8,
+    pub user_x: f32,
+    pub user_y: f32,
+    /// `width * height` colors, row by row from the top left.
+    pub colors: Vec<LifxColor>,
+}
+
+#[derive(Serialize, Debug)]
+pub struct TilesResult {
+    pub id: String,
+    pub label: String,
+    pub status: String,
+    pub message: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tiles: Option<Vec<TileState>>,
+}
+
+impl TilesResult {
+    fn error(bulb: &BulbInfo, status: &str, message: String) -> Self {
+        TilesResult {
+            id: bulb.id.clone(),
+            label: bulb.label.clone(),
+            status: status.to_string(),
+            message: Some(message),
+            tiles: None,
+        }
+    }
+}
+
+#[derive(Serialize)]
+pub struct TilesResponse {
+    pub results: Vec<TilesResult>,
+}
+
+pub struct TilesHandler;
+
+impl TilesHandler {
+    pub fn new() -> Self {
+        TilesHandler
+    }
+
+    /// Reads the framebuffer of every tile with Get64.
+    ///
+    /// Waits for replies through `Manager::wait_for_bulb`, so the bulbs lock must not be held.
+    pub fn handle_get(&self, mgr: &Manager, bulbs: &[&BulbInfo]) -> TilesResponse {
+        let deadline = Instant::now() + REPLY_TIMEOUT;
+        let results = bulbs.iter().map(|bulb| {
+            if !bulb.has_matrix() {
+                return TilesResult::error(bulb, "error", "Device does not support tiles".to_string());
+            }
+            match self.read_tiles(mgr, bulb, deadline) {
+                Ok(tiles) => TilesResult {
+                    id: bulb.id.clone(),
+                    label: bulb.label.clone(),
+                    status: "ok".to_string(),
+                    message: None,
+                    tiles: Some(tiles),
+                },
+                Err((status, message)) => TilesResult::error(bulb, status, message),
+            }
+        }).collect();
+
+        TilesResponse { results }
+    }
+
+    /// Draws the requested pixels with one Set64 per tile.
+    ///
+    /// Waits for acks through `delivery::confirm` unless the request is `fast`, so the bulbs
+    /// lock must not be held.
+    pub fn handle_set(&self, mgr: &Manager, bulbs: &[&BulbInfo], request: TilesRequest) -> TilesResponse {
+        let duration_ms = (request.duration.unwrap_or(0.0).max(0.0) * 1000.0) as u32;
+
+        let sent_at = Instant::now();
+        let sent: Vec<_> = bulbs.iter().map(|bulb| self.draw_tiles(mgr, bulb, &request.tiles, duration_ms)).collect();
+        let statuses = delivery::confirm_unless_fast(&mgr.sock, bulbs, &sent, sent_at, request.fast.unwrap_or(false));
+
+        let results = bulbs.iter().zip(sent).zip(statuses).map(|((bulb, result), status)| {
+            match result {
+                Ok(()) => TilesResult {
+                    id: bulb.id.clone(),
+                    label: bulb.label.clone(),
+                    status: status.map_or("error", |s| s.as_str()).to_string(),
+                    message: None,
+                    tiles: None,
+                },
+                Err(message) => TilesResult::error(bulb, "error", message),
+            }
+        }).collect();
+
+        TilesResponse { results }
+    }
+
+    fn read_tiles(&self, mgr: &Manager, bulb: &BulbInfo, deadline: Instant) -> std::result::Result<Vec<TileState>, (&'static str, String)> {
+        let chain = match bulb.tile_chain.as_ref() {
+            Some(chain) => chain.clone(),
+            None => mgr.wait_for_bulb(bulb.target, deadline, |b| b.tile_chain.as_ref().cloned())
+                .ok_or_else(|| ("timed_out", "No tile chain received from device".to_string()))?,
+        };
+
+        let sent_at = Instant::now();
+        for (index, tile) in chain.iter().enumerate() {
+            let message = ExtMessage::Get64 { tile_index: index as u8, length: 1, x: 0, y: 0, width: tile.width };
+            bulb.send_ext(&mgr.sock, &message)
+                .map_err(|e| ("error", format!("Failed to request tile {}: {}", index, e)))?;
+        }
+
+        let pixels = mgr.wait_for_bulb(bulb.target, deadline, |b| {
+            (0..chain.len() as u8)
+                .map(|index| b.tile_colors.get(&index).and_then(|d| d.updated_since(sent_at)).cloned())
+                .collect::<Option<Vec<Vec<HSBK>>>>()
+        }).ok_or_else(|| ("timed_out", "Not every tile reported its pixels".to_string()))?;
+
+        Ok(chain.iter().zip(pixels).enumerate().map(|(index, (tile, colors))| {
+            tile_state(index as u8, tile, &colors)
+        }).collect())
+    }
+
+    fn draw_tiles(
+        &self,
+        mgr: &Manager,
+        bulb: &BulbInfo,
+        tiles: &[TilePixels],
+        duration_ms: u32,
+    ) -> std::result::Result<(), String> {
+        if !bulb.has_matrix() {
+            return Err("Device does not support tiles".to_string());
+        }
+        let chain = bulb.tile_chain.as_ref();
+
+        for tile in tiles {
+            let (width, height) = match chain {
+                Some(chain) => chain.get(tile.index as usize)
+                    .map(|t| (t.width, t.height))
+                    .ok_or_else(|| format!("Tile {} does not exist, device has {} tiles", tile.index, chain.len()))?,
+                None => (DEFAULT_TILE_SIZE, DEFAULT_TILE_SIZE),
+            };
+
+            let palette = tile.colors.iter()
+                .map(|c| parse_color(c, bulb.lifx_color.as_ref()).map_err(|e| e.to_string()))
+                .collect::<std::result::Result<Vec<_>, _>>()?;
+            let colors = tile_pixels(&palette, width, height)?;
+
+            let message = ExtMessage::Set64 {
+                tile_index: tile.index,
+                length: 1,
+                x: 0,
+                y: 0,
+                width,
+                duration_ms,
+                colors,
+            };
+            bulb.send_ext(&mgr.sock, &message)
+                .map_err(|e| format!("Failed to set tile {}: {}", tile.index, e))?;
+        }
+        Ok(())
+    }
+}
+
+impl Default for TilesHandler {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Expands a single color to the whole tile, or checks that the given pixels fit on it.
+fn tile_pixels(palette: &[HSBK], width: u8, height: u8) -> std::result::Result<Vec<HSBK>, String> {
+    let size = (width as usize * height as usize).min(TILE_PIXELS);
+    match palette.len() {
+        1 => Ok(vec![palette[0]; size]),
+        n if n <= size => Ok(palette.to_vec()),
+        n => Err(format!("Got {} colors for a {}x{} tile", n, width, height)),
+    }
+}
+
+fn tile_state(index: u8, tile: &TileDevice, colors: &[HSBK]) -> TileState {
+    let size = tile.width as usize * tile.height as usize;
+    TileState {
+        index,
+        width: tile.width,
+        height: tile.height,
+        user_x: tile.user_x,
+        user_y: tile.user_y,
+        colors: colors.iter().take(size).map(|c| LifxColor {
+            hue: c.hue,
+            saturation: c.saturation,
+            kelvin: c.kelvin,
+            brightness: c.brightness,
+        }).collect(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn red() -> HSBK {
+        HSBK { hue: 0, saturation: 65535, brightness: 65535, kelvin: 3500 }
+    }
+
+    #[test]
+    fn test_tiles_request_validation() {
+        let request: TilesRequest = serde_json::from_str(
+            r#"{"tiles": [{"index": 0, "colors": ["red", "blue"]}], "duration": 1.0}"#
+        ).unwrap();
+        assert!(request.validate().is_ok());
+
+        let empty = TilesRequest { tiles: vec![], duration: None, fast: None };
+        assert!(empty.validate().is_err());
+
+        let too_many = TilesRequest {
+            tiles: vec![TilePixels { index: 0, colors: vec!["red".to_string(); 65] }],
+            duration: None,
+            fast: None,
+        };
+        assert!(too_many.validate().is_err());
+    }
+
+    #[test]
+    fn test_tile_pixels() {
+        assert_eq!(tile_pixels(&[red()], 8, 8).unwrap().len(), 64);
+        // Candle tiles are 5x6
+        assert_eq!(tile_pixels(&[red()], 5, 6).unwrap().len(), 30);
+        assert_eq!(tile_pixels(&[red(); 10], 8, 8).unwrap().len(), 10);
+        assert!(tile_pixels(&[red(); 31], 5, 6).is_err());
+    }
+
+    #[test]
+    fn test_tile_state_trims_padding() {
+        let tile = TileDevice { width: 5, height: 6, user_x: 1.0, ..Default::default() };
+        let state = tile_state(3, &tile, &vec![red(); 64]);
+
+        assert_eq!(state.index, 3);
+        assert_eq!(state.colors.len(), 30);
+        assert_eq!(state.colors[0].saturation, 65535);
+    }
+}
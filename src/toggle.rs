@@ -0,0 +1,149 @@
+use serde::{Deserialize, Serialize};
+use std::time::Instant;
+use lifx_rs::lan::PowerLevel;
+use crate::error::{LifxError, Result};
+use crate::{delivery, BulbInfo, Manager};
+
+/// Body of `POST /v1/lights/:selector/toggle`.
+#[derive(Deserialize, Debug, Clone, Default)]
+pub struct ToggleRequest {
+    /// Transition time in seconds.
+    pub duration: Option<f64>,
+    /// Don't wait for the bulbs to acknowledge.
+    pub fast: Option<bool>,
+}
+
+impl ToggleRequest {
+    pub fn validate(&self) -> Result<()> {
+        match self.duration {
+            Some(d) if !d.is_finite() || d < 0.0 => {
+                Err(LifxError::ValidationError("duration must be a non-negative number".to_string()))
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+#[derive(Serialize, Debug)]
+pub struct ToggleResult {
+    pub id: String,
+    pub label: String,
+    pub status: String,
+    /// Power the bulb was switched to, `on` or `off`.
+    pub power: String,
+    pub message: Option<String>,
+}
+
+#[derive(Serialize)]
+pub struct ToggleResponse {
+    pub results: Vec<ToggleResult>,
+}
+
+pub struct ToggleHandler;
+
+impl ToggleHandler {
+    pub fn new() -> Self {
+        ToggleHandler
+    }
+
+    /// Flips the power of the selection as one: if any bulb is on they all turn off,
+    /// otherwise they all turn on. Uses the cached power level of each bulb.
+    ///
+    /// Waits for acks through `delivery::confirm` unless the request is `fast`, so the bulbs
+    /// lock must not be held.
+    pub fn handle_toggle(&self, mgr: &Manager, bulbs: &[&BulbInfo], request: ToggleRequest) -> ToggleResponse {
+        let level = toggled_power(bulbs);
+        let power = if level == PowerLevel::Enabled { "on" } else { "off" };
+        let duration_ms = (request.duration.unwrap_or(0.0).max(0.0) * 1000.0) as u32;
+
+        let sent_at = Instant::now();
+        let sent: Vec<_> = bulbs.iter()
+            .map(|bulb| bulb.set_power_with_duration(&mgr.sock, level, duration_ms))
+            .collect();
+        let statuses = delivery::confirm_unless_fast(&mgr.sock, bulbs, &sent, sent_at, request.fast.unwrap_or(false));
+
+        let results = bulbs.iter().zip(sent).zip(statuses).map(|((bulb, result), status)| {
+            ToggleResult {
+                id: bulb.id.clone(),
+                label: bulb.label.clone(),
+                status: status.map_or("error", |s| s.as_str()).to_string(),
+                power: power.to_string(),
+                message: result.err().map(|e| format!("Failed to set power: {:?}", e)),
+            }
+        }).collect();
+
+        ToggleResponse { results }
+    }
+}
+
+impl Default for ToggleHandler {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Power to switch the whole selection to. Bulbs whose power is not known yet count as off.
+fn toggled_power(bulbs: &[&BulbInfo]) -> PowerLevel {
+    let any_on = bulbs.iter().any(|bulb| bulb.power_level.as_ref() == Some(&PowerLevel::Enabled));
+    if any_on {
+        PowerLevel::Standby
+    } else {
+        PowerLevel::Enabled
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+
+    fn bulb(target: u64, power: Option<PowerLevel>) -> BulbInfo {
+        let addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(192, 168, 1, 100)), 56700);
+        let mut bulb = BulbInfo::new(0x1234, target, addr);
+        if let Some(power) = power {
+            bulb.power_level.update(power);
+        }
+        bulb
+    }
+
+    #[test]
+    fn test_any_bulb_on_turns_all_off() {
+        let on = bulb(1, Some(PowerLevel::Enabled));
+        let off = bulb(2, Some(PowerLevel::Standby));
+        assert_eq!(toggled_power(&[&on, &off]), PowerLevel::Standby);
+        assert_eq!(toggled_power(&[&on]), PowerLevel::Standby);
+    }
+
+    #[test]
+    fn test_all_off_or_unknown_turns_on() {
+        let off = bulb(1, Some(PowerLevel::Standby));
+        let unknown = bulb(2, None);
+        assert_eq!(toggled_power(&[&off, &unknown]), PowerLevel::Enabled);
+        assert_eq!(toggled_power(&[]), PowerLevel::Enabled);
+    }
+
+    #[test]
+    fn test_acknowledged_toggle_is_toggled_back() {
+        let device = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        let sock = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        let mut light = BulbInfo::new(0x1234, 1, device.local_addr().unwrap());
+        light.power_level.update(PowerLevel::Standby);
+
+        let level = toggled_power(&[&light]);
+        light.set_power_with_duration(&sock, level, 0).unwrap();
+        let message = light.delivery.acknowledge(0).unwrap();
+        light.apply_acknowledged(message);
+
+        // The next toggle starts from the acknowledged power, not the last poll
+        assert_eq!(light.power, "on");
+        assert_eq!(toggled_power(&[&light]), PowerLevel::Standby);
+    }
+
+    #[test]
+    fn test_toggle_request_validation() {
+        let request: ToggleRequest = serde_json::from_str(r#"{"duration": 2.5}"#).unwrap();
+        assert!(request.validate().is_ok());
+        assert!(ToggleRequest::default().validate().is_ok());
+        assert!(ToggleRequest { duration: Some(-1.0), ..Default::default() }.validate().is_err());
+    }
+}
@@ -0,0 +1,361 @@
+use serde::{Deserialize, Serialize};
+use std::time::Instant;
+use lifx_rs::lan::{ApplicationRequest, HSBK};
+use crate::color::{parse_color, ColorSpec};
+use crate::error::{LifxError, Result};
+use crate::protocol::{ExtMessage, EXTENDED_ZONES_PER_MESSAGE};
+use crate::selector::{Selector, ZoneRange};
+use crate::{delivery, BulbInfo, Manager};
+
+/// Body of `PUT /v1/lights/:selector/zones`.
+///
+/// Either `colors` is painted across the selected zones in order, or a gradient is drawn
+/// from `from` to `to`. Zones are selected with the `|start-end` selector suffix.
+#[derive(Deserialize, Debug, Clone)]
+pub struct ZonesRequest {
+    pub colors: Option<Vec<String>>,
+    pub from: Option<String>,
+    pub to: Option<String>,
+    /// Transition time in seconds.
+    pub duration: Option<f64>,
+    /// Don't wait for the bulbs to acknowledge.
+    pub fast: Option<bool>,
+}
+
+impl ZonesRequest {
+    pub fn validate(&self) -> Result<()> {
+        match (&self.colors, &self.from, &self.to) {
+            (Some(colors), None, None) if !colors.is_empty() => {}
+            (Some(_), None, None) => return Err(LifxError::ValidationError("colors cannot be empty".to_string())),
+            (None, Some(_), Some(_)) => {}
+            (None, _, _) => return Err(LifxError::MissingField("colors or from/to".to_string())),
+            _ => return Err(LifxError::ValidationError("Use either colors or from/to, not both".to_string())),
+        }
+        for color in self.colors.iter().flatten().chain(&self.from).chain(&self.to) {
+            ColorSpec::parse(color)?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Serialize, Debug)]
+pub struct ZoneResult {
+    pub id: String,
+    pub label: String,
+    pub status: String,
+    pub message: Option<String>,
+    /// Number of zones that were set.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub zones: Option<usize>,
+}
+
+#[derive(Serialize)]
+pub struct ZonesResponse {
+    pub results: Vec<ZoneResult>,
+}
+
+pub struct ZonesHandler;
+
+impl ZonesHandler {
+    pub fn new() -> Self {
+        ZonesHandler
+    }
+
+    /// Waits for acks through `delivery::confirm` unless the request is `fast`, so the bulbs
+    /// lock must not be held.
+    pub fn handle_zones(
+        &self,
+        mgr: &Manager,
+        bulbs: &[&BulbInfo],
+        selector: &Selector,
+        request: ZonesRequest,
+    ) -> ZonesResponse {
+        let sent_at = Instant::now();
+        let sent: Vec<_> = bulbs.iter()
+            .map(|bulb| self.apply_zones(mgr, bulb, selector.zones_for(bulb), &request))
+            .collect();
+        let statuses = delivery::confirm_unless_fast(&mgr.sock, bulbs, &sent, sent_at, request.fast.unwrap_or(false));
+
+        let results = bulbs.iter().zip(sent).zip(statuses).map(|((bulb, result), status)| {
+            ZoneResult {
+                id: bulb.id.clone(),
+                label: bulb.label.clone(),
+                status: status.map_or("error", |s| s.as_str()).to_string(),
+                zones: result.as_ref().ok().copied(),
+                message: result.err(),
+            }
+        }).collect();
+
+        ZonesResponse { results }
+    }
+
+    fn apply_zones(
+        &self,
+        mgr: &Manager,
+        bulb: &BulbInfo,
+        ranges: Option<&[ZoneRange]>,
+        request: &ZonesRequest,
+    ) -> std::result::Result<usize, String> {
+        if !bulb.is_multizone() {
+            return Err("Device does not support multizone".to_string());
+        }
+        let count = bulb.zone_count()
+            .ok_or_else(|| "Zone count not known yet, try again shortly".to_string())?;
+
+        let indices = selected_zones(ranges, count);
+        if indices.is_empty() {
+            return Err(format!("No zones selected, device has {} zones", count));
+        }
+
+        let colors = match request.colors {
+            Some(ref colors) => {
+                let palette = colors.iter()
+                    .map(|c| parse_color(c, bulb.lifx_color.as_ref()).map_err(|e| e.to_string()))
+                    .collect::<std::result::Result<Vec<_>, _>>()?;
+                spread(&palette, indices.len())
+            }
+            None => {
+                let from = request.from.as_deref().ok_or_else(|| "Missing from color".to_string())?;
+                let to = request.to.as_deref().ok_or_else(|| "Missing to color".to_string())?;
+                gradient(
+                    parse_color(from, bulb.lifx_color.as_ref()).map_err(|e| e.to_string())?,
+                    parse_color(to, bulb.lifx_color.as_ref()).map_err(|e| e.to_string())?,
+                    indices.len(),
+                )
+            }
+        };
+
+        let duration_ms = (request.duration.unwrap_or(0.0).max(0.0) * 1000.0) as u32;
+        let runs = contiguous_runs(&indices, &colors);
+        self.send_runs(mgr, bulb, &runs, duration_ms)
+            .map_err(|e| format!("Failed to set zones: {:?}", e))?;
+
+        Ok(indices.len())
+    }
+
+    /// Sets the zones of `bulb` to `colors`, one per zone starting from the first.
+    pub(crate) fn paint(
+        &self,
+        mgr: &Manager,
+        bulb: &BulbInfo,
+        colors: &[HSBK],
+        duration_ms: u32,
+    ) -> std::result::Result<(), failure::Error> {
+        self.send_runs(mgr, bulb, &[(0, colors.to_vec())], duration_ms)
+    }
+
+    fn send_runs(
+        &self,
+        mgr: &Manager,
+        bulb: &BulbInfo,
+        runs: &[(u16, Vec<HSBK>)],
+        duration_ms: u32,
+    ) -> std::result::Result<(), failure::Error> {
+        if bulb.supports_extended_multizone() {
+            self.send_extended(mgr, bulb, runs, duration_ms)
+        } else {
+            self.send_legacy(mgr, bulb, runs, duration_ms)
+        }
+    }
+
+    /// SetExtendedColorZones, up to 82 zones per message; only the last message applies.
+    fn send_extended(
+        &self,
+        mgr: &Manager,
+        bulb: &BulbInfo,
+        runs: &[(u16, Vec<HSBK>)],
+        duration_ms: u32,
+    ) -> std::result::Result<(), failure::Error> {
+        let chunks: Vec<(u16, &[HSBK])> = runs.iter()
+            .flat_map(|(start, colors)| {
+                colors.chunks(EXTENDED_ZONES_PER_MESSAGE)
+                    .enumerate()
+                    .map(move |(i, chunk)| (start + (i * EXTENDED_ZONES_PER_MESSAGE) as u16, chunk))
+            })
+            .collect();
+
+        for (i, (zone_index, colors)) in chunks.iter().enumerate() {
+            let message = ExtMessage::SetExtendedColorZones {
+                duration_ms,
+                apply: i + 1 == chunks.len(),
+                zone_index: *zone_index,
+                colors: colors.to_vec(),
+            };
+            bulb.send_ext(&mgr.sock, &message)?;
+        }
+        Ok(())
+    }
+
+    /// SetColorZones for every stretch of identical colors; only the last message applies.
+    fn send_legacy(
+        &self,
+        mgr: &Manager,
+        bulb: &BulbInfo,
+        runs: &[(u16, Vec<HSBK>)],
+        duration_ms: u32,
+    ) -> std::result::Result<(), failure::Error> {
+        let mut stretches: Vec<(u16, u16, HSBK)> = Vec::new();
+        for (start, colors) in runs {
+            for (i, color) in colors.iter().enumerate() {
+                let zone = start + i as u16;
+                match stretches.last_mut() {
+                    Some((_, end, last)) if *end + 1 == zone && same_color(last, color) => *end = zone,
+                    _ => stretches.push((zone, zone, *color)),
+                }
+            }
+        }
+
+        for (i, (start, end, color)) in stretches.iter().enumerate() {
+            let apply = if i + 1 == stretches.len() {
+                ApplicationRequest::Apply
+            } else {
+                ApplicationRequest::NoApply
+            };
+            bulb.set_color_zones(&mgr.sock, *start, *end, *color, duration_ms, apply)?;
+        }
+        Ok(())
+    }
+}
+
+impl Default for ZonesHandler {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Sorted, deduplicated zone indices selected by `ranges`, clamped to the bulb's zones.
+fn selected_zones(ranges: Option<&[ZoneRange]>, count: u16) -> Vec<u16> {
+    let mut indices: Vec<u16> = match ranges {
+        Some(ranges) if !ranges.is_empty() => ranges.iter()
+            .flat_map(|range| range.start..=range.end.min(count.saturating_sub(1)))
+            .filter(|zone| *zone < count)
+            .collect(),
+        _ => (0..count).collect(),
+    };
+    indices.sort_unstable();
+    indices.dedup();
+    indices
+}
+
+/// Stretches `palette` over `count` zones, giving each color an equal share.
+fn spread(palette: &[HSBK], count: usize) -> Vec<HSBK> {
+    (0..count).map(|i| palette[i * palette.len() / count]).collect()
+}
+
+/// Interpolates `count` colors from `from` to `to`, taking the short way around the hue wheel.
+fn gradient(from: HSBK, to: HSBK, count: usize) -> Vec<HSBK> {
+    let lerp = |a: u16, b: u16, t: f64| (a as f64 + (b as f64 - a as f64) * t).round() as u16;
+    let hue_delta = {
+        let delta = to.hue as f64 - from.hue as f64;
+        if delta > 32768.0 {
+            delta - 65536.0
+        } else if delta < -32768.0 {
+            delta + 65536.0
+        } else {
+            delta
+        }
+    };
+
+    (0..count).map(|i| {
+        let t = if count > 1 { i as f64 / (count - 1) as f64 } else { 0.0 };
+        HSBK {
+            hue: (from.hue as f64 + hue_delta * t).round().rem_euclid(65536.0) as u16,
+            saturation: lerp(from.saturation, to.saturation, t),
+            brightness: lerp(from.brightness, to.brightness, t),
+            kelvin: lerp(from.kelvin, to.kelvin, t),
+        }
+    }).collect()
+}
+
+/// Groups zones with consecutive indices, pairing each run's first index with its colors.
+fn contiguous_runs(indices: &[u16], colors: &[HSBK]) -> Vec<(u16, Vec<HSBK>)> {
+    let mut runs: Vec<(u16, Vec<HSBK>)> = Vec::new();
+    for (zone, color) in indices.iter().zip(colors) {
+        match runs.last_mut() {
+            Some((start, run)) if *start + run.len() as u16 == *zone => run.push(*color),
+            _ => runs.push((*zone, vec![*color])),
+        }
+    }
+    runs
+}
+
+fn same_color(a: &HSBK, b: &HSBK) -> bool {
+    a.hue == b.hue && a.saturation == b.saturation && a.brightness == b.brightness && a.kelvin == b.kelvin
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hsbk(hue: u16, brightness: u16) -> HSBK {
+        HSBK { hue, saturation: 65535, brightness, kelvin: 3500 }
+    }
+
+    #[test]
+    fn test_zones_request_validation() {
+        let colors = ZonesRequest { colors: Some(vec!["red".to_string()]), from: None, to: None, duration: None, fast: None };
+        assert!(colors.validate().is_ok());
+
+        let gradient = ZonesRequest { colors: None, from: Some("red".to_string()), to: Some("blue".to_string()), duration: None, fast: None };
+        assert!(gradient.validate().is_ok());
+
+        let empty = ZonesRequest { colors: Some(vec![]), from: None, to: None, duration: None, fast: None };
+        assert!(empty.validate().is_err());
+
+        let half_gradient = ZonesRequest { colors: None, from: Some("red".to_string()), to: None, duration: None, fast: None };
+        assert!(half_gradient.validate().is_err());
+
+        let both = ZonesRequest { colors: Some(vec!["red".to_string()]), from: Some("red".to_string()), to: Some("blue".to_string()), duration: None, fast: None };
+        assert!(both.validate().is_err());
+
+        let bad_color = ZonesRequest { colors: None, from: Some("red".to_string()), to: Some("hue:400".to_string()), duration: None, fast: None };
+        assert!(bad_color.validate().is_err());
+    }
+
+    #[test]
+    fn test_selected_zones() {
+        assert_eq!(selected_zones(None, 4), vec![0, 1, 2, 3]);
+
+        let ranges = [ZoneRange { start: 6, end: 20 }, ZoneRange { start: 0, end: 1 }, ZoneRange { start: 1, end: 2 }];
+        assert_eq!(selected_zones(Some(&ranges), 8), vec![0, 1, 2, 6, 7]);
+
+        let out_of_range = [ZoneRange { start: 10, end: 12 }];
+        assert!(selected_zones(Some(&out_of_range), 8).is_empty());
+    }
+
+    #[test]
+    fn test_spread_palette() {
+        let red = hsbk(0, 65535);
+        let blue = hsbk(43690, 65535);
+
+        let zones = spread(&[red, blue], 5);
+        assert_eq!(zones.iter().map(|c| c.hue).collect::<Vec<_>>(), vec![0, 0, 0, 43690, 43690]);
+        assert_eq!(spread(&[red], 3).len(), 3);
+    }
+
+    #[test]
+    fn test_gradient() {
+        let zones = gradient(hsbk(0, 0), hsbk(21845, 65535), 3);
+        assert_eq!(zones[0].hue, 0);
+        assert_eq!(zones[1].hue, 10923);
+        assert_eq!(zones[1].brightness, 32768);
+        assert_eq!(zones[2].hue, 21845);
+
+        // Red-ish hues on either side of 0 meet through 0, not through cyan
+        let zones = gradient(hsbk(64000, 65535), hsbk(1000, 65535), 3);
+        assert_eq!(zones[1].hue, 65268);
+        assert_eq!(gradient(hsbk(5, 5), hsbk(10, 10), 1)[0].hue, 5);
+    }
+
+    #[test]
+    fn test_contiguous_runs() {
+        let colors = vec![hsbk(1, 1), hsbk(2, 2), hsbk(3, 3), hsbk(4, 4)];
+        let runs = contiguous_runs(&[0, 1, 5, 6], &colors);
+
+        assert_eq!(runs.len(), 2);
+        assert_eq!(runs[0].0, 0);
+        assert_eq!(runs[0].1.len(), 2);
+        assert_eq!(runs[1].0, 5);
+        assert_eq!(runs[1].1[1].hue, 4);
+    }
+}
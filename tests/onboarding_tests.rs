@@ -0,0 +1,127 @@
+use lifx_api_server::onboarding::{configure_access_point, OnboardingRequest, WifiSecurity};
+use lifx_api_server::protocol::{decode_packet, encode_packet, ExtMessage, PacketOptions};
+use lifx_api_server::Config;
+use std::io::{Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream, UdpSocket};
+use std::sync::mpsc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+const GET_SERVICE: u16 = 2;
+const STATE_SERVICE: u16 = 3;
+const ACKNOWLEDGEMENT: u16 = 45;
+const TARGET: u64 = 0x5634_12d5_73d0;
+
+/// A bulb in soft AP mode: answers GetService and acknowledges SetAccessPoint, which it
+/// reports through the returned channel.
+fn fake_soft_ap_bulb() -> (SocketAddr, mpsc::Receiver<ExtMessage>) {
+    let sock = UdpSocket::bind("127.0.0.1:0").expect("Failed to bind fake bulb");
+    sock.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    let addr = sock.local_addr().unwrap();
+    let (tx, rx) = mpsc::channel();
+
+    thread::spawn(move || {
+        let mut buf = [0; 1024];
+        while let Ok((len, from)) = sock.recv_from(&mut buf) {
+            let (header, payload) = match decode_packet(&buf[..len]) {
+                Some(packet) => packet,
+                None => continue,
+            };
+            let reply = PacketOptions { source: header.source, target: TARGET, sequence: header.sequence, ..Default::default() };
+            if header.message_type == GET_SERVICE {
+                // UDP service on the LIFX port
+                let mut state = vec![1];
+                state.extend_from_slice(&56700u32.to_le_bytes());
+                sock.send_to(&encode_packet(&reply, STATE_SERVICE, &state), from).unwrap();
+            } else if let Some(message) = ExtMessage::decode(header.message_type, payload) {
+                assert_eq!(header.target, TARGET);
+                if header.ack_required {
+                    sock.send_to(&encode_packet(&reply, ACKNOWLEDGEMENT, &[]), from).unwrap();
+                }
+                let _ = tx.send(message);
+            }
+        }
+    });
+
+    (addr, rx)
+}
+
+/// Sends a bare HTTP/1.0 request to the API on `port` and returns the response status.
+fn http_status(port: u16, method: &str, path: &str, body: &str) -> u16 {
+    let mut stream = TcpStream::connect(("127.0.0.1", port)).expect("Failed to connect to API");
+    stream.set_read_timeout(Some(Duration::from_secs(30))).unwrap();
+    write!(
+        stream,
+        "{} {} HTTP/1.0\r\nHost: localhost\r\nContent-Type: application/json\r\nContent-Length: {}\r\n\r\n{}",
+        method, path, body.len(), body
+    ).unwrap();
+
+    let mut response = String::new();
+    stream.read_to_string(&mut response).unwrap();
+    response.split_whitespace().nth(1).and_then(|status| status.parse().ok()).expect("Malformed HTTP response")
+}
+
+fn request(security: WifiSecurity) -> OnboardingRequest {
+    serde_json::from_value(serde_json::json!({
+        "ssid": "IoT",
+        "passphrase": "correct horse",
+        "security": security,
+    })).unwrap()
+}
+
+#[test]
+fn test_configure_access_point_on_fake_bulb() {
+    let (addr, received) = fake_soft_ap_bulb();
+    let sock = UdpSocket::bind("127.0.0.1:0").unwrap();
+
+    let target = configure_access_point(&sock, addr, 0xbeef, &request(WifiSecurity::Wpa2AesPsk)).unwrap();
+    assert_eq!(target, TARGET);
+
+    let message = received.recv_timeout(Duration::from_secs(1)).unwrap();
+    assert_eq!(message, ExtMessage::SetAccessPoint {
+        interface: 2,
+        ssid: "IoT".to_string(),
+        passphrase: "correct horse".to_string(),
+        security: 5,
+    });
+}
+
+#[test]
+fn test_configure_access_point_without_reply() {
+    // Bound but never read, so every request goes unanswered
+    let silent = UdpSocket::bind("127.0.0.1:0").unwrap();
+    let sock = UdpSocket::bind("127.0.0.1:0").unwrap();
+
+    let result = configure_access_point(&sock, silent.local_addr().unwrap(), 0xbeef, &request(WifiSecurity::WpaAesPsk));
+    assert!(result.is_err());
+}
+
+#[test]
+fn test_api_answers_while_onboarding_waits() {
+    let (soft_ap, received) = fake_soft_ap_bulb();
+    let port = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+    // Bound to loopback, discovery skips the broadcast addresses of the host's real interfaces
+    lifx_api_server::start(Config {
+        port,
+        auth_required: false,
+        http_bind_address: Some("127.0.0.1".to_string()),
+        lan_bind_address: Some("127.0.0.1".to_string()),
+        ..Default::default()
+    }).unwrap();
+
+    // The fake bulb takes the credentials but never joins, so the request waits out its timeout
+    let body = serde_json::json!({
+        "address": soft_ap.to_string(),
+        "ssid": "IoT",
+        "passphrase": "correct horse",
+        "timeout": 3,
+    }).to_string();
+    let onboarding = thread::spawn(move || http_status(port, "POST", "/v1/onboarding", &body));
+    received.recv_timeout(Duration::from_secs(5)).expect("Bulb never got the credentials");
+
+    let started = Instant::now();
+    assert_eq!(http_status(port, "GET", "/v1/lights/all", ""), 200);
+    assert!(started.elapsed() < Duration::from_secs(2), "API blocked behind the onboarding wait");
+
+    assert_eq!(onboarding.join().unwrap(), 200);
+}